@@ -1,18 +1,167 @@
-#[derive(Clone)]
+use defmt::{Format, info};
+
+use crate::encoder::Rotation;
+
+/// Level below which a release counts as finished
+const SILENCE: f32 = 0.001;
+
+#[derive(Clone, Copy, PartialEq, Format)]
 pub struct TimeMs(pub u16);
 
 impl TimeMs {
     pub fn init() -> Self {
         Self(0)
     }
+
+    /// Progress per sample of a stage lasting this long, a whole stage at
+    /// once when it is shorter than a sample
+    pub(crate) fn step(&self, sample_rate: f32) -> f32 {
+        let samples = self.0 as f32 * sample_rate / 1000.0;
+        if samples <= 1.0 { 1.0 } else { 1.0 / samples }
+    }
+}
+
+/// Ratios the envelope stretches its stage times by, for velocity
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeScale {
+    pub attack: f32,
+    pub decay: f32,
+    pub release: f32,
 }
 
+impl TimeScale {
+    pub const ONE: Self = Self {
+        attack: 1.0,
+        decay: 1.0,
+        release: 1.0,
+    };
+}
+
+/// What a voice needs from an envelope, so it can use an ADSR or a
+/// breakpoint one
+pub trait Generator {
+    /// Starts over from the current level, so retriggering doesn't click
+    fn note_on(&mut self);
+    /// Like `note_on`, but an envelope that is still held carries on where
+    /// it is instead of starting over
+    fn legato(&mut self);
+    fn note_off(&mut self);
+    /// Takes effect on the next sample, like a config edit
+    fn set_time_scale(&mut self, scale: TimeScale);
+    fn is_active(&self) -> bool;
+    fn is_released(&self) -> bool;
+    /// The level returned by the last `next`
+    fn value(&self) -> f32;
+    fn next(&mut self) -> f32;
+}
+
+/// Shape of a stage, from its start level to its target
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Curve {
+    Linear,
+    /// Fast near the top and slow near zero, like a plucked string dying out
+    Exponential,
+    /// Fast near zero and slow near the top, like an analog attack
+    Logarithmic,
+}
+
+impl Curve {
+    pub fn next(&self) -> Self {
+        match self {
+            Curve::Linear => Curve::Exponential,
+            Curve::Exponential => Curve::Logarithmic,
+            Curve::Logarithmic => Curve::Linear,
+        }
+    }
+
+    /// Moves `progress` through a stage from `start` to `target` on by
+    /// `step` and gives the level there. The stage is over once `progress`
+    /// reaches 1
+    pub(crate) fn ramp(&self, start: f32, target: f32, progress: &mut f32, step: f32) -> f32 {
+        *progress += step;
+        // within half a step counts as there, rounding can leave it short
+        if *progress > 1.0 - step / 2.0 {
+            *progress = 1.0;
+        }
+
+        if *progress >= 1.0 {
+            return target;
+        }
+
+        start + (target - start) * self.shape(*progress, target > start)
+    }
+
+    /// Share of the way to the target after `progress` of the stage, both
+    /// in `0.0..=1.0`
+    fn shape(&self, progress: f32, rising: bool) -> f32 {
+        let fast_start = 1.0 - (1.0 - progress) * (1.0 - progress) * (1.0 - progress);
+        let slow_start = progress * progress * progress;
+
+        match (self, rising) {
+            (Curve::Linear, _) => progress,
+            (Curve::Exponential, true) | (Curve::Logarithmic, false) => slow_start,
+            (Curve::Exponential, false) | (Curve::Logarithmic, true) => fast_start,
+        }
+    }
+}
+
+/// Read by the envelope on every sample, so edits apply to notes already
+/// playing
 #[derive(Clone)]
 pub struct Adsr {
     pub attack: TimeMs,
     pub decay: TimeMs,
     pub sustain_level: f32,
     pub release: TimeMs,
+    pub attack_curve: Curve,
+    pub decay_curve: Curve,
+    pub release_curve: Curve,
+}
+
+impl Adsr {
+    pub fn adjust(&mut self, param: &AdsrParams, rotation: Rotation) {
+        let time = |time: &TimeMs| match rotation {
+            Rotation::Right => TimeMs(time.0.saturating_add(5).min(10_000)),
+            Rotation::Left => TimeMs(time.0.saturating_sub(5)),
+        };
+
+        match param {
+            AdsrParams::Attack => {
+                self.attack = time(&self.attack);
+                info!("Set attack: {} ms", self.attack.0);
+            }
+            AdsrParams::Decay => {
+                self.decay = time(&self.decay);
+                info!("Set decay: {} ms", self.decay.0);
+            }
+            AdsrParams::Sustain => {
+                let new = if rotation == Rotation::Right {
+                    self.sustain_level + 0.01
+                } else {
+                    self.sustain_level - 0.01
+                };
+
+                self.sustain_level = new.clamp(0.0, 1.0);
+                info!("Set sustain: {}", self.sustain_level);
+            }
+            AdsrParams::Release => {
+                self.release = time(&self.release);
+                info!("Set release: {} ms", self.release.0);
+            }
+            AdsrParams::AttackCurve => {
+                self.attack_curve = self.attack_curve.next();
+                info!("Set attack curve: {}", self.attack_curve);
+            }
+            AdsrParams::DecayCurve => {
+                self.decay_curve = self.decay_curve.next();
+                info!("Set decay curve: {}", self.decay_curve);
+            }
+            AdsrParams::ReleaseCurve => {
+                self.release_curve = self.release_curve.next();
+                info!("Set release curve: {}", self.release_curve);
+            }
+        }
+    }
 }
 
 #[derive(PartialEq, Clone)]
@@ -27,90 +176,333 @@ pub enum Phase {
 #[derive(Clone)]
 pub struct Envelope {
     config: Adsr,
-    #[allow(unused)]
     sample_rate: f32,
+    scale: TimeScale,
     current_value: f32,
     phase: Phase,
-    timer: u32,
-    release_start_value: f32,
-    attack_samples: u32,
-    decay_samples: u32,
-    release_samples: u32,
+    /// How far into the current stage, `0.0..=1.0`
+    progress: f32,
+    /// Level the current stage started from
+    start_value: f32,
 }
 
 impl Envelope {
     pub const fn new(config: Adsr, sample_rate: f32) -> Self {
-        let attack_samples = (config.attack.0 as f32 * sample_rate / 1000.0) as u32;
-        let decay_samples = (config.decay.0 as f32 * sample_rate / 1000.0) as u32;
-        let release_samples = (config.release.0 as f32 * sample_rate / 1000.0) as u32;
-
         Self {
             config,
             sample_rate,
+            scale: TimeScale::ONE,
             current_value: 0.0,
             phase: Phase::Idle,
-            timer: 0,
-            release_start_value: 0.0,
-            attack_samples,
-            decay_samples,
-            release_samples,
+            progress: 0.0,
+            start_value: 0.0,
         }
     }
 
-    pub fn note_on(&mut self) {
-        self.phase = Phase::Attack;
-        self.timer = 0;
+    pub fn config(&self) -> &Adsr {
+        &self.config
     }
 
-    pub fn note_off(&mut self) {
-        self.phase = Phase::Release;
-        self.timer = 0;
-        self.release_start_value = self.current_value;
+    /// Takes effect on the next sample, mid-stage included
+    pub fn set_config(&mut self, config: &Adsr) {
+        self.config = config.clone();
     }
 
-    pub fn is_active(&self) -> bool {
+    fn enter(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.progress = 0.0;
+        self.start_value = self.current_value;
+    }
+
+    /// Moves the current stage on by `step` towards `target`, and tells
+    /// whether it is over
+    fn ramp(&mut self, step: f32, curve: Curve, target: f32) -> bool {
+        self.current_value = curve.ramp(self.start_value, target, &mut self.progress, step);
+        self.progress >= 1.0
+    }
+}
+
+impl Generator for Envelope {
+    /// Starts the attack from the current level
+    fn note_on(&mut self) {
+        self.enter(Phase::Attack);
+    }
+
+    fn legato(&mut self) {
+        if !self.is_active() || self.is_released() {
+            self.note_on();
+        }
+    }
+
+    fn note_off(&mut self) {
+        if self.is_active() {
+            self.enter(Phase::Release);
+        }
+    }
+
+    fn set_time_scale(&mut self, scale: TimeScale) {
+        self.scale = scale;
+    }
+
+    fn is_active(&self) -> bool {
         self.phase != Phase::Idle
     }
 
-    pub fn next(&mut self) -> f32 {
+    fn is_released(&self) -> bool {
+        self.phase == Phase::Release
+    }
+
+    fn value(&self) -> f32 {
+        self.current_value.clamp(0.0, 1.0)
+    }
+
+    fn next(&mut self) -> f32 {
         match self.phase {
             Phase::Idle => self.current_value = 0.0,
             Phase::Attack => {
-                if self.timer >= self.attack_samples - 1 {
-                    self.current_value = 1.0;
-                    self.phase = Phase::Decay;
-                    self.timer = 0;
-                } else {
-                    self.current_value = (self.timer as f32) / (self.attack_samples as f32);
+                let step = self
+                    .config
+                    .attack
+                    .step(self.sample_rate * self.scale.attack);
+                if self.ramp(step, self.config.attack_curve, 1.0) {
+                    self.enter(Phase::Decay);
                 }
-                self.timer += 1;
             }
             Phase::Decay => {
-                if self.timer >= self.decay_samples - 1 {
-                    self.current_value = self.config.sustain_level;
+                let step = self.config.decay.step(self.sample_rate * self.scale.decay);
+                if self.ramp(step, self.config.decay_curve, self.config.sustain_level) {
                     self.phase = Phase::Sustain;
-                    self.timer = 0;
-                } else {
-                    let t = self.timer as f32 / self.decay_samples as f32;
-                    self.current_value = 1.0 - t * (1.0 - self.config.sustain_level);
                 }
-
-                self.timer += 1;
             }
             Phase::Sustain => self.current_value = self.config.sustain_level,
             Phase::Release => {
-                if self.timer >= self.release_samples - 1 || self.current_value <= 0.001 {
+                let step = self
+                    .config
+                    .release
+                    .step(self.sample_rate * self.scale.release);
+                if self.ramp(step, self.config.release_curve, 0.0) || self.current_value <= SILENCE
+                {
+                    self.current_value = 0.0;
                     self.phase = Phase::Idle;
-                    self.timer = 0;
-                } else {
-                    let t = self.timer as f32 / self.release_samples as f32;
-                    self.current_value = self.release_start_value * (1.0 - t);
                 }
-
-                self.timer += 1;
             }
         }
 
         self.current_value.clamp(0.0, 1.0)
     }
 }
+
+#[derive(Debug, Format)]
+pub enum AdsrParams {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    AttackCurve,
+    DecayCurve,
+    ReleaseCurve,
+}
+
+impl AdsrParams {
+    pub const fn init_param() -> Self {
+        Self::Attack
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use AdsrParams::*;
+
+        match param {
+            Attack => Some(Decay),
+            Decay => Some(Sustain),
+            Sustain => Some(Release),
+            Release => Some(AttackCurve),
+            AttackCurve => Some(DecayCurve),
+            DecayCurve => Some(ReleaseCurve),
+            ReleaseCurve => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A millisecond is this many samples
+    const RATE: f32 = 1_000.0;
+
+    fn adsr(attack: u16, decay: u16, sustain_level: f32, release: u16) -> Adsr {
+        Adsr {
+            attack: TimeMs(attack),
+            decay: TimeMs(decay),
+            sustain_level,
+            release: TimeMs(release),
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
+        }
+    }
+
+    fn render(env: &mut Envelope, len: usize) -> Vec<f32> {
+        (0..len).map(|_| env.next()).collect()
+    }
+
+    #[test]
+    fn stages_take_their_time() {
+        let mut env = Envelope::new(adsr(10, 10, 0.5, 10), RATE);
+        env.note_on();
+
+        let held = render(&mut env, 30);
+        assert_eq!(held[9], 1.0);
+        assert!(held[8] < 1.0);
+        assert_eq!(held[19], 0.5);
+        assert!(held[18] > 0.5);
+        assert_eq!(held[29], 0.5);
+
+        env.note_off();
+        let released = render(&mut env, 10);
+        assert_eq!(released[9], 0.0);
+        assert!(!env.is_active());
+    }
+
+    #[test]
+    fn zero_length_stages_are_skipped() {
+        let mut env = Envelope::new(adsr(0, 0, 0.7, 0), RATE);
+        env.note_on();
+
+        assert_eq!(env.next(), 1.0);
+        assert_eq!(env.next(), 0.7);
+        assert_eq!(env.next(), 0.7);
+
+        env.note_off();
+        assert_eq!(env.next(), 0.0);
+        assert!(!env.is_active());
+    }
+
+    #[test]
+    fn retrigger_starts_from_the_current_level() {
+        let mut env = Envelope::new(adsr(10, 10, 0.5, 100), RATE);
+        env.note_on();
+        render(&mut env, 30);
+        env.note_off();
+        render(&mut env, 20);
+
+        let before = env.value();
+        env.note_on();
+        let after = env.next();
+
+        assert!(before > 0.3);
+        assert!(
+            after >= before && after - before < 0.1,
+            "{before} -> {after}"
+        );
+    }
+
+    #[test]
+    fn legato_keeps_a_held_envelope_going() {
+        let mut env = Envelope::new(adsr(10, 10, 0.5, 10), RATE);
+        env.note_on();
+        render(&mut env, 5);
+        let level = env.value();
+
+        env.legato();
+        assert!(env.next() > level);
+        render(&mut env, 30);
+
+        env.legato();
+        assert_eq!(env.next(), 0.5);
+
+        // a released one starts over
+        env.note_off();
+        render(&mut env, 5);
+        env.legato();
+        render(&mut env, 10);
+        assert_eq!(env.value(), 1.0);
+    }
+
+    #[test]
+    fn edits_apply_to_a_sounding_envelope() {
+        let mut env = Envelope::new(adsr(10, 10, 0.5, 1_000), RATE);
+        env.note_on();
+        render(&mut env, 30);
+
+        let mut config = env.config().clone();
+        config.sustain_level = 0.8;
+        env.set_config(&config);
+        assert_eq!(env.next(), 0.8);
+
+        // cutting a long release short ends it right away
+        env.note_off();
+        render(&mut env, 100);
+        config.release = TimeMs(0);
+        env.set_config(&config);
+        assert_eq!(env.next(), 0.0);
+        assert!(!env.is_active());
+    }
+
+    #[test]
+    fn time_scale_stretches_the_stages() {
+        let mut env = Envelope::new(adsr(10, 10, 0.5, 10), RATE);
+        env.set_time_scale(TimeScale {
+            attack: 2.0,
+            decay: 0.5,
+            release: 3.0,
+        });
+        env.note_on();
+
+        let held = render(&mut env, 30);
+        assert!(held[18] < 1.0);
+        assert_eq!(held[19], 1.0);
+        assert_eq!(held[24], 0.5);
+
+        env.note_off();
+        render(&mut env, 29);
+        assert!(env.is_active());
+        env.next();
+        assert!(!env.is_active());
+    }
+
+    #[test]
+    fn curves_bend_the_stages() {
+        let midpoints = |curve: Curve| {
+            let mut config = adsr(100, 100, 0.0, 100);
+            config.attack_curve = curve;
+            config.decay_curve = curve;
+            let mut env = Envelope::new(config, RATE);
+            env.note_on();
+
+            let samples = render(&mut env, 200);
+            (samples[49], samples[149])
+        };
+
+        let (linear_attack, linear_decay) = midpoints(Curve::Linear);
+        let (exp_attack, exp_decay) = midpoints(Curve::Exponential);
+        let (log_attack, log_decay) = midpoints(Curve::Logarithmic);
+
+        assert!((linear_attack - 0.5).abs() < 1e-5 && (linear_decay - 0.5).abs() < 1e-5);
+        // exponential is slow off zero and quick to fall from the top
+        assert!(exp_attack < linear_attack && exp_decay < linear_decay);
+        assert!(log_attack > linear_attack && log_decay > linear_decay);
+    }
+
+    #[test]
+    fn curves_reach_their_targets() {
+        for curve in [Curve::Linear, Curve::Exponential, Curve::Logarithmic] {
+            let mut config = adsr(20, 20, 0.4, 20);
+            config.attack_curve = curve;
+            config.decay_curve = curve;
+            config.release_curve = curve;
+            let mut env = Envelope::new(config, RATE);
+
+            env.note_on();
+            let held = render(&mut env, 50);
+            assert_eq!(held[19], 1.0);
+            assert_eq!(held[49], 0.4);
+            assert!(held.iter().all(|v| (0.0..=1.0).contains(v)));
+
+            env.note_off();
+            render(&mut env, 20);
+            assert!(!env.is_active());
+        }
+    }
+}
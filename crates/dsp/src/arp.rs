@@ -0,0 +1,470 @@
+use defmt::{Format, info};
+use heapless::Vec;
+use libm::roundf;
+use midi_parser::parser::{MidiMessage, Note, Velocity};
+
+use crate::{
+    clock::StepClock, consts::MAX_HELD_NOTES, encoder::Rotation, lfo::Division, mono::NoteStack,
+    noise::Rng,
+};
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum ArpMode {
+    Up,
+    Down,
+    /// Up then down, without playing the top and bottom notes twice
+    UpDown,
+    Random,
+    /// In the order the keys were pressed
+    AsPlayed,
+    /// All the keys at once, an octave up every step
+    Chord,
+}
+
+impl ArpMode {
+    pub fn next(&self) -> Self {
+        match self {
+            ArpMode::Up => ArpMode::Down,
+            ArpMode::Down => ArpMode::UpDown,
+            ArpMode::UpDown => ArpMode::Random,
+            ArpMode::Random => ArpMode::AsPlayed,
+            ArpMode::AsPlayed => ArpMode::Chord,
+            ArpMode::Chord => ArpMode::Up,
+        }
+    }
+}
+
+pub struct ArpSettings {
+    pub enabled: bool,
+    pub mode: ArpMode,
+    /// `1..=4`
+    pub octaves: u8,
+    /// `0.05..=1.0`, part of a step the notes sound for
+    pub gate: f32,
+    /// Keeps playing the keys let go until a new chord is played
+    pub latch: bool,
+}
+
+impl ArpSettings {
+    pub const fn init() -> Self {
+        Self {
+            enabled: false,
+            mode: ArpMode::Up,
+            octaves: 1,
+            gate: 0.5,
+            latch: false,
+        }
+    }
+}
+
+/// Turns the keys held into a pattern of notes, between the MIDI input and
+/// the voice pool
+pub struct Arpeggiator {
+    settings: ArpSettings,
+    clock: StepClock,
+    /// Keys down, in the order played
+    down: NoteStack,
+    /// Keys it plays: the ones down, or with latch the last chord played
+    keys: NoteStack,
+    /// Notes it has on in the voice pool
+    sounding: Vec<Note, MAX_HELD_NOTES>,
+    /// Position in the pattern of the next step
+    step: usize,
+    /// Samples until the notes of the step end
+    until_release: f32,
+    rng: Rng,
+}
+
+impl Arpeggiator {
+    pub const fn init() -> Self {
+        Self {
+            settings: ArpSettings::init(),
+            clock: StepClock::init(Division::Sixteenth),
+            down: NoteStack::new(),
+            keys: NoteStack::new(),
+            sounding: Vec::new(),
+            step: 0,
+            until_release: 0.0,
+            rng: Rng::new(1),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.clock.set_tempo(bpm);
+    }
+
+    /// BPM it steps at, measured from the ticks when on the MIDI clock
+    pub fn tempo(&self) -> f32 {
+        self.clock.tempo()
+    }
+
+    /// BPM of the MIDI clock, when it steps on it
+    pub fn midi_tempo(&self) -> Option<f32> {
+        self.clock.midi_tempo()
+    }
+
+    pub fn note_on(&mut self, note: &Note, velocity: &Velocity) {
+        if self.down.is_empty() {
+            // a new chord, replacing a latched one
+            self.keys.clear();
+        }
+        if self.keys.is_empty() {
+            // on the internal clock the first step plays right away, on the
+            // MIDI clock it waits for the next step of the grid
+            self.step = 0;
+            self.clock.restart();
+        }
+
+        let velocity = velocity.0 as f32 / 127.0;
+        self.down.push(note, velocity);
+        self.keys.push(note, velocity);
+    }
+
+    pub fn note_off(&mut self, note: &Note) {
+        self.down.remove(note);
+        if !self.settings.latch {
+            self.keys.remove(note);
+        }
+    }
+
+    /// Runs a sample on, sending the notes of the steps to `play`
+    pub fn next_sample(&mut self, mut play: impl FnMut(MidiMessage)) {
+        if !self.sounding.is_empty() && self.until_release <= 0.0 {
+            self.release(&mut play);
+        }
+
+        if let Some(samples) = self.clock.next_sample()
+            && self.settings.enabled
+        {
+            self.advance(samples, &mut play);
+        }
+
+        self.until_release -= 1.0;
+    }
+
+    /// A MIDI clock tick
+    pub fn clock(&mut self, mut play: impl FnMut(MidiMessage)) {
+        if let Some(samples) = self.clock.tick()
+            && self.settings.enabled
+        {
+            self.advance(samples, &mut play);
+        }
+    }
+
+    /// MIDI start, the next tick plays the first step
+    pub fn start(&mut self) {
+        self.step = 0;
+        self.clock.start();
+    }
+
+    /// MIDI continue
+    pub fn resume(&mut self) {
+        self.clock.resume();
+    }
+
+    /// MIDI stop, the notes end right away
+    pub fn stop(&mut self) {
+        self.clock.stop();
+        self.until_release = 0.0;
+    }
+
+    /// Plays the next step, `samples` long
+    fn advance(&mut self, samples: f32, play: &mut impl FnMut(MidiMessage)) {
+        self.release(play);
+
+        if self.keys.is_empty() {
+            self.step = 0;
+            return;
+        }
+
+        if self.settings.mode == ArpMode::Chord {
+            let octave = self.step % self.settings.octaves as usize;
+            for &(note, velocity) in self.keys.notes() {
+                let note = transpose(&note, octave);
+                play(MidiMessage::NoteOn(note, to_velocity(velocity)));
+                let _ = self.sounding.push(note);
+            }
+        } else {
+            let (note, velocity) = self.pick(self.step);
+            play(MidiMessage::NoteOn(note, to_velocity(velocity)));
+            let _ = self.sounding.push(note);
+        }
+
+        self.step = self.step.wrapping_add(1);
+        self.until_release = self.settings.gate * samples;
+    }
+
+    /// The note at `step` of the pattern, with its velocity
+    fn pick(&mut self, step: usize) -> (Note, f32) {
+        let mut keys: Vec<(Note, f32), MAX_HELD_NOTES> =
+            self.keys.notes().iter().copied().collect();
+        if self.settings.mode != ArpMode::AsPlayed {
+            keys.sort_unstable_by_key(|(note, _)| note.num);
+        }
+
+        let span = keys.len() * self.settings.octaves as usize;
+        let index = match self.settings.mode {
+            ArpMode::Up | ArpMode::AsPlayed | ArpMode::Chord => step % span,
+            ArpMode::Down => span - 1 - step % span,
+            ArpMode::UpDown => {
+                let cycle = (2 * span).saturating_sub(2).max(1);
+                let i = step % cycle;
+                if i < span { i } else { cycle - i }
+            }
+            ArpMode::Random => self.rng.next_u32() as usize % span,
+        };
+
+        let (note, velocity) = keys[index % keys.len()];
+        (transpose(&note, index / keys.len()), velocity)
+    }
+
+    fn release(&mut self, play: &mut impl FnMut(MidiMessage)) {
+        for note in self.sounding.iter() {
+            play(MidiMessage::NoteOff(*note, Velocity(64)));
+        }
+        self.sounding.clear();
+    }
+
+    pub fn adjust(&mut self, param: &ArpParams, rotation: Rotation) {
+        let settings = &mut self.settings;
+        match param {
+            ArpParams::Enabled => {
+                settings.enabled = rotation == Rotation::Right;
+                if !settings.enabled {
+                    self.down.clear();
+                    self.keys.clear();
+                    self.until_release = 0.0;
+                }
+                info!("Set arpeggiator: {}", settings.enabled);
+            }
+            ArpParams::Mode => {
+                settings.mode = settings.mode.next();
+                info!("Set arpeggiator mode: {}", settings.mode);
+            }
+            ArpParams::Octaves => {
+                settings.octaves = match rotation {
+                    Rotation::Right => (settings.octaves + 1).min(4),
+                    Rotation::Left => (settings.octaves - 1).max(1),
+                };
+                info!("Set arpeggiator octaves: {}", settings.octaves);
+            }
+            ArpParams::Division => self.clock.adjust_division(rotation),
+            ArpParams::Swing => self.clock.adjust_swing(rotation),
+            ArpParams::Gate => {
+                settings.gate = match rotation {
+                    Rotation::Right => (settings.gate + 0.05).min(1.0),
+                    Rotation::Left => (settings.gate - 0.05).max(0.05),
+                };
+                info!("Set arpeggiator gate: {}", settings.gate);
+            }
+            ArpParams::Latch => {
+                settings.latch = rotation == Rotation::Right;
+                if !settings.latch {
+                    self.keys = self.down.clone();
+                }
+                info!("Set arpeggiator latch: {}", settings.latch);
+            }
+            ArpParams::Clock => self.clock.switch_source(),
+        }
+    }
+}
+
+/// `note` `octaves` up, dropping back an octave at a time past the MIDI range
+fn transpose(note: &Note, octaves: usize) -> Note {
+    let mut num = note.num as usize + 12 * octaves;
+    while num > 127 {
+        num -= 12;
+    }
+
+    Note::new(num as u8)
+}
+
+fn to_velocity(velocity: f32) -> Velocity {
+    Velocity(roundf(velocity * 127.0) as u8)
+}
+
+#[derive(Debug, Format)]
+pub enum ArpParams {
+    Enabled,
+    Mode,
+    Octaves,
+    Division,
+    Swing,
+    Gate,
+    Latch,
+    Clock,
+}
+
+impl ArpParams {
+    pub const fn init_param() -> Self {
+        Self::Enabled
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use ArpParams::*;
+
+        match param {
+            Enabled => Some(Mode),
+            Mode => Some(Octaves),
+            Octaves => Some(Division),
+            Division => Some(Swing),
+            Swing => Some(Gate),
+            Gate => Some(Latch),
+            Latch => Some(Clock),
+            Clock => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ClockSource;
+
+    /// 96 kHz at 120 BPM
+    const SIXTEENTH: usize = 12_000;
+
+    fn arp(mode: ArpMode, octaves: u8) -> Arpeggiator {
+        let mut arp = Arpeggiator::init();
+        arp.settings.enabled = true;
+        arp.settings.mode = mode;
+        arp.settings.octaves = octaves;
+        arp
+    }
+
+    fn press(arp: &mut Arpeggiator, notes: &[u8]) {
+        for &num in notes {
+            arp.note_on(&Note::new(num), &Velocity(100));
+        }
+    }
+
+    /// Sample and note of the note ons over `samples`
+    fn run(arp: &mut Arpeggiator, samples: usize) -> std::vec::Vec<(usize, u8)> {
+        let mut ons = std::vec::Vec::new();
+        for i in 0..samples {
+            arp.next_sample(|msg| {
+                if let MidiMessage::NoteOn(note, _) = msg {
+                    ons.push((i, note.num));
+                }
+            });
+        }
+        ons
+    }
+
+    fn notes(arp: &mut Arpeggiator, steps: usize) -> std::vec::Vec<u8> {
+        run(arp, steps * SIXTEENTH)
+            .into_iter()
+            .map(|(_, num)| num)
+            .collect()
+    }
+
+    #[test]
+    fn modes_order_the_notes() {
+        let mut up = arp(ArpMode::Up, 2);
+        press(&mut up, &[64, 60, 67]);
+        assert_eq!(notes(&mut up, 7), [60, 64, 67, 72, 76, 79, 60]);
+
+        let mut down = arp(ArpMode::Down, 1);
+        press(&mut down, &[64, 60, 67]);
+        assert_eq!(notes(&mut down, 4), [67, 64, 60, 67]);
+
+        let mut up_down = arp(ArpMode::UpDown, 1);
+        press(&mut up_down, &[64, 60, 67]);
+        assert_eq!(notes(&mut up_down, 6), [60, 64, 67, 64, 60, 64]);
+
+        let mut played = arp(ArpMode::AsPlayed, 1);
+        press(&mut played, &[64, 60, 67]);
+        assert_eq!(notes(&mut played, 4), [64, 60, 67, 64]);
+
+        let mut random = arp(ArpMode::Random, 2);
+        press(&mut random, &[64, 60, 67]);
+        assert!(
+            notes(&mut random, 16)
+                .iter()
+                .all(|n| [60, 64, 67, 72, 76, 79].contains(n))
+        );
+    }
+
+    #[test]
+    fn chord_plays_the_keys_together() {
+        let mut arp = arp(ArpMode::Chord, 2);
+        press(&mut arp, &[60, 64]);
+
+        assert_eq!(
+            run(&mut arp, 2 * SIXTEENTH),
+            [(0, 60), (0, 64), (SIXTEENTH, 72), (SIXTEENTH, 76)]
+        );
+    }
+
+    #[test]
+    fn steps_land_on_the_sample() {
+        let mut arp = arp(ArpMode::Up, 1);
+        arp.settings.gate = 0.25;
+        press(&mut arp, &[60]);
+
+        let mut events = std::vec::Vec::new();
+        for i in 0..2 * SIXTEENTH {
+            arp.next_sample(|msg| events.push((i, msg)));
+        }
+
+        let (on, off) = (MidiMessage::NoteOn, MidiMessage::NoteOff);
+        let note = Note::new(60);
+        assert_eq!(
+            events,
+            [
+                (0, on(note, Velocity(100))),
+                (SIXTEENTH / 4, off(note, Velocity(64))),
+                (SIXTEENTH, on(note, Velocity(100))),
+                (SIXTEENTH * 5 / 4, off(note, Velocity(64))),
+            ]
+        );
+    }
+
+    #[test]
+    fn latch_holds_the_chord_until_a_new_one() {
+        let mut arp = arp(ArpMode::Up, 1);
+        arp.settings.latch = true;
+        press(&mut arp, &[60, 64]);
+        arp.note_off(&Note::new(60));
+        arp.note_off(&Note::new(64));
+        assert_eq!(notes(&mut arp, 3), [60, 64, 60]);
+
+        press(&mut arp, &[67]);
+        assert_eq!(notes(&mut arp, 2), [67, 67]);
+
+        arp.adjust(&ArpParams::Latch, Rotation::Left);
+        arp.note_off(&Note::new(67));
+        assert!(notes(&mut arp, 2).is_empty());
+    }
+
+    #[test]
+    fn midi_clock_drives_the_steps() {
+        let mut arp = arp(ArpMode::Up, 1);
+        arp.clock.source = ClockSource::Midi;
+        press(&mut arp, &[60, 64]);
+        arp.start();
+
+        let mut ons = std::vec::Vec::new();
+        for tick in 0..13 {
+            arp.clock(|msg| {
+                if let MidiMessage::NoteOn(note, _) = msg {
+                    ons.push((tick, note.num));
+                }
+            });
+            run(&mut arp, 1_000);
+        }
+
+        assert_eq!(ons, [(0, 60), (6, 64), (12, 60)]);
+        assert_eq!(arp.tempo(), 240.0);
+
+        arp.stop();
+        let mut after_stop = 0;
+        arp.clock(|_| after_stop += 1);
+        assert_eq!(after_stop, 0);
+    }
+}
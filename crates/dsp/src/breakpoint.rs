@@ -0,0 +1,505 @@
+use defmt::{Format, info};
+use heapless::Vec;
+
+use crate::{
+    adsr::{Curve, Generator, TimeMs, TimeScale},
+    consts::ENVELOPE_STAGES,
+    encoder::Rotation,
+};
+
+/// A ramp from wherever the envelope is to `level`
+#[derive(Clone)]
+pub struct Stage {
+    /// `0.0..=1.0`
+    pub level: f32,
+    pub time: TimeMs,
+    pub curve: Curve,
+}
+
+impl Stage {
+    pub const fn init() -> Self {
+        Self {
+            level: 0.0,
+            time: TimeMs(100),
+            curve: Curve::Linear,
+        }
+    }
+}
+
+/// Release of an envelope held on its last stage, which leaves no stages
+/// to release with
+static IMPLICIT_RELEASE: Stage = Stage {
+    level: 0.0,
+    time: TimeMs(20),
+    curve: Curve::Linear,
+};
+
+/// Stages of a breakpoint envelope. The stages after the sustain point and
+/// the loop are the release; without either, the envelope runs through
+/// once and ignores the note off. It goes idle after the last stage, which
+/// should end at zero, or after `IMPLICIT_RELEASE` if it's held
+#[derive(Clone)]
+pub struct Breakpoints {
+    pub stages: Vec<Stage, ENVELOPE_STAGES>,
+    /// Stage whose level is held until the note off
+    pub sustain: Option<usize>,
+    /// First and last stage repeated until the note off
+    pub loop_points: Option<(usize, usize)>,
+}
+
+impl Breakpoints {
+    pub const fn init() -> Self {
+        Self {
+            stages: Vec::new(),
+            sustain: None,
+            loop_points: None,
+        }
+    }
+
+    /// First stage of the release, if there is one
+    fn release_stage(&self) -> Option<usize> {
+        // `None` is below any `Some`
+        let held = self.sustain.max(self.loop_points.map(|(_, end)| end));
+        held.map(|stage| stage + 1)
+    }
+
+    pub fn adjust(&mut self, stage: usize, param: &BreakpointParams, rotation: Rotation) {
+        match param {
+            BreakpointParams::Stages => {
+                match rotation {
+                    Rotation::Right => {
+                        let _ = self.stages.push(Stage::init());
+                    }
+                    Rotation::Left => {
+                        self.stages.pop();
+                    }
+                }
+
+                // points past the end go away with their stage
+                let count = self.stages.len();
+                self.sustain = self.sustain.filter(|&s| s < count);
+                self.loop_points = self.loop_points.filter(|&(_, end)| end < count);
+                info!("Set envelope stages: {}", count);
+            }
+            BreakpointParams::Level => {
+                let Some(stage) = self.stages.get_mut(stage) else {
+                    return;
+                };
+                let new = if rotation == Rotation::Right {
+                    stage.level + 0.01
+                } else {
+                    stage.level - 0.01
+                };
+
+                stage.level = new.clamp(0.0, 1.0);
+                info!("Set stage level: {}", stage.level);
+            }
+            BreakpointParams::Time => {
+                let Some(stage) = self.stages.get_mut(stage) else {
+                    return;
+                };
+                stage.time = match rotation {
+                    Rotation::Right => TimeMs(stage.time.0.saturating_add(5).min(10_000)),
+                    Rotation::Left => TimeMs(stage.time.0.saturating_sub(5)),
+                };
+                info!("Set stage time: {} ms", stage.time.0);
+            }
+            BreakpointParams::Curve => {
+                let Some(stage) = self.stages.get_mut(stage) else {
+                    return;
+                };
+                stage.curve = stage.curve.next();
+                info!("Set stage curve: {}", stage.curve);
+            }
+            BreakpointParams::Sustain => {
+                // toggles the sustain point on the selected stage
+                self.sustain = match self.sustain {
+                    Some(s) if s == stage => None,
+                    _ if stage < self.stages.len() => Some(stage),
+                    sustain => sustain,
+                };
+                info!("Set sustain stage: {}", self.sustain);
+            }
+            BreakpointParams::LoopStart => {
+                if stage < self.stages.len() {
+                    let end = self.loop_points.map_or(stage, |(_, end)| end.max(stage));
+                    self.loop_points = Some((stage, end));
+                }
+                info!("Set loop: {}", self.loop_points);
+            }
+            BreakpointParams::LoopEnd => {
+                // on the loop's only stage it turns the loop off
+                self.loop_points = match self.loop_points {
+                    Some((start, end)) if start == stage && end == stage => None,
+                    Some((start, _)) if stage < self.stages.len() => {
+                        Some((start.min(stage), stage))
+                    }
+                    None if stage < self.stages.len() => Some((stage, stage)),
+                    loop_points => loop_points,
+                };
+                info!("Set loop: {}", self.loop_points);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Position {
+    Idle,
+    Ramp(usize),
+    Sustain(usize),
+}
+
+/// Runs through `Breakpoints` like `adsr::Envelope` runs through an ADSR,
+/// including live edits
+#[derive(Clone)]
+pub struct BreakpointEnvelope {
+    config: Breakpoints,
+    sample_rate: f32,
+    /// The first stage counts as the attack, the rest up to the release as
+    /// the decay
+    scale: TimeScale,
+    current_value: f32,
+    position: Position,
+    released: bool,
+    /// How far into the current stage, `0.0..=1.0`
+    progress: f32,
+    /// Level the current stage started from
+    start_value: f32,
+}
+
+impl BreakpointEnvelope {
+    pub const fn new(config: Breakpoints, sample_rate: f32) -> Self {
+        Self {
+            config,
+            sample_rate,
+            scale: TimeScale::ONE,
+            current_value: 0.0,
+            position: Position::Idle,
+            released: false,
+            progress: 0.0,
+            start_value: 0.0,
+        }
+    }
+
+    pub fn config(&self) -> &Breakpoints {
+        &self.config
+    }
+
+    /// Takes effect on the next sample, mid-stage included
+    pub fn set_config(&mut self, config: &Breakpoints) {
+        self.config = config.clone();
+    }
+
+    /// The stage at `index`, or the implicit release after holding the last
+    fn stage(&self, index: usize) -> Option<&Stage> {
+        match self.config.stages.get(index) {
+            None if self.released && self.config.release_stage() == Some(index) => {
+                Some(&IMPLICIT_RELEASE)
+            }
+            stage => stage,
+        }
+    }
+
+    fn enter(&mut self, stage: usize) {
+        if self.stage(stage).is_some() {
+            self.position = Position::Ramp(stage);
+            self.progress = 0.0;
+            self.start_value = self.current_value;
+        } else {
+            self.position = Position::Idle;
+            self.current_value = 0.0;
+        }
+    }
+
+    /// Where to go once `stage` is over
+    fn finish(&mut self, stage: usize) {
+        if !self.released {
+            if let Some((start, end)) = self.config.loop_points
+                && end == stage
+            {
+                return self.enter(start);
+            }
+
+            if self.config.sustain == Some(stage) {
+                self.position = Position::Sustain(stage);
+                return;
+            }
+        }
+
+        self.enter(stage + 1);
+    }
+}
+
+impl Generator for BreakpointEnvelope {
+    /// Starts the first stage from the current level
+    fn note_on(&mut self) {
+        self.released = false;
+        self.enter(0);
+    }
+
+    fn legato(&mut self) {
+        if !self.is_active() || self.is_released() {
+            self.note_on();
+        }
+    }
+
+    fn note_off(&mut self) {
+        if !self.is_active() || self.released {
+            return;
+        }
+
+        self.released = true;
+        if let Some(stage) = self.config.release_stage() {
+            self.enter(stage);
+        }
+    }
+
+    fn set_time_scale(&mut self, scale: TimeScale) {
+        self.scale = scale;
+    }
+
+    fn is_active(&self) -> bool {
+        self.position != Position::Idle
+    }
+
+    fn is_released(&self) -> bool {
+        self.is_active() && self.released
+    }
+
+    fn value(&self) -> f32 {
+        self.current_value.clamp(0.0, 1.0)
+    }
+
+    fn next(&mut self) -> f32 {
+        match self.position {
+            Position::Idle => self.current_value = 0.0,
+            Position::Sustain(stage) => match self.config.stages.get(stage) {
+                Some(stage) => self.current_value = stage.level,
+                None => self.enter(stage),
+            },
+            Position::Ramp(index) => {
+                // edits can take the stage away under us
+                let Some(stage) = self.stage(index) else {
+                    self.enter(index);
+                    return 0.0;
+                };
+                let (time, level, curve) = (stage.time, stage.level, stage.curve);
+
+                let scale = if index == 0 {
+                    self.scale.attack
+                } else if self
+                    .config
+                    .release_stage()
+                    .is_some_and(|release| index >= release)
+                {
+                    self.scale.release
+                } else {
+                    self.scale.decay
+                };
+
+                let step = time.step(self.sample_rate * scale);
+                self.current_value = curve.ramp(self.start_value, level, &mut self.progress, step);
+
+                if self.progress >= 1.0 {
+                    self.finish(index);
+                }
+            }
+        }
+
+        self.current_value.clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum BreakpointParams {
+    /// Adds or removes the last stage
+    Stages,
+    Level,
+    Time,
+    Curve,
+    Sustain,
+    LoopStart,
+    LoopEnd,
+}
+
+impl BreakpointParams {
+    pub const fn init_param() -> Self {
+        Self::Stages
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use BreakpointParams::*;
+
+        match param {
+            Stages => Some(Level),
+            Level => Some(Time),
+            Time => Some(Curve),
+            Curve => Some(Sustain),
+            Sustain => Some(LoopStart),
+            LoopStart => Some(LoopEnd),
+            LoopEnd => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A millisecond is this many samples
+    const RATE: f32 = 1_000.0;
+
+    fn breakpoints(stages: &[(f32, u16)]) -> Breakpoints {
+        let mut config = Breakpoints::init();
+        for &(level, time) in stages {
+            let _ = config.stages.push(Stage {
+                level,
+                time: TimeMs(time),
+                curve: Curve::Linear,
+            });
+        }
+
+        config
+    }
+
+    fn render(env: &mut BreakpointEnvelope, len: usize) -> std::vec::Vec<f32> {
+        (0..len).map(|_| env.next()).collect()
+    }
+
+    #[test]
+    fn runs_through_the_stages() {
+        let config = breakpoints(&[(1.0, 10), (0.2, 10), (0.6, 10), (0.0, 10)]);
+        let mut env = BreakpointEnvelope::new(config, RATE);
+        env.note_on();
+
+        let samples = render(&mut env, 40);
+        assert_eq!(samples[9], 1.0);
+        assert_eq!(samples[19], 0.2);
+        assert_eq!(samples[29], 0.6);
+        assert_eq!(samples[39], 0.0);
+        assert!(!env.is_active());
+    }
+
+    #[test]
+    fn holds_at_the_sustain_point() {
+        let mut config = breakpoints(&[(1.0, 10), (0.5, 10), (0.0, 10)]);
+        config.sustain = Some(1);
+        let mut env = BreakpointEnvelope::new(config, RATE);
+        env.note_on();
+
+        let held = render(&mut env, 100);
+        assert!(held[20..].iter().all(|v| *v == 0.5));
+        assert!(env.is_active() && !env.is_released());
+
+        env.note_off();
+        assert!(env.is_released());
+        assert_eq!(render(&mut env, 10)[9], 0.0);
+        assert!(!env.is_active());
+    }
+
+    #[test]
+    fn holding_the_last_stage_releases_without_a_step() {
+        let mut config = breakpoints(&[(1.0, 10), (0.5, 10)]);
+        config.sustain = Some(1);
+        let mut env = BreakpointEnvelope::new(config, RATE);
+        env.note_on();
+        render(&mut env, 50);
+
+        env.note_off();
+        let released = render(&mut env, 30);
+        let mut last = 0.5;
+        for value in released {
+            assert!(last - value <= 0.5 / 20.0 + 1e-6, "{last} -> {value}");
+            last = value;
+        }
+        assert!(!env.is_active());
+    }
+
+    #[test]
+    fn loops_until_released() {
+        let mut config = breakpoints(&[(1.0, 10), (0.5, 10), (1.0, 10), (0.0, 20)]);
+        config.loop_points = Some((1, 2));
+        let mut env = BreakpointEnvelope::new(config, RATE);
+        env.note_on();
+
+        let held = render(&mut env, 90);
+        // attack, then down and up every 20 samples
+        for cycle in 0..4 {
+            assert_eq!(held[19 + 20 * cycle], 0.5, "cycle {cycle}");
+            assert_eq!(held[29 + 20 * cycle], 1.0, "cycle {cycle}");
+        }
+
+        env.note_off();
+        let released = render(&mut env, 20);
+        assert!(released.windows(2).all(|w| w[1] <= w[0]));
+        assert!(!env.is_active());
+    }
+
+    #[test]
+    fn one_shot_ignores_the_note_off() {
+        let config = breakpoints(&[(1.0, 10), (0.0, 10)]);
+        let mut env = BreakpointEnvelope::new(config, RATE);
+        env.note_on();
+
+        render(&mut env, 5);
+        env.note_off();
+        assert_eq!(render(&mut env, 5)[4], 1.0);
+    }
+
+    #[test]
+    fn zero_time_stages_jump() {
+        let mut config = breakpoints(&[(0.8, 0), (0.3, 0), (0.0, 0)]);
+        config.sustain = Some(1);
+        let mut env = BreakpointEnvelope::new(config, RATE);
+        env.note_on();
+
+        assert_eq!(render(&mut env, 3), [0.8, 0.3, 0.3]);
+        env.note_off();
+        assert_eq!(env.next(), 0.0);
+        assert!(!env.is_active());
+    }
+
+    #[test]
+    fn retrigger_starts_from_the_current_level() {
+        let mut config = breakpoints(&[(1.0, 10), (0.0, 100)]);
+        config.sustain = Some(0);
+        let mut env = BreakpointEnvelope::new(config, RATE);
+        env.note_on();
+        render(&mut env, 20);
+        env.note_off();
+        render(&mut env, 20);
+
+        let before = env.value();
+        env.note_on();
+        let after = env.next();
+        assert!(
+            after >= before && after - before < 0.1,
+            "{before} -> {after}"
+        );
+    }
+
+    #[test]
+    fn empty_envelope_stays_idle() {
+        let mut env = BreakpointEnvelope::new(Breakpoints::init(), RATE);
+        env.note_on();
+
+        assert!(!env.is_active());
+        assert_eq!(env.next(), 0.0);
+    }
+
+    #[test]
+    fn removing_stages_drops_their_points() {
+        let mut config = breakpoints(&[(1.0, 10), (0.5, 10), (0.0, 10)]);
+        config.sustain = Some(2);
+        config.loop_points = Some((0, 1));
+
+        config.adjust(0, &BreakpointParams::Stages, Rotation::Left);
+        assert_eq!(config.sustain, None);
+        assert_eq!(config.loop_points, Some((0, 1)));
+
+        config.adjust(0, &BreakpointParams::Stages, Rotation::Left);
+        assert_eq!(config.loop_points, None);
+    }
+}
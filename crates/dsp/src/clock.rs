@@ -0,0 +1,239 @@
+use defmt::{Format, info};
+
+use crate::{consts::SAMPLE_RATE, encoder::Rotation, lfo::Division};
+
+/// MIDI clock ticks per quarter note
+const PPQN: f32 = 24.0;
+/// Longer gaps between MIDI clock ticks are a stopped clock, not a tempo
+const MAX_TICK_SAMPLES: f32 = SAMPLE_RATE;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum ClockSource {
+    /// Steps at the tempo set on the synth
+    Internal,
+    /// Steps on incoming MIDI clock ticks
+    Midi,
+}
+
+/// Times the steps of the arpeggiator and the sequencer, on either clock,
+/// following the MIDI transport
+pub struct StepClock {
+    pub source: ClockSource,
+    pub division: Division,
+    /// `0.0..=0.5`, how much longer the on-beat steps are than the
+    /// off-beat ones, as a part of a step
+    pub swing: f32,
+    /// BPM of the internal clock
+    tempo: f32,
+    /// Until the next step, in samples on the internal clock and in ticks
+    /// on the MIDI clock
+    until_step: f32,
+    /// Steps so far, for telling the off-beats that swing
+    beat: u32,
+    /// Samples since the last MIDI clock tick, and between the last two
+    since_tick: f32,
+    tick_samples: f32,
+    /// A MIDI stop holds the steps until a start or continue
+    running: bool,
+}
+
+impl StepClock {
+    pub const fn init(division: Division) -> Self {
+        Self {
+            source: ClockSource::Internal,
+            division,
+            swing: 0.0,
+            tempo: 120.0,
+            until_step: 0.0,
+            beat: 0,
+            // the first tick has nothing to measure from
+            since_tick: MAX_TICK_SAMPLES,
+            // 120 BPM until the clock says otherwise
+            tick_samples: SAMPLE_RATE / 48.0,
+            running: true,
+        }
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.tempo = bpm;
+    }
+
+    /// BPM it steps at, measured from the ticks when on the MIDI clock
+    pub fn tempo(&self) -> f32 {
+        match self.source {
+            ClockSource::Internal => self.tempo,
+            ClockSource::Midi => 60.0 * SAMPLE_RATE / (PPQN * self.tick_samples),
+        }
+    }
+
+    /// BPM measured from the ticks, when on the MIDI clock
+    pub fn midi_tempo(&self) -> Option<f32> {
+        (self.source == ClockSource::Midi).then(|| self.tempo())
+    }
+
+    /// On the internal clock, the next sample starts a step. The MIDI clock
+    /// keeps its grid
+    pub fn restart(&mut self) {
+        if self.source == ClockSource::Internal {
+            self.beat = 0;
+            self.until_step = 0.0;
+        }
+    }
+
+    /// Runs a sample on, returning the length in samples of a step that
+    /// starts on it
+    pub fn next_sample(&mut self) -> Option<f32> {
+        self.since_tick += 1.0;
+
+        if self.source != ClockSource::Internal || !self.running {
+            return None;
+        }
+
+        let step = if self.until_step <= 0.0 {
+            let beats = self.step_beats();
+            self.until_step += beats * self.samples_per_beat();
+            Some(beats * self.samples_per_beat())
+        } else {
+            None
+        };
+        self.until_step -= 1.0;
+
+        step
+    }
+
+    /// A MIDI clock tick, returning the length in samples of a step that
+    /// starts on it
+    pub fn tick(&mut self) -> Option<f32> {
+        if self.since_tick < MAX_TICK_SAMPLES {
+            self.tick_samples = self.since_tick.max(1.0);
+        }
+        self.since_tick = 0.0;
+
+        if self.source != ClockSource::Midi || !self.running {
+            return None;
+        }
+
+        // steps with swing don't fall on whole ticks, the rest carries over
+        let step = if self.until_step < 0.5 {
+            let beats = self.step_beats();
+            self.until_step += beats * PPQN;
+            Some(beats * self.samples_per_beat())
+        } else {
+            None
+        };
+        self.until_step -= 1.0;
+
+        step
+    }
+
+    /// MIDI start, the next tick or sample starts the first step
+    pub fn start(&mut self) {
+        self.running = true;
+        self.beat = 0;
+        self.until_step = 0.0;
+    }
+
+    /// MIDI continue
+    pub fn resume(&mut self) {
+        self.running = true;
+    }
+
+    /// MIDI stop
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    fn samples_per_beat(&self) -> f32 {
+        60.0 * SAMPLE_RATE / self.tempo()
+    }
+
+    /// Length in quarter notes of the step starting
+    fn step_beats(&mut self) -> f32 {
+        let swing = if self.beat % 2 == 0 {
+            1.0 + self.swing
+        } else {
+            1.0 - self.swing
+        };
+        self.beat = self.beat.wrapping_add(1);
+
+        self.division.beats() * swing
+    }
+
+    pub fn adjust_division(&mut self, rotation: Rotation) {
+        self.division = match rotation {
+            Rotation::Right => self.division.faster(),
+            Rotation::Left => self.division.slower(),
+        };
+        info!("Set step rate: {}", self.division);
+    }
+
+    pub fn adjust_swing(&mut self, rotation: Rotation) {
+        self.swing = match rotation {
+            Rotation::Right => (self.swing + 0.05).min(0.5),
+            Rotation::Left => (self.swing - 0.05).max(0.0),
+        };
+        info!("Set swing: {}", self.swing);
+    }
+
+    pub fn switch_source(&mut self) {
+        self.source = match self.source {
+            ClockSource::Internal => ClockSource::Midi,
+            ClockSource::Midi => ClockSource::Internal,
+        };
+        self.until_step = 0.0;
+        info!("Set clock: {}", self.source);
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples the steps start on over `samples`
+    fn starts(clock: &mut StepClock, samples: usize) -> std::vec::Vec<usize> {
+        (0..samples)
+            .filter(|_| clock.next_sample().is_some())
+            .collect()
+    }
+
+    #[test]
+    fn swing_delays_the_off_beats() {
+        let mut clock = StepClock::init(Division::Sixteenth);
+        clock.swing = 0.25;
+
+        // 12 000 samples a step at 120 BPM
+        assert_eq!(starts(&mut clock, 48_000), [0, 15_000, 24_000, 39_000]);
+    }
+
+    #[test]
+    fn midi_clock_sets_the_tempo_and_transport() {
+        let mut clock = StepClock::init(Division::Sixteenth);
+        clock.source = ClockSource::Midi;
+        clock.start();
+
+        let mut steps = std::vec::Vec::new();
+        for tick in 0..13 {
+            if let Some(samples) = clock.tick() {
+                steps.push((tick, samples));
+            }
+            for _ in 0..1_000 {
+                assert_eq!(clock.next_sample(), None);
+            }
+        }
+
+        // a tick every 1000 samples is 240 BPM
+        assert_eq!(clock.tempo(), 240.0);
+        assert_eq!(
+            steps
+                .iter()
+                .map(|(tick, _)| *tick)
+                .collect::<std::vec::Vec<_>>(),
+            [0, 6, 12]
+        );
+        assert_eq!(steps[1].1, 6_000.0);
+
+        clock.stop();
+        assert_eq!(clock.tick(), None);
+    }
+}
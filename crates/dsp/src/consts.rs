@@ -1,5 +1,28 @@
 pub const SAMPLE_RATE: f32 = 96_000.0;
-pub const TABLE_SIZE: usize = 64;
+pub const TABLE_SIZE: usize = 256;
 pub const MAX_DAC_VALUE: u16 = 4095;
 pub const MAX_VOICES: usize = 8;
 pub const MAX_TRACKING_VOICES: usize = MAX_VOICES * 2;
+pub const MAX_OSCILLATORS: usize = 3;
+pub const FM_OPERATORS: usize = 4;
+pub const MAX_UNISON: usize = MAX_VOICES;
+pub const LFOS: usize = 2;
+pub const MOD_SLOTS: usize = 8;
+pub const ENVELOPE_STAGES: usize = 8;
+/// Keys remembered in mono, for returning to them on release
+pub const MAX_HELD_NOTES: usize = 16;
+/// Samples between updates of per-voice modulation
+pub const CONTROL_PERIOD: u32 = 32;
+pub const MAX_STEPS: usize = 64;
+/// Sequencer patterns kept in memory
+pub const PATTERNS: usize = 8;
+/// Frames of a delay line of 0.68 s, 256 KiB as `i16` stereo
+pub const DELAY_FRAMES: usize = 65_536;
+/// Samples of reverb memory, 192 KiB as `f32`, room for the 100 ms
+/// pre-delay and 0.41 s of lines, the longest 65 ms
+pub const REVERB_SAMPLES: usize = 49_152;
+/// Chorus, flanger or phaser slots after the voice mix
+pub const EFFECT_SLOTS: usize = 2;
+/// Frames of the modulated delay line of an effect slot, 21 ms, 16 KiB as
+/// `f32` stereo
+pub const EFFECT_LINE_FRAMES: usize = 2_048;
@@ -0,0 +1,391 @@
+use defmt::{Format, info};
+use libm::{expf, roundf};
+
+use crate::{
+    adsr::TimeMs,
+    consts::{DELAY_FRAMES, SAMPLE_RATE},
+    encoder::Rotation,
+    lfo::Division,
+};
+
+/// A stereo frame of the delay line
+pub type DelayFrame = [i16; 2];
+
+/// The line stores `-4.0..4.0`, the voices summed can go past 1
+const HEADROOM: f32 = 4.0;
+const SCALE: f32 = i16::MAX as f32 / HEADROOM;
+/// Samples a change of delay time crossfades over
+const CROSSFADE: f32 = SAMPLE_RATE * 0.03;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum DelayTime {
+    Ms(TimeMs),
+    /// A division at the current tempo
+    Sync(Division),
+}
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum DelayMode {
+    /// Each side echoes itself
+    Stereo,
+    /// The echoes bounce from side to side
+    PingPong,
+}
+
+pub struct DelaySettings {
+    pub mode: DelayMode,
+    pub time: DelayTime,
+    /// `0.0..=0.95`
+    pub feedback: f32,
+    /// Cutoff of the low-pass in the feedback loop, each echo gets darker
+    pub damping: f32,
+    /// `0.0..=1.0`, dry to wet
+    pub mix: f32,
+}
+
+impl DelaySettings {
+    pub const fn init() -> Self {
+        Self {
+            mode: DelayMode::Stereo,
+            time: DelayTime::Sync(Division::Eighth),
+            feedback: 0.4,
+            damping: 6_000.0,
+            mix: 0.0,
+        }
+    }
+}
+
+/// Stereo and ping-pong delay on a line given to it, so it can sit in
+/// whichever RAM has room for it
+pub struct StereoDelay {
+    settings: DelaySettings,
+    line: &'static mut [DelayFrame],
+    write: usize,
+    /// Delay in samples heard, and the one being crossfaded to
+    time: usize,
+    next_time: usize,
+    /// `0.0..=1.0` through the crossfade to `next_time`
+    fade: f32,
+    tempo: f32,
+    /// Damping filter coefficient and state, per side
+    damping: f32,
+    lowpass: [f32; 2],
+}
+
+impl StereoDelay {
+    pub const fn init() -> Self {
+        Self {
+            settings: DelaySettings::init(),
+            line: &mut [],
+            write: 0,
+            time: 1,
+            next_time: 1,
+            fade: 1.0,
+            tempo: 120.0,
+            damping: 0.0,
+            lowpass: [0.0; 2],
+        }
+    }
+
+    /// Gives it the line to delay on, its length sets the longest delay.
+    /// Until then the delay passes the sound through
+    pub fn set_line(&mut self, line: &'static mut [DelayFrame]) {
+        line.fill([0; 2]);
+        self.line = line;
+        self.write = 0;
+        self.time = self.target();
+        self.next_time = self.time;
+        self.fade = 1.0;
+        self.damping = coefficient(self.settings.damping);
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.tempo = bpm;
+    }
+
+    /// Longest delay in samples the line holds, `DELAY_FRAMES` long until
+    /// it is set
+    fn longest(&self) -> usize {
+        match self.line.len() {
+            0 => DELAY_FRAMES - 1,
+            len => len.max(2) - 1,
+        }
+    }
+
+    /// Delay in samples for the settings. A division longer than the line
+    /// halves until it fits, so the echoes stay on the beat
+    fn target(&self) -> usize {
+        let samples = |seconds: f32| roundf(seconds * SAMPLE_RATE) as usize;
+        let longest = self.longest();
+
+        let samples = match self.settings.time {
+            DelayTime::Ms(ms) => samples(ms.0 as f32 / 1000.0),
+            DelayTime::Sync(division) => {
+                let mut seconds = division.beats() * 60.0 / self.tempo;
+                while samples(seconds) > longest {
+                    seconds /= 2.0;
+                }
+                samples(seconds)
+            }
+        };
+
+        samples.clamp(1, longest)
+    }
+
+    pub fn process(&mut self, (left, right): (f32, f32)) -> (f32, f32) {
+        if self.line.is_empty() {
+            return (left, right);
+        }
+
+        // a new time starts crossfading in once the last one is through, the
+        // read position never jumps or sweeps
+        if self.fade >= 1.0 {
+            self.time = self.next_time;
+            let target = self.target();
+            if target != self.time {
+                self.next_time = target;
+                self.fade = 0.0;
+            }
+        }
+
+        let len = self.line.len();
+        let read = |time: usize| self.line[(self.write + len - time) % len];
+        let (current, next) = (read(self.time), read(self.next_time));
+
+        let mut delayed = [0.0; 2];
+        for (side, out) in delayed.iter_mut().enumerate() {
+            let current = current[side] as f32 / SCALE;
+            let next = next[side] as f32 / SCALE;
+            *out = current + (next - current) * self.fade;
+        }
+        if self.fade < 1.0 {
+            self.fade = (self.fade + 1.0 / CROSSFADE).min(1.0);
+        }
+
+        for (lowpass, delayed) in self.lowpass.iter_mut().zip(delayed) {
+            *lowpass += (delayed - *lowpass) * self.damping;
+        }
+        let [back_left, back_right] = self.lowpass.map(|side| side * self.settings.feedback);
+
+        let input = match self.settings.mode {
+            DelayMode::Stereo => [left + back_left, right + back_right],
+            // in from the left, then across on every repeat
+            DelayMode::PingPong => [(left + right) / 2.0 + back_right, back_left],
+        };
+        self.line[self.write] =
+            input.map(|side| roundf(side * SCALE).clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+        self.write = (self.write + 1) % len;
+
+        let mix = self.settings.mix;
+        (
+            left * (1.0 - mix) + delayed[0] * mix,
+            right * (1.0 - mix) + delayed[1] * mix,
+        )
+    }
+
+    pub fn adjust(&mut self, param: &DelayParams, rotation: Rotation) {
+        let longest_ms = (self.longest() as f32 * 1000.0 / SAMPLE_RATE) as u16;
+        let settings = &mut self.settings;
+        let up = rotation == Rotation::Right;
+
+        match param {
+            DelayParams::Mode => {
+                settings.mode = match settings.mode {
+                    DelayMode::Stereo => DelayMode::PingPong,
+                    DelayMode::PingPong => DelayMode::Stereo,
+                };
+                info!("Set delay mode: {}", settings.mode);
+            }
+            DelayParams::Time => {
+                settings.time = match (settings.time, rotation) {
+                    (DelayTime::Ms(ms), Rotation::Right) => {
+                        DelayTime::Ms(TimeMs(ms.0.saturating_add(5).min(longest_ms)))
+                    }
+                    (DelayTime::Ms(ms), Rotation::Left) => {
+                        DelayTime::Ms(TimeMs(ms.0.saturating_sub(5).clamp(1, longest_ms)))
+                    }
+                    (DelayTime::Sync(division), Rotation::Right) => {
+                        DelayTime::Sync(division.slower())
+                    }
+                    (DelayTime::Sync(division), Rotation::Left) => {
+                        DelayTime::Sync(division.faster())
+                    }
+                };
+                info!("Set delay time: {}", settings.time);
+            }
+            DelayParams::Sync => {
+                settings.time = match rotation {
+                    Rotation::Right => DelayTime::Sync(Division::Eighth),
+                    Rotation::Left => DelayTime::Ms(TimeMs(250)),
+                };
+                info!("Set delay time: {}", settings.time);
+            }
+            DelayParams::Feedback => {
+                settings.feedback = if up {
+                    (settings.feedback + 0.05).min(0.95)
+                } else {
+                    (settings.feedback - 0.05).max(0.0)
+                };
+                info!("Set delay feedback: {}", settings.feedback);
+            }
+            DelayParams::Damping => {
+                settings.damping = if up {
+                    (settings.damping * 1.1).min(20_000.0)
+                } else {
+                    (settings.damping / 1.1).max(200.0)
+                };
+                self.damping = coefficient(settings.damping);
+                info!("Set delay damping: {} Hz", settings.damping);
+            }
+            DelayParams::Mix => {
+                settings.mix = if up {
+                    (settings.mix + 0.05).min(1.0)
+                } else {
+                    (settings.mix - 0.05).max(0.0)
+                };
+                info!("Set delay mix: {}", settings.mix);
+            }
+        }
+    }
+}
+
+/// One-pole low-pass coefficient for `cutoff`
+fn coefficient(cutoff: f32) -> f32 {
+    1.0 - expf(-core::f32::consts::TAU * cutoff / SAMPLE_RATE)
+}
+
+#[derive(Debug, Format)]
+pub enum DelayParams {
+    Mode,
+    Time,
+    Sync,
+    Feedback,
+    Damping,
+    Mix,
+}
+
+impl DelayParams {
+    pub const fn init_param() -> Self {
+        Self::Mode
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use DelayParams::*;
+
+        match param {
+            Mode => Some(Time),
+            Time => Some(Sync),
+            Sync => Some(Feedback),
+            Feedback => Some(Damping),
+            Damping => Some(Mix),
+            Mix => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 10 ms
+    const TIME: usize = 960;
+
+    fn delay(mode: DelayMode) -> StereoDelay {
+        let mut delay = StereoDelay::init();
+        delay.settings.mode = mode;
+        delay.settings.time = DelayTime::Ms(TimeMs(10));
+        delay.settings.mix = 1.0;
+        delay.set_line(std::vec![[0; 2]; 16 * TIME].leak());
+        delay
+    }
+
+    /// Output for an impulse on the left
+    fn impulse(delay: &mut StereoDelay, samples: usize) -> std::vec::Vec<(f32, f32)> {
+        (0..samples)
+            .map(|i| delay.process(if i == 0 { (1.0, 0.0) } else { (0.0, 0.0) }))
+            .collect()
+    }
+
+    /// Sum of a side over the echo at `echo` times the delay
+    fn echo(out: &[(f32, f32)], echo: usize, right: bool) -> f32 {
+        out[echo * TIME..(echo + 1) * TIME]
+            .iter()
+            .map(|(l, r)| if right { r } else { l })
+            .sum()
+    }
+
+    #[test]
+    fn stereo_echoes_decay_by_the_feedback() {
+        let mut delay = delay(DelayMode::Stereo);
+        let out = impulse(&mut delay, 3 * TIME);
+
+        // the damping smears an echo out, but keeps its sum
+        assert!(out[..TIME].iter().all(|(l, _)| *l == 0.0));
+        assert!((echo(&out, 1, false) - 1.0).abs() < 1e-3);
+        assert!((echo(&out, 2, false) - 0.4).abs() < 1e-3);
+        assert!(out.iter().all(|(_, r)| *r == 0.0));
+    }
+
+    #[test]
+    fn ping_pong_bounces_between_the_sides() {
+        let mut delay = delay(DelayMode::PingPong);
+        let out = impulse(&mut delay, 4 * TIME);
+
+        // the input comes in as the mono sum
+        assert!((echo(&out, 1, false) - 0.5).abs() < 1e-3);
+        assert_eq!(echo(&out, 1, true), 0.0);
+        assert!((echo(&out, 2, true) - 0.2).abs() < 1e-3);
+        assert!((echo(&out, 3, false) - 0.08).abs() < 1e-3);
+    }
+
+    #[test]
+    fn time_changes_dont_click() {
+        let mut delay = delay(DelayMode::Stereo);
+        delay.settings.feedback = 0.0;
+        let sine = |i: usize| libm::sinf(core::f32::consts::TAU * 100.0 * i as f32 / SAMPLE_RATE);
+
+        let mut last = 0.0;
+        let mut biggest_step: f32 = 0.0;
+        for i in 0..8 * TIME {
+            if i == 2 * TIME {
+                delay.settings.time = DelayTime::Ms(TimeMs(25));
+            }
+            let (out, _) = delay.process((sine(i), sine(i)));
+            if i > TIME {
+                biggest_step = biggest_step.max((out - last).abs());
+            }
+            last = out;
+        }
+
+        // a 100 Hz sine moves by at most 0.0066 a sample
+        assert!(biggest_step < 0.01, "{biggest_step}");
+        assert_eq!(delay.time, 25 * TIME / 10);
+    }
+
+    #[test]
+    fn synced_time_follows_the_tempo() {
+        let mut delay = delay(DelayMode::Stereo);
+        delay.settings.time = DelayTime::Sync(Division::ThirtySecond);
+
+        delay.set_tempo(120.0);
+        assert_eq!(delay.target(), 6_000);
+        delay.set_tempo(60.0);
+        assert_eq!(delay.target(), 12_000);
+        // longer than the line, down to a 32nd
+        delay.settings.time = DelayTime::Sync(Division::Bar);
+        assert_eq!(delay.target(), 12_000);
+    }
+
+    #[test]
+    fn time_goes_as_far_as_the_line() {
+        let mut delay = delay(DelayMode::Stereo);
+        for _ in 0..1_000 {
+            delay.adjust(&DelayParams::Time, Rotation::Right);
+        }
+
+        // 16 × 10 ms, less the sample being written
+        assert!(delay.settings.time == DelayTime::Ms(TimeMs(159)));
+        assert_eq!(delay.target(), 159 * TIME / 10);
+    }
+}
@@ -0,0 +1,373 @@
+use core::f32::consts::TAU;
+
+use defmt::{Format, info};
+use libm::{floorf, powf, sinf, sqrtf, tanhf};
+
+use crate::{consts::SAMPLE_RATE, encoder::Rotation};
+
+/// Half-band low-pass taps, Kaiser windowed, flat to a quarter of the
+/// oversampled rate and 84 dB down from three quarters of it
+const TAPS: usize = 31;
+const CENTRE: usize = TAPS / 2;
+/// Taps 1, 3, 5... from the centre, the even ones are zero
+const COEFFICIENTS: [f32; 8] = [
+    0.313_055_9,
+    -0.091_227_83,
+    0.041_538_08,
+    -0.019_228_28,
+    0.008_020_59,
+    -0.002_734_532,
+    0.000_642_275,
+    -0.000_049_632,
+];
+/// Tube offset, the negative half saturates sooner for even harmonics
+const TUBE_BIAS: f32 = 0.3;
+/// Level the output is matched at
+const REFERENCE: f32 = 0.5;
+/// Cutoff of the high-pass taking out the offset the tube shape leaves
+const DC_CUTOFF: f32 = 10.0;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum DriveShape {
+    SoftClip,
+    HardClip,
+    /// Asymmetric saturation
+    Tube,
+    /// Folds back what goes past full scale
+    Fold,
+}
+
+impl DriveShape {
+    pub fn next(&self) -> Self {
+        use DriveShape::*;
+
+        match self {
+            SoftClip => HardClip,
+            HardClip => Tube,
+            Tube => Fold,
+            Fold => SoftClip,
+        }
+    }
+
+    fn apply(&self, x: f32) -> f32 {
+        match self {
+            DriveShape::SoftClip => tanhf(x),
+            DriveShape::HardClip => x.clamp(-1.0, 1.0),
+            DriveShape::Tube => tanhf(x + TUBE_BIAS) - tanhf(TUBE_BIAS),
+            // a triangle through the origin with a period of 4
+            DriveShape::Fold => {
+                let phase = (x + 1.0) / 4.0;
+                1.0 - (4.0 * (phase - floorf(phase)) - 2.0).abs()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Oversampling {
+    Off,
+    Double,
+    Quadruple,
+}
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum DrivePlacement {
+    Off,
+    /// In every voice, after the filter
+    Voice,
+    /// On the voices mixed together
+    Master,
+}
+
+#[derive(Clone)]
+pub struct DriveSettings {
+    pub placement: DrivePlacement,
+    pub shape: DriveShape,
+    /// `0.0..=36.0` dB
+    pub drive: f32,
+    pub oversampling: Oversampling,
+    /// Input gain for the drive, and output gain making up for it
+    gain: f32,
+    makeup: f32,
+}
+
+impl DriveSettings {
+    pub const fn init() -> Self {
+        Self {
+            placement: DrivePlacement::Off,
+            shape: DriveShape::SoftClip,
+            drive: 0.0,
+            oversampling: Oversampling::Double,
+            gain: 1.0,
+            makeup: 1.0,
+        }
+    }
+
+    /// Works out the gains, the makeup keeps a sine at `REFERENCE` as loud
+    /// as it went in, without ever boosting it
+    fn update(&mut self) {
+        self.gain = powf(10.0, self.drive / 20.0);
+
+        let steps = 64;
+        let mut energy = 0.0;
+        for step in 0..steps {
+            let sample = REFERENCE * sinf(TAU * step as f32 / steps as f32);
+            let shaped = self.shape.apply(sample * self.gain);
+            energy += shaped * shaped;
+        }
+        let rms = sqrtf(energy / steps as f32);
+        let dry = REFERENCE / core::f32::consts::SQRT_2;
+        self.makeup = if rms > 1e-3 {
+            (dry / rms).min(1.0)
+        } else {
+            1.0
+        };
+    }
+
+    pub fn adjust(&mut self, param: &DriveParams, rotation: Rotation) {
+        let up = rotation == Rotation::Right;
+
+        match param {
+            DriveParams::Placement => {
+                self.placement = match (self.placement, rotation) {
+                    (DrivePlacement::Off, Rotation::Right) => DrivePlacement::Voice,
+                    (DrivePlacement::Voice, Rotation::Right) => DrivePlacement::Master,
+                    (DrivePlacement::Master, Rotation::Left) => DrivePlacement::Voice,
+                    (DrivePlacement::Voice, Rotation::Left) => DrivePlacement::Off,
+                    (placement, _) => placement,
+                };
+                info!("Set drive placement: {}", self.placement);
+            }
+            DriveParams::Shape => {
+                self.shape = self.shape.next();
+                info!("Set drive shape: {}", self.shape);
+            }
+            DriveParams::Drive => {
+                self.drive = if up {
+                    (self.drive + 1.0).min(36.0)
+                } else {
+                    (self.drive - 1.0).max(0.0)
+                };
+                info!("Set drive: {} dB", self.drive);
+            }
+            DriveParams::Oversampling => {
+                self.oversampling = match (self.oversampling, rotation) {
+                    (Oversampling::Off, Rotation::Right) => Oversampling::Double,
+                    (Oversampling::Double, Rotation::Right) => Oversampling::Quadruple,
+                    (Oversampling::Quadruple, Rotation::Left) => Oversampling::Double,
+                    (Oversampling::Double, Rotation::Left) => Oversampling::Off,
+                    (oversampling, _) => oversampling,
+                };
+                info!("Set drive oversampling: {}", self.oversampling);
+            }
+        }
+        self.update();
+    }
+}
+
+/// FIR half-band low-pass. The history is kept twice over so the taps are
+/// always in one piece
+#[derive(Clone, Copy)]
+struct HalfBand {
+    history: [f32; 2 * TAPS],
+    write: usize,
+}
+
+impl HalfBand {
+    const fn init() -> Self {
+        Self {
+            history: [0.0; 2 * TAPS],
+            write: 0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        self.history[self.write] = x;
+        self.history[self.write + TAPS] = x;
+        self.write = (self.write + 1) % TAPS;
+
+        let taps = &self.history[self.write..self.write + TAPS];
+        let mut y = 0.5 * taps[CENTRE];
+        for (i, coefficient) in COEFFICIENTS.iter().enumerate() {
+            let distance = 2 * i + 1;
+            y += coefficient * (taps[CENTRE - distance] + taps[CENTRE + distance]);
+        }
+        y
+    }
+}
+
+/// Doubles the rate on the way in and halves it on the way out
+#[derive(Clone, Copy)]
+struct Stage {
+    up: HalfBand,
+    down: HalfBand,
+}
+
+impl Stage {
+    const fn init() -> Self {
+        Self {
+            up: HalfBand::init(),
+            down: HalfBand::init(),
+        }
+    }
+
+    /// Zero stuffed, the gain of 2 makes up for the zeros
+    fn up(&mut self, x: f32) -> [f32; 2] {
+        [self.up.process(2.0 * x), self.up.process(0.0)]
+    }
+
+    fn down(&mut self, [first, second]: [f32; 2]) -> f32 {
+        self.down.process(first);
+        self.down.process(second)
+    }
+}
+
+/// State of a drive stage, one per voice or per side of the master bus
+pub struct Drive {
+    stages: [Stage; 2],
+    /// DC blocker input and output
+    dc: (f32, f32),
+}
+
+impl Drive {
+    pub const fn init() -> Self {
+        Self {
+            stages: [Stage::init(); 2],
+            dc: (0.0, 0.0),
+        }
+    }
+
+    pub fn process(&mut self, x: f32, settings: &DriveSettings) -> f32 {
+        let shape = |x: f32| settings.shape.apply(x * settings.gain);
+        let [outer, inner] = &mut self.stages;
+
+        let shaped = match settings.oversampling {
+            Oversampling::Off => shape(x),
+            Oversampling::Double => {
+                let doubled = outer.up(x).map(shape);
+                outer.down(doubled)
+            }
+            Oversampling::Quadruple => {
+                let halves = outer.up(x).map(|x| {
+                    let quarters = inner.up(x).map(shape);
+                    inner.down(quarters)
+                });
+                outer.down(halves)
+            }
+        };
+
+        let (last_in, last_out) = self.dc;
+        let out = shaped - last_in + (1.0 - TAU * DC_CUTOFF / SAMPLE_RATE) * last_out;
+        self.dc = (shaped, out);
+
+        out * settings.makeup
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum DriveParams {
+    Placement,
+    Shape,
+    Drive,
+    Oversampling,
+}
+
+impl DriveParams {
+    pub const fn init_param() -> Self {
+        Self::Placement
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use DriveParams::*;
+
+        match param {
+            Placement => Some(Shape),
+            Shape => Some(Drive),
+            Drive => Some(Oversampling),
+            Oversampling => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(shape: DriveShape, drive: f32, oversampling: Oversampling) -> DriveSettings {
+        let mut settings = DriveSettings::init();
+        settings.shape = shape;
+        settings.drive = drive;
+        settings.oversampling = oversampling;
+        settings.update();
+        settings
+    }
+
+    /// Output for a sine at `hz`, after the filters have settled
+    fn sine(settings: &DriveSettings, hz: f32, level: f32) -> std::vec::Vec<f32> {
+        let mut drive = Drive::init();
+        (0..SAMPLE_RATE as usize / 5)
+            .map(|i| drive.process(level * sinf(TAU * hz * i as f32 / SAMPLE_RATE), settings))
+            .skip(SAMPLE_RATE as usize / 10)
+            .collect()
+    }
+
+    /// Level of `hz` in `samples`
+    fn goertzel(samples: &[f32], hz: f32) -> f32 {
+        let coefficient = 2.0 * libm::cosf(TAU * hz / SAMPLE_RATE);
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for sample in samples {
+            (s1, s2) = (sample + coefficient * s1 - s2, s1);
+        }
+        sqrtf(s1 * s1 + s2 * s2 - coefficient * s1 * s2) * 2.0 / samples.len() as f32
+    }
+
+    #[test]
+    fn oversampling_keeps_the_aliases_down() {
+        // the 5th harmonic of 15 kHz, 75 kHz, folds back to 21 kHz
+        let alias = |oversampling| {
+            let out = sine(
+                &settings(DriveShape::HardClip, 24.0, oversampling),
+                15_000.0,
+                0.5,
+            );
+            goertzel(&out, 21_000.0) / goertzel(&out, 15_000.0)
+        };
+
+        let (plain, double, quadruple) = (
+            alias(Oversampling::Off),
+            alias(Oversampling::Double),
+            alias(Oversampling::Quadruple),
+        );
+        assert!(plain > 0.05, "{plain}");
+        assert!(double < plain / 10.0, "{double}");
+        assert!(quadruple < plain / 10.0, "{quadruple}");
+    }
+
+    #[test]
+    fn makeup_keeps_the_level() {
+        let rms = |samples: &[f32]| {
+            sqrtf(samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32)
+        };
+        let dry = REFERENCE / core::f32::consts::SQRT_2;
+
+        for shape in [DriveShape::SoftClip, DriveShape::HardClip, DriveShape::Tube] {
+            let out = sine(
+                &settings(shape, 24.0, Oversampling::Double),
+                100.0,
+                REFERENCE,
+            );
+            assert!((rms(&out) / dry - 1.0).abs() < 0.05, "{}", rms(&out));
+        }
+    }
+
+    #[test]
+    fn fold_stays_in_range() {
+        for x in [-7.3, -2.0, -0.5, 0.0, 0.5, 1.0, 1.5, 3.0, 9.9] {
+            let y = DriveShape::Fold.apply(x);
+            assert!((-1.0..=1.0).contains(&y), "{x} {y}");
+        }
+        assert_eq!(DriveShape::Fold.apply(0.5), 0.5);
+        assert_eq!(DriveShape::Fold.apply(1.5), 0.5);
+    }
+}
@@ -1,35 +1,148 @@
-use core::f32::consts::TAU;
+use core::f32::consts::PI;
 
 use defmt::{Format, info};
+use libm::{exp2f, log2f, tanf};
 
-use crate::{consts::SAMPLE_RATE, encoder::Rotation};
+use crate::{
+    adsr::{Adsr, Curve, TimeMs},
+    consts::SAMPLE_RATE,
+    encoder::Rotation,
+    ladder::Ladder,
+};
 
+/// Key tracking leaves the cutoff alone at middle C
+const KEY_TRACKING_CENTER: f32 = 261.63;
+
+/// Damping at full resonance. Slightly negative, so the filter rings up on
+/// its own and self-oscillates
+const MIN_DAMPING: f32 = -0.1;
+/// Damping added with the square of the band-pass output. It balances
+/// `MIN_DAMPING` at a self-oscillation level of about 0.5 and keeps loud
+/// input at high resonance from running away
+const SATURATION: f32 = 0.4;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum FilterModel {
+    /// 12 dB/oct state variable filter, with a choice of [`FilterMode`]
+    Svf,
+    /// 6 to 24 dB/oct lowpass ladder with drive
+    Ladder,
+}
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+impl FilterMode {
+    pub fn next(&self) -> Self {
+        match self {
+            FilterMode::LowPass => FilterMode::HighPass,
+            FilterMode::HighPass => FilterMode::BandPass,
+            FilterMode::BandPass => FilterMode::Notch,
+            FilterMode::Notch => FilterMode::LowPass,
+        }
+    }
+}
+
+/// Zero-delay-feedback state variable filter, 12 dB/oct (after Andrew
+/// Simper's trapezoidal SVF), or a [`Ladder`]
 pub struct Filter {
+    pub model: FilterModel,
     pub cutoff: f32,
+    /// `0.0..=1.0`, self-oscillates at the top
     pub resonance: f32,
     pub gain: f32,
-    pub z1: f32,
+    pub mode: FilterMode,
+    /// Drive and slope of the ladder, cutoff and resonance come from above
+    pub ladder: Ladder,
     pub sample_rate: f32,
+    /// Integrator states
+    ic1eq: f32,
+    ic2eq: f32,
+    /// Band-pass output of the last sample, for the nonlinear damping
+    band: f32,
+    /// Prewarped cutoff and the cutoff it was computed for
+    g: f32,
+    g_cutoff: f32,
 }
 
 impl Filter {
     pub const fn new() -> Self {
         Self {
+            model: FilterModel::Svf,
             cutoff: 10_000.0,
             gain: 1.0,
-            resonance: 0.71,
+            resonance: 0.3,
+            mode: FilterMode::LowPass,
+            ladder: Ladder::init(),
             sample_rate: SAMPLE_RATE,
-            z1: 0.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+            band: 0.0,
+            g: 0.0,
+            g_cutoff: 0.0,
         }
     }
 
     pub fn process(&mut self, input: f32) -> f32 {
-        let dt = 1.0 / self.sample_rate;
-        let rc = 1.0 / (TAU * self.cutoff);
-        let alpha = dt / (rc + dt);
+        self.process_at(input, self.cutoff)
+    }
 
-        self.z1 += alpha * (input - self.z1);
-        self.gain * self.z1
+    /// Filters `input` at `cutoff` instead of `self.cutoff`, for cutoff
+    /// modulated every sample
+    pub fn process_at(&mut self, input: f32, cutoff: f32) -> f32 {
+        if self.model == FilterModel::Ladder {
+            self.ladder.resonance = self.resonance;
+            return self.gain * self.ladder.process_at(input, cutoff);
+        }
+
+        // tan() only when the cutoff moves
+        if cutoff != self.g_cutoff {
+            let clamped = cutoff.clamp(10.0, 0.45 * self.sample_rate);
+            self.g = tanf(PI * clamped / self.sample_rate);
+            self.g_cutoff = cutoff;
+        }
+
+        let g = self.g;
+        let k = 2.0 * (1.0 - self.resonance)
+            + MIN_DAMPING * self.resonance
+            + SATURATION * self.band * self.band;
+
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let band = a1 * self.ic1eq + a2 * v3;
+        let low = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+
+        self.ic1eq = 2.0 * band - self.ic1eq;
+        self.ic2eq = 2.0 * low - self.ic2eq;
+        self.band = band;
+
+        let output = match self.mode {
+            FilterMode::LowPass => low,
+            FilterMode::HighPass => input - k * band - low,
+            FilterMode::BandPass => band,
+            FilterMode::Notch => input - k * band,
+        };
+
+        self.gain * output
+    }
+
+    /// Takes the settings of `other`, keeping its own state
+    pub fn follow(&mut self, other: &Filter) {
+        self.model = other.model;
+        self.ladder.drive = other.ladder.drive;
+        self.ladder.slope = other.ladder.slope;
+        self.cutoff = other.cutoff;
+        self.resonance = other.resonance;
+        self.gain = other.gain;
+        self.mode = other.mode;
     }
 
     pub fn adjust(&mut self, param: &FilterParam, rotation: Rotation) {
@@ -40,15 +153,17 @@ impl Filter {
                 } else {
                     1.0 / 1.01
                 };
-                self.cutoff = delta * self.cutoff;
+                self.cutoff = (delta * self.cutoff).clamp(20.0, 20_000.0);
                 info!("Set filter cutoff: {}", self.cutoff);
             }
             FilterParam::Resonance => {
-                if rotation == Rotation::Right {
-                    self.resonance += 0.05;
+                let new = if rotation == Rotation::Right {
+                    self.resonance + 0.05
                 } else {
-                    self.resonance -= 0.05;
+                    self.resonance - 0.05
                 };
+
+                self.resonance = new.clamp(0.0, 1.0);
                 info!("Set filter resonance: {}", self.resonance);
             }
             FilterParam::Gain => {
@@ -59,6 +174,130 @@ impl Filter {
                 };
                 info!("Set filter gain: {}", self.gain);
             }
+            FilterParam::Mode => {
+                self.mode = self.mode.next();
+                info!("Set filter mode: {}", self.mode);
+            }
+            FilterParam::Model => {
+                self.model = match self.model {
+                    FilterModel::Svf => FilterModel::Ladder,
+                    FilterModel::Ladder => FilterModel::Svf,
+                };
+                info!("Set filter model: {}", self.model);
+            }
+            FilterParam::Drive => {
+                let new = if rotation == Rotation::Right {
+                    self.ladder.drive + 0.1
+                } else {
+                    self.ladder.drive - 0.1
+                };
+
+                self.ladder.drive = new.clamp(0.1, 10.0);
+                info!("Set filter drive: {}", self.ladder.drive);
+            }
+            FilterParam::Slope => {
+                self.ladder.slope = self.ladder.slope.next();
+                info!("Set filter slope: {}", self.ladder.slope);
+            }
+        }
+    }
+}
+
+/// Per-voice cutoff modulation of a patch: the filter envelope, the key and
+/// the velocity, all in octaves
+pub struct FilterModulation {
+    pub envelope: Adsr,
+    /// Octaves at the envelope's peak, `-6.0..=6.0`
+    pub env_amount: f32,
+    /// `0.0..=1.0`, at 1 the cutoff moves with the note frequency
+    pub key_tracking: f32,
+    /// Octaves at full velocity, `0.0..=4.0`
+    pub velocity: f32,
+}
+
+impl FilterModulation {
+    pub const fn init() -> Self {
+        Self {
+            envelope: Adsr {
+                attack: TimeMs(5),
+                decay: TimeMs(300),
+                release: TimeMs(200),
+                sustain_level: 0.3,
+                attack_curve: Curve::Linear,
+                decay_curve: Curve::Linear,
+                release_curve: Curve::Linear,
+            },
+            env_amount: 0.0,
+            key_tracking: 0.0,
+            velocity: 0.0,
+        }
+    }
+
+    /// `cutoff` moved by the envelope level `env`, the note frequency and
+    /// `velocity` in `0.0..=1.0`
+    pub fn cutoff(&self, cutoff: f32, env: f32, note_freq: f32, velocity: f32) -> f32 {
+        let octaves = self.env_amount * env
+            + self.key_tracking * log2f(note_freq / KEY_TRACKING_CENTER)
+            + self.velocity * velocity;
+
+        cutoff * exp2f(octaves)
+    }
+
+    pub fn adjust(&mut self, param: &FilterModParams, rotation: Rotation) {
+        match param {
+            FilterModParams::EnvAmount => {
+                let new = if rotation == Rotation::Right {
+                    self.env_amount + 0.1
+                } else {
+                    self.env_amount - 0.1
+                };
+
+                self.env_amount = new.clamp(-6.0, 6.0);
+                info!("Set filter env amount: {}", self.env_amount);
+            }
+            FilterModParams::KeyTracking => {
+                let new = if rotation == Rotation::Right {
+                    self.key_tracking + 0.05
+                } else {
+                    self.key_tracking - 0.05
+                };
+
+                self.key_tracking = new.clamp(0.0, 1.0);
+                info!("Set filter key tracking: {}", self.key_tracking);
+            }
+            FilterModParams::Velocity => {
+                let new = if rotation == Rotation::Right {
+                    self.velocity + 0.1
+                } else {
+                    self.velocity - 0.1
+                };
+
+                self.velocity = new.clamp(0.0, 4.0);
+                info!("Set filter velocity: {}", self.velocity);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum FilterModParams {
+    EnvAmount,
+    KeyTracking,
+    Velocity,
+}
+
+impl FilterModParams {
+    pub const fn init_param() -> Self {
+        Self::EnvAmount
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use FilterModParams::*;
+
+        match param {
+            EnvAmount => Some(KeyTracking),
+            KeyTracking => Some(Velocity),
+            Velocity => None,
         }
     }
 }
@@ -68,6 +307,10 @@ pub enum FilterParam {
     Cutoff,
     Resonance,
     Gain,
+    Mode,
+    Model,
+    Drive,
+    Slope,
 }
 
 impl FilterParam {
@@ -81,7 +324,130 @@ impl FilterParam {
         match param {
             Cutoff => Some(Resonance),
             Resonance => Some(Gain),
-            Gain => None,
+            Gain => Some(Mode),
+            Mode => Some(Model),
+            Model => Some(Drive),
+            Drive => Some(Slope),
+            Slope => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(mode: FilterMode, cutoff: f32, resonance: f32) -> Filter {
+        let mut filter = Filter::new();
+        filter.mode = mode;
+        filter.cutoff = cutoff;
+        filter.resonance = resonance;
+        filter
+    }
+
+    /// Steady state peak of a unit sine at `freq` through the filter
+    fn gain_at(filter: &mut Filter, freq: f32) -> f32 {
+        let sine = |i: usize| (2.0 * PI * freq * i as f32 / SAMPLE_RATE).sin();
+
+        for i in 0..SAMPLE_RATE as usize / 4 {
+            filter.process(sine(i));
+        }
+
+        (0..SAMPLE_RATE as usize / 10).fold(0.0, |peak: f32, i| {
+            peak.max(filter.process(sine(i + SAMPLE_RATE as usize / 4)).abs())
+        })
+    }
+
+    #[test]
+    fn modes_pass_their_bands() {
+        let cutoff = 1_000.0;
+        let gains = |mode| {
+            [100.0, cutoff, 10_000.0].map(|freq| gain_at(&mut filter(mode, cutoff, 0.0), freq))
+        };
+
+        let [low, at, high] = gains(FilterMode::LowPass);
+        assert!(low > 0.95 && high < 0.02, "low pass: {low} {high}");
+        // resonance 0 is critically damped, -6 dB at the cutoff
+        assert!((at - 0.5).abs() < 0.02, "low pass at cutoff: {at}");
+
+        let [low, _, high] = gains(FilterMode::HighPass);
+        assert!(low < 0.02 && high > 0.95, "high pass: {low} {high}");
+
+        let [low, at, high] = gains(FilterMode::BandPass);
+        assert!(
+            at > 4.0 * low && at > 4.0 * high,
+            "band pass: {low} {at} {high}"
+        );
+
+        let [low, at, high] = gains(FilterMode::Notch);
+        assert!(
+            low > 0.9 && high > 0.9 && at < 0.05,
+            "notch: {low} {at} {high}"
+        );
+    }
+
+    #[test]
+    fn resonance_peaks_at_the_cutoff() {
+        let flat = gain_at(&mut filter(FilterMode::LowPass, 2_000.0, 0.0), 2_000.0);
+        let resonant = gain_at(&mut filter(FilterMode::LowPass, 2_000.0, 0.9), 2_000.0);
+
+        assert!(resonant > 2.0 * flat, "{flat} {resonant}");
+    }
+
+    #[test]
+    fn self_oscillation_is_stable() {
+        for cutoff in [100.0, 1_000.0, 10_000.0, 20_000.0] {
+            let mut filter = filter(FilterMode::LowPass, cutoff, 1.0);
+            filter.process(1.0);
+
+            let samples: Vec<f32> = (0..SAMPLE_RATE as usize)
+                .map(|_| filter.process(0.0))
+                .collect();
+            let tail = crate::golden::peak(&samples[samples.len() / 2..]);
+
+            assert!((0.3..1.0).contains(&tail), "{cutoff} Hz: {tail}");
+        }
+    }
+
+    #[test]
+    fn key_tracking_follows_the_note() {
+        let mut modulation = FilterModulation::init();
+        let c4 = KEY_TRACKING_CENTER;
+
+        assert_eq!(modulation.cutoff(1_000.0, 0.0, 4.0 * c4, 1.0), 1_000.0);
+
+        modulation.key_tracking = 1.0;
+        let tracked = modulation.cutoff(1_000.0, 0.0, 4.0 * c4, 0.0);
+        assert!((tracked - 4_000.0).abs() < 0.1, "{tracked}");
+
+        modulation.key_tracking = 0.5;
+        let half = modulation.cutoff(1_000.0, 0.0, 4.0 * c4, 0.0);
+        assert!((half - 2_000.0).abs() < 0.1, "{half}");
+    }
+
+    #[test]
+    fn env_amount_is_bipolar() {
+        let mut modulation = FilterModulation::init();
+        modulation.env_amount = 2.0;
+        let up = modulation.cutoff(1_000.0, 1.0, KEY_TRACKING_CENTER, 0.0);
+
+        modulation.env_amount = -2.0;
+        let down = modulation.cutoff(1_000.0, 1.0, KEY_TRACKING_CENTER, 0.0);
+
+        assert!((up - 4_000.0).abs() < 0.1 && (down - 250.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn modulated_cutoff_stays_bounded() {
+        let mut filter = filter(FilterMode::LowPass, 1_000.0, 0.95);
+
+        for i in 0..SAMPLE_RATE as usize {
+            let sweep = 20.0 * 1_000f32.powf((i as f32 / 500.0).sin() * 0.5 + 0.5);
+            let input = if (i / 100) % 2 == 0 { 1.0 } else { -1.0 };
+
+            let output = filter.process_at(input, sweep);
+            assert!(output.is_finite() && output.abs() < 4.0, "{i}: {output}");
         }
     }
 }
@@ -0,0 +1,521 @@
+use defmt::{Format, info};
+use midi_parser::parser::Note;
+
+use crate::{
+    adsr::{Adsr, AdsrParams, Curve, Envelope, Generator, TimeMs},
+    consts::{FM_OPERATORS, SAMPLE_RATE},
+    encoder::Rotation,
+    wavetable::{self, Interpolation},
+};
+
+/// Phase deviation in cycles of a modulator at full output level
+const MOD_DEPTH: f32 = 2.0;
+/// Where a fixed frequency starts, and how far it goes
+const FIXED_HZ: f32 = 440.0;
+const MIN_FIXED_HZ: f32 = 1.0;
+const MAX_FIXED_HZ: f32 = 8_000.0;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Frequency {
+    /// Multiple of the note frequency
+    Ratio(f32),
+    /// Hz, regardless of the note
+    Fixed(f32),
+}
+
+#[derive(Clone)]
+pub struct OperatorSettings {
+    pub frequency: Frequency,
+    pub level: f32,
+    pub envelope: Adsr,
+}
+
+impl OperatorSettings {
+    pub const fn init() -> Self {
+        Self {
+            frequency: Frequency::Ratio(1.0),
+            level: 0.0,
+            envelope: Adsr {
+                attack: TimeMs(5),
+                decay: TimeMs(300),
+                release: TimeMs(200),
+                sustain_level: 0.6,
+                attack_curve: Curve::Linear,
+                decay_curve: Curve::Linear,
+                release_curve: Curve::Linear,
+            },
+        }
+    }
+
+    /// The envelope applies from the next note on
+    pub fn adjust(&mut self, param: &OperatorParams, rotation: Rotation) {
+        match param {
+            OperatorParams::Mode => {
+                self.frequency = match self.frequency {
+                    Frequency::Ratio(_) => Frequency::Fixed(FIXED_HZ),
+                    Frequency::Fixed(_) => Frequency::Ratio(1.0),
+                };
+                info!("Set operator frequency: {}", self.frequency);
+            }
+            OperatorParams::Ratio => {
+                let Frequency::Ratio(ratio) = self.frequency else {
+                    return;
+                };
+
+                // coarse DX-style steps: 0.5, 1, 2, 3, ...
+                let new = match rotation {
+                    Rotation::Right if ratio < 1.0 => 1.0,
+                    Rotation::Right => ratio + 1.0,
+                    Rotation::Left if ratio <= 1.0 => 0.5,
+                    Rotation::Left => ratio - 1.0,
+                };
+
+                self.frequency = Frequency::Ratio(new.min(16.0));
+                info!("Set operator frequency: {}", self.frequency);
+            }
+            OperatorParams::FixedHz => {
+                let Frequency::Fixed(hz) = self.frequency else {
+                    return;
+                };
+
+                // a semitone a step
+                let step = if rotation == Rotation::Right {
+                    1.0 / 12.0
+                } else {
+                    -1.0 / 12.0
+                };
+
+                let new = hz * libm::exp2f(step);
+                self.frequency = Frequency::Fixed(new.clamp(MIN_FIXED_HZ, MAX_FIXED_HZ));
+                info!("Set operator frequency: {}", self.frequency);
+            }
+            OperatorParams::Level => {
+                let new = if rotation == Rotation::Right {
+                    self.level + 0.02
+                } else {
+                    self.level - 0.02
+                };
+
+                self.level = new.clamp(0.0, 1.0);
+                info!("Set operator level: {}", self.level);
+            }
+            OperatorParams::Envelope(param) => self.envelope.adjust(param, rotation),
+        }
+    }
+}
+
+/// Operator routings of the classic 4-operator FM synths. Operators are
+/// numbered from 1, op 4 is the one with feedback
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Algorithm {
+    /// 4 → 3 → 2 → 1
+    Stack,
+    /// (3 + 4) → 2 → 1
+    TwoToStack,
+    /// (2 + (4 → 3)) → 1
+    BranchToOne,
+    /// ((4 → 2) + 3) → 1
+    BranchToOneAlt,
+    /// (2 → 1) + (4 → 3)
+    TwoStacks,
+    /// 4 → (1, 2, 3)
+    OneToThree,
+    /// (4 → 3) + 2 + 1
+    StackAndTwo,
+    /// 1 + 2 + 3 + 4
+    Additive,
+}
+
+impl Algorithm {
+    /// Bitmasks of the operators modulating each operator. Modulators always
+    /// have a higher index than their carriers
+    const fn modulators(&self) -> [u8; FM_OPERATORS] {
+        use Algorithm::*;
+
+        match self {
+            Stack => [0b0010, 0b0100, 0b1000, 0],
+            TwoToStack => [0b0010, 0b1100, 0, 0],
+            BranchToOne => [0b0110, 0, 0b1000, 0],
+            BranchToOneAlt => [0b0110, 0b1000, 0, 0],
+            TwoStacks => [0b0010, 0, 0b1000, 0],
+            OneToThree => [0b1000, 0b1000, 0b1000, 0],
+            StackAndTwo => [0, 0, 0b1000, 0],
+            Additive => [0, 0, 0, 0],
+        }
+    }
+
+    /// Bitmask of the operators heard at the output
+    const fn carriers(&self) -> u8 {
+        use Algorithm::*;
+
+        match self {
+            Stack | TwoToStack | BranchToOne | BranchToOneAlt => 0b0001,
+            TwoStacks => 0b0101,
+            OneToThree | StackAndTwo => 0b0111,
+            Additive => 0b1111,
+        }
+    }
+
+    fn next(&self) -> Self {
+        use Algorithm::*;
+
+        match self {
+            Stack => TwoToStack,
+            TwoToStack => BranchToOne,
+            BranchToOne => BranchToOneAlt,
+            BranchToOneAlt => TwoStacks,
+            TwoStacks => OneToThree,
+            OneToThree => StackAndTwo,
+            StackAndTwo => Additive,
+            Additive => Stack,
+        }
+    }
+}
+
+pub struct FmSettings {
+    pub operators: [OperatorSettings; FM_OPERATORS],
+    pub algorithm: Algorithm,
+    /// Self-modulation of op 4, `0.0..=1.0`
+    pub feedback: f32,
+}
+
+impl FmSettings {
+    pub const fn init() -> Self {
+        let mut operators = [const { OperatorSettings::init() }; FM_OPERATORS];
+        operators[0].level = 1.0;
+        operators[1].level = 0.3;
+
+        Self {
+            operators,
+            algorithm: Algorithm::Stack,
+            feedback: 0.0,
+        }
+    }
+
+    pub fn adjust_operator(&mut self, op: usize, param: &OperatorParams, rotation: Rotation) {
+        self.operators[op].adjust(param, rotation);
+    }
+
+    pub fn adjust(&mut self, param: &FmParams, rotation: Rotation) {
+        match param {
+            FmParams::Algorithm => {
+                self.algorithm = self.algorithm.next();
+                info!("Set FM algorithm: {}", self.algorithm);
+            }
+            FmParams::Feedback => {
+                let new = if rotation == Rotation::Right {
+                    self.feedback + 0.05
+                } else {
+                    self.feedback - 0.05
+                };
+
+                self.feedback = new.clamp(0.0, 1.0);
+                info!("Set FM feedback: {}", self.feedback);
+            }
+        }
+    }
+}
+
+/// Operator phases and envelopes of a voice. The voice's amplitude envelope
+/// still gates it, so the operator envelopes only shape the timbre
+pub struct FmVoice {
+    freq: f32,
+    /// Pitch modulation, as a frequency ratio
+    pitch: f32,
+    phases: [f32; FM_OPERATORS],
+    envelopes: [Envelope; FM_OPERATORS],
+    /// The last two outputs of op 4, averaged to tame the feedback
+    feedback: [f32; 2],
+}
+
+impl FmVoice {
+    pub fn new(note: &Note, settings: &FmSettings) -> Self {
+        Self {
+            freq: note.freq,
+            pitch: 1.0,
+            phases: [0.0; FM_OPERATORS],
+            envelopes: core::array::from_fn(|i| {
+                Envelope::new(settings.operators[i].envelope.clone(), SAMPLE_RATE)
+            }),
+            feedback: [0.0; 2],
+        }
+    }
+
+    /// Offsets the note frequency by `ratio`, for unison stacks. Operators
+    /// at a fixed frequency stay put
+    pub fn detune(&mut self, ratio: f32) {
+        self.freq *= ratio;
+    }
+
+    /// Changes the pitch without restarting, `detune` being the unison ratio
+    pub fn set_note(&mut self, note: &Note, detune: f32) {
+        self.freq = note.freq * detune;
+    }
+
+    pub fn set_pitch_mod(&mut self, ratio: f32) {
+        self.pitch = ratio;
+    }
+
+    pub fn note_on(&mut self) {
+        self.phases = [0.0; FM_OPERATORS];
+        self.feedback = [0.0; 2];
+        self.retrigger();
+    }
+
+    /// Restarts the operator envelopes from where they are, leaving the
+    /// phases running
+    pub fn retrigger(&mut self) {
+        for env in self.envelopes.iter_mut() {
+            env.note_on();
+        }
+    }
+
+    pub fn note_off(&mut self) {
+        for env in self.envelopes.iter_mut() {
+            env.note_off();
+        }
+    }
+
+    pub fn next_sample(&mut self, settings: &FmSettings) -> f32 {
+        let modulators = settings.algorithm.modulators();
+        let carriers = settings.algorithm.carriers();
+
+        let mut outputs = [0.0; FM_OPERATORS];
+        let mut sample = 0.0;
+
+        // modulators have higher indices, so they're ready before their carriers
+        for op in (0..FM_OPERATORS).rev() {
+            let mut modulation = 0.0;
+            for (m, output) in outputs.iter().enumerate() {
+                if modulators[op] & (1 << m) != 0 {
+                    modulation += output;
+                }
+            }
+
+            if op == FM_OPERATORS - 1 {
+                modulation += settings.feedback * (self.feedback[0] + self.feedback[1]) * 0.5;
+            }
+
+            let phase = self.phases[op] + modulation * MOD_DEPTH;
+            let phase = phase - libm::floorf(phase);
+            let operator = &settings.operators[op];
+
+            outputs[op] = wavetable::lookup(&wavetable::SINE, phase, Interpolation::Linear)
+                * operator.level
+                * self.envelopes[op].next();
+
+            if carriers & (1 << op) != 0 {
+                sample += outputs[op];
+            }
+
+            let freq = match operator.frequency {
+                Frequency::Ratio(ratio) => self.freq * self.pitch * ratio,
+                Frequency::Fixed(freq) => freq,
+            };
+
+            self.phases[op] += freq / SAMPLE_RATE;
+            if self.phases[op] >= 1.0 {
+                self.phases[op] -= 1.0;
+            }
+        }
+
+        self.feedback = [self.feedback[1], outputs[FM_OPERATORS - 1]];
+
+        sample
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum FmParams {
+    Algorithm,
+    Feedback,
+}
+
+impl FmParams {
+    pub const fn init_param() -> Self {
+        Self::Algorithm
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use FmParams::*;
+
+        match param {
+            Algorithm => Some(Feedback),
+            Feedback => None,
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum OperatorParams {
+    /// Switches between a ratio to the note and a fixed frequency
+    Mode,
+    Ratio,
+    FixedHz,
+    Level,
+    Envelope(AdsrParams),
+}
+
+impl OperatorParams {
+    pub const fn init_param() -> Self {
+        Self::Mode
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use OperatorParams::*;
+
+        match param {
+            Mode => Some(Ratio),
+            Ratio => Some(FixedHz),
+            FixedHz => Some(Level),
+            Level => Some(Envelope(AdsrParams::init_param())),
+            Envelope(param) => AdsrParams::next_param(param).map(Envelope),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(algorithm: Algorithm, levels: [f32; FM_OPERATORS]) -> FmSettings {
+        let mut settings = FmSettings::init();
+        settings.algorithm = algorithm;
+
+        for (op, level) in settings.operators.iter_mut().zip(levels) {
+            op.level = level;
+            op.envelope = Adsr {
+                attack: TimeMs(1),
+                decay: TimeMs(1),
+                release: TimeMs(1),
+                sustain_level: 1.0,
+                attack_curve: Curve::Linear,
+                decay_curve: Curve::Linear,
+                release_curve: Curve::Linear,
+            };
+        }
+
+        settings
+    }
+
+    fn render(settings: &FmSettings, note: &Note, len: usize) -> Vec<f32> {
+        let mut voice = FmVoice::new(note, settings);
+        voice.note_on();
+
+        (0..len).map(|_| voice.next_sample(settings)).collect()
+    }
+
+    #[test]
+    fn unmodulated_carrier_is_a_sine() {
+        let settings = settings(Algorithm::Stack, [1.0, 0.0, 0.0, 0.0]);
+        let note = Note::new(69);
+        let samples = render(&settings, &note, 2_000);
+
+        // past the 1 ms attack and decay
+        for (i, s) in samples.iter().enumerate().skip(200) {
+            let expected = (core::f32::consts::TAU * note.freq * i as f32 / SAMPLE_RATE).sin();
+            assert!((s - expected).abs() < 1e-3, "{i}: {s} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn algorithms_sum_their_carriers() {
+        let carriers = [
+            (Algorithm::Stack, 1),
+            (Algorithm::TwoToStack, 1),
+            (Algorithm::BranchToOne, 1),
+            (Algorithm::BranchToOneAlt, 1),
+            (Algorithm::TwoStacks, 2),
+            (Algorithm::OneToThree, 3),
+            (Algorithm::StackAndTwo, 3),
+            (Algorithm::Additive, 4),
+        ];
+
+        for (algorithm, count) in carriers {
+            // only the carriers sound, all at the same ratio, so their sines
+            // add up in phase
+            let mut levels = [0.0; FM_OPERATORS];
+            for (op, level) in levels.iter_mut().enumerate() {
+                if algorithm.carriers() & (1 << op) != 0 {
+                    *level = 1.0;
+                }
+            }
+
+            let settings = settings(algorithm, levels);
+            let peak = crate::golden::peak(&render(&settings, &Note::new(60), 4_000));
+
+            assert!((peak - count as f32).abs() < 0.01, "{algorithm:?}: {peak}");
+        }
+    }
+
+    #[test]
+    fn modulation_adds_harmonics() {
+        let note = Note::new(57);
+        let plain = render(
+            &settings(Algorithm::Stack, [1.0, 0.0, 0.0, 0.0]),
+            &note,
+            16_384,
+        );
+        let modulated = render(
+            &settings(Algorithm::Stack, [1.0, 0.5, 0.0, 0.0]),
+            &note,
+            16_384,
+        );
+
+        let centroid = crate::golden::spectral_centroid;
+        assert!(centroid(&modulated) > centroid(&plain) * 1.5);
+    }
+
+    #[test]
+    fn fixed_frequency_ignores_the_note() {
+        let mut settings = settings(Algorithm::Stack, [1.0, 0.0, 0.0, 0.0]);
+        settings.operators[0].frequency = Frequency::Fixed(440.0);
+
+        assert_eq!(
+            render(&settings, &Note::new(40), 1_000),
+            render(&settings, &Note::new(90), 1_000)
+        );
+    }
+
+    #[test]
+    fn operator_params_reach_fixed_frequency_and_envelope() {
+        let mut op = OperatorSettings::init();
+
+        op.adjust(&OperatorParams::Mode, Rotation::Right);
+        assert!(op.frequency == Frequency::Fixed(440.0));
+        // the ratio stays out of a fixed frequency
+        op.adjust(&OperatorParams::Ratio, Rotation::Right);
+        op.adjust(&OperatorParams::FixedHz, Rotation::Left);
+        let Frequency::Fixed(hz) = op.frequency else {
+            panic!("not fixed");
+        };
+        assert!((hz - 415.3).abs() < 0.1, "{hz}");
+
+        let attack = op.envelope.attack.0;
+        op.adjust(
+            &OperatorParams::Envelope(AdsrParams::Attack),
+            Rotation::Right,
+        );
+        assert_eq!(op.envelope.attack.0, attack + 5);
+
+        let mut params = 1;
+        let mut param = OperatorParams::init_param();
+        while let Some(next) = OperatorParams::next_param(&param) {
+            param = next;
+            params += 1;
+        }
+        // through every envelope param
+        assert_eq!(params, 4 + 7);
+    }
+
+    #[test]
+    fn full_feedback_stays_bounded() {
+        let mut settings = settings(Algorithm::Stack, [1.0, 1.0, 1.0, 1.0]);
+        settings.feedback = 1.0;
+
+        let samples = render(&settings, &Note::new(36), 96_000);
+
+        assert!(samples.iter().all(|s| s.is_finite() && s.abs() <= 1.0));
+    }
+}
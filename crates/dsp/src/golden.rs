@@ -0,0 +1,320 @@
+//! Golden-audio regression tests.
+//!
+//! Scripted note sequences are rendered on the host through [`State`] (and so
+//! through `VoicePool`, `Envelope` and `Oscillator`) and compared against the
+//! reference renders stored in `golden/` as 32-bit float mono WAV files.
+//!
+//! Renders are compared by RMS error, peak level and spectral centroid rather
+//! than bit for bit, so harmless floating point noise doesn't break the suite.
+//! After an intentional change of the sound, re-record the references with
+//! `cargo bless` and listen to the diff before committing it.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use midi_parser::parser::{MidiMessage, Note, Velocity};
+
+use crate::{consts::SAMPLE_RATE, filter::Filter, state::State};
+
+struct Tolerance {
+    rms_error: f32,
+    peak: f32,
+    /// Relative to the reference centroid
+    centroid: f32,
+}
+
+const TOLERANCE: Tolerance = Tolerance {
+    rms_error: 1e-3,
+    peak: 1e-3,
+    centroid: 0.01,
+};
+
+const FFT_SIZE: usize = 4096;
+
+type Script = [(u32, MidiMessage)];
+
+fn on(num: u8) -> MidiMessage {
+    MidiMessage::NoteOn(Note::new(num), Velocity(100))
+}
+
+fn off(num: u8) -> MidiMessage {
+    MidiMessage::NoteOff(Note::new(num), Velocity(0))
+}
+
+fn ms_to_samples(ms: u32) -> usize {
+    (ms as f32 * SAMPLE_RATE / 1000.0) as usize
+}
+
+/// Renders `length_ms` of audio, sending every message of the script at its
+/// time (in ms) right before the sample it lands on
+pub fn render(script: &Script, length_ms: u32) -> Vec<f32> {
+    let mut state = State::new();
+    let mut events = script.iter().peekable();
+
+    (0..ms_to_samples(length_ms))
+        .map(|i| {
+            while let Some((_, msg)) = events.next_if(|(at, _)| ms_to_samples(*at) <= i) {
+                state.process_midi_msg(msg);
+            }
+
+            state.next_sample()
+        })
+        .collect()
+}
+
+fn check(name: &str, samples: &[f32]) {
+    let path = reference_path(name);
+
+    if cfg!(feature = "bless") {
+        write_wav(&path, samples);
+        println!("blessed {}", path.display());
+        return;
+    }
+
+    let Some(reference) = read_wav(&path) else {
+        panic!("no reference render for `{name}`, run `cargo bless` to record it");
+    };
+
+    assert_eq!(
+        samples.len(),
+        reference.len(),
+        "`{name}`: render length differs from the reference"
+    );
+
+    let rms_error = rms_error(samples, &reference);
+    let peak = (peak(samples) - peak(&reference)).abs();
+    let reference_centroid = spectral_centroid(&reference);
+    let centroid = (spectral_centroid(samples) - reference_centroid).abs() / reference_centroid;
+
+    assert!(
+        rms_error <= TOLERANCE.rms_error
+            && peak <= TOLERANCE.peak
+            && centroid <= TOLERANCE.centroid,
+        "`{name}` differs from the reference: rms error {rms_error}, peak diff {peak}, \
+         centroid diff {:.2}%",
+        centroid * 100.0,
+    );
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("golden")
+        .join(format!("{name}.wav"))
+}
+
+pub fn rms_error(a: &[f32], b: &[f32]) -> f32 {
+    let sum: f32 = a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum();
+    (sum / a.len() as f32).sqrt()
+}
+
+pub fn peak(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0, |acc, s| acc.max(s.abs()))
+}
+
+/// Spectral centroid in Hz of the averaged magnitude spectrum
+pub fn spectral_centroid(samples: &[f32]) -> f32 {
+    let spectrum = magnitude_spectrum(samples, FFT_SIZE);
+    let bin_width = SAMPLE_RATE / FFT_SIZE as f32;
+
+    let (weighted, total) = spectrum
+        .iter()
+        .enumerate()
+        .fold((0.0, 0.0), |(weighted, total), (bin, mag)| {
+            (weighted + bin as f32 * bin_width * mag, total + mag)
+        });
+
+    if total == 0.0 { 0.0 } else { weighted / total }
+}
+
+/// Magnitude spectrum (bins `0..=size / 2`) averaged over half-overlapping
+/// frames of `size` samples. `size` must be a power of two.
+///
+/// Frames are Blackman-Harris windowed, so leakage stays below -90 dB past a
+/// few bins from a partial and doesn't get mistaken for aliasing
+pub fn magnitude_spectrum(samples: &[f32], size: usize) -> Vec<f32> {
+    let window: Vec<f64> = (0..size)
+        .map(|i| {
+            let x = std::f64::consts::TAU * i as f64 / size as f64;
+            0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos() - 0.01168 * (3.0 * x).cos()
+        })
+        .collect();
+
+    let mut total = vec![0.0; size / 2 + 1];
+    let mut frames = 0;
+
+    for start in (0..=samples.len().saturating_sub(size)).step_by(size / 2) {
+        let mut frame: Vec<(f64, f64)> = (0..size)
+            .map(|i| {
+                (
+                    samples.get(start + i).copied().unwrap_or(0.0) as f64 * window[i],
+                    0.0,
+                )
+            })
+            .collect();
+
+        fft(&mut frame);
+
+        for (acc, (re, im)) in total.iter_mut().zip(&frame) {
+            *acc += (re * re + im * im).sqrt();
+        }
+        frames += 1;
+    }
+
+    total.iter().map(|m| (m / frames as f64) as f32).collect()
+}
+
+/// In-place iterative radix-2 FFT over `(re, im)` pairs
+fn fft(data: &mut [(f64, f64)]) {
+    let n = data.len();
+    assert!(n.is_power_of_two(), "FFT size must be a power of two");
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -std::f64::consts::TAU / len as f64;
+        let (w_im, w_re) = angle.sin_cos();
+
+        for chunk in data.chunks_mut(len) {
+            let (mut re, mut im) = (1.0, 0.0);
+
+            for k in 0..len / 2 {
+                let (a_re, a_im) = chunk[k];
+                let (b_re, b_im) = chunk[k + len / 2];
+                let (t_re, t_im) = (b_re * re - b_im * im, b_re * im + b_im * re);
+
+                chunk[k] = (a_re + t_re, a_im + t_im);
+                chunk[k + len / 2] = (a_re - t_re, a_im - t_im);
+
+                (re, im) = (re * w_re - im * w_im, re * w_im + im * w_re);
+            }
+        }
+
+        len <<= 1;
+    }
+}
+
+fn write_wav(path: &Path, samples: &[f32]) {
+    let data_len = (samples.len() * 4) as u32;
+    let mut bytes = Vec::with_capacity(44 + data_len as usize);
+
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&3u16.to_le_bytes()); // IEEE float
+    bytes.extend_from_slice(&1u16.to_le_bytes()); // mono
+    bytes.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+    bytes.extend_from_slice(&(SAMPLE_RATE as u32 * 4).to_le_bytes());
+    bytes.extend_from_slice(&4u16.to_le_bytes());
+    bytes.extend_from_slice(&32u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_len.to_le_bytes());
+
+    for s in samples {
+        bytes.extend_from_slice(&s.to_le_bytes());
+    }
+
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, bytes).unwrap();
+}
+
+fn read_wav(path: &Path) -> Option<Vec<f32>> {
+    let bytes = fs::read(path).ok()?;
+    assert_eq!(
+        &bytes[0..4],
+        b"RIFF",
+        "{} is not a WAV file",
+        path.display()
+    );
+
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        pos += 8;
+
+        if id == b"data" {
+            let samples = bytes[pos..pos + len]
+                .as_chunks::<4>()
+                .0
+                .iter()
+                .map(|b| f32::from_le_bytes(*b))
+                .collect();
+            return Some(samples);
+        }
+
+        pos += len;
+    }
+
+    panic!("{} has no data chunk", path.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_single_note() {
+        let samples = render(&[(0, on(57)), (120, off(57))], 360);
+        check("single_note", &samples);
+    }
+
+    #[test]
+    fn golden_chord() {
+        let script = [
+            (0, on(48)),
+            (10, on(52)),
+            (20, on(55)),
+            (150, off(52)),
+            (180, off(48)),
+            (200, off(55)),
+        ];
+        check("chord", &render(&script, 420));
+    }
+
+    #[test]
+    fn golden_retrigger() {
+        // note-off in the middle of the attack, then the same note again
+        let script = [(0, on(69)), (30, off(69)), (60, on(69)), (180, off(69))];
+        check("retrigger", &render(&script, 400));
+    }
+
+    #[test]
+    fn golden_filtered_bass() {
+        let mut filter = Filter::new();
+        filter.cutoff = 800.0;
+
+        let samples: Vec<f32> = render(&[(0, on(36)), (200, off(36))], 420)
+            .into_iter()
+            .map(|s| filter.process(s))
+            .collect();
+
+        check("filtered_bass", &samples);
+    }
+
+    #[test]
+    fn spectral_centroid_of_sine() {
+        let freq = 1_000.0;
+        let sine: Vec<f32> = (0..FFT_SIZE * 4)
+            .map(|i| (core::f32::consts::TAU * freq * i as f32 / SAMPLE_RATE).sin())
+            .collect();
+
+        let centroid = spectral_centroid(&sine);
+        assert!((centroid - freq).abs() < 30.0, "centroid: {centroid}");
+    }
+}
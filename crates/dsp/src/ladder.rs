@@ -0,0 +1,247 @@
+use core::f32::consts::PI;
+
+use defmt::Format;
+use libm::tanf;
+
+use crate::consts::SAMPLE_RATE;
+
+/// Feedback gain at full resonance. 4 is the edge of self-oscillation of the
+/// linear ladder, a bit more keeps it ringing through the saturation
+const MAX_FEEDBACK: f32 = 4.2;
+
+/// Output tap, one pole per 6 dB/oct
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Slope {
+    Db6,
+    Db12,
+    Db18,
+    Db24,
+}
+
+impl Slope {
+    pub fn next(&self) -> Self {
+        match self {
+            Slope::Db6 => Slope::Db12,
+            Slope::Db12 => Slope::Db18,
+            Slope::Db18 => Slope::Db24,
+            Slope::Db24 => Slope::Db6,
+        }
+    }
+
+    const fn stage(&self) -> usize {
+        match self {
+            Slope::Db6 => 0,
+            Slope::Db12 => 1,
+            Slope::Db18 => 2,
+            Slope::Db24 => 3,
+        }
+    }
+}
+
+/// Moog-style ladder: four one-pole lowpass stages with resonance fed back
+/// from the last one. The stages are zero-delay-feedback (TPT) one-poles and
+/// the feedback loop is solved for the linear part of the ladder, with the
+/// saturation applied on top, which keeps the cutoff accurate up to Nyquist
+pub struct Ladder {
+    pub cutoff: f32,
+    /// `0.0..=1.0`, self-oscillates at the top
+    pub resonance: f32,
+    /// Input gain into the saturating stages, `1.0` is clean-ish
+    pub drive: f32,
+    pub slope: Slope,
+    pub sample_rate: f32,
+    states: [f32; 4],
+    /// One-pole gain `g / (1 + g)` and the cutoff it was computed for
+    gain: f32,
+    gain_cutoff: f32,
+}
+
+impl Ladder {
+    pub const fn init() -> Self {
+        Self {
+            cutoff: 10_000.0,
+            resonance: 0.0,
+            drive: 1.0,
+            slope: Slope::Db24,
+            sample_rate: SAMPLE_RATE,
+            states: [0.0; 4],
+            gain: 0.0,
+            gain_cutoff: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.process_at(input, self.cutoff)
+    }
+
+    /// Filters `input` at `cutoff` instead of `self.cutoff`, for cutoff
+    /// modulated every sample
+    pub fn process_at(&mut self, input: f32, cutoff: f32) -> f32 {
+        if cutoff != self.gain_cutoff {
+            let clamped = cutoff.clamp(10.0, 0.45 * self.sample_rate);
+            let g = tanf(PI * clamped / self.sample_rate);
+            self.gain = g / (1.0 + g);
+            self.gain_cutoff = cutoff;
+        }
+
+        let gain = self.gain;
+        let k = MAX_FEEDBACK * self.resonance;
+        // the feedback takes 1 / (1 + k) off the passband, put it back
+        let input = self.drive * input * (1.0 + k);
+
+        // each stage is `gain * x + (1 - gain) * state`, so the ladder output
+        // is `gain^4 * x` plus what the states contribute
+        let from_states = self
+            .states
+            .iter()
+            .fold(0.0, |acc, s| gain * acc + (1.0 - gain) * s);
+        let gain4 = gain * gain * gain * gain;
+        let estimate = (gain4 * input + from_states) / (1.0 + k * gain4);
+
+        let mut x = saturate(input - k * estimate);
+        let mut outputs = [0.0; 4];
+
+        for (state, output) in self.states.iter_mut().zip(outputs.iter_mut()) {
+            let v = gain * (x - *state);
+            *output = v + *state;
+            *state = *output + v;
+            x = saturate(*output);
+        }
+
+        outputs[self.slope.stage()]
+    }
+}
+
+/// Padé approximation of tanh, exact at ±3 where it reaches ±1
+fn saturate(x: f32) -> f32 {
+    let x = x.clamp(-3.0, 3.0);
+    x * (27.0 + x * x) / (27.0 + 9.0 * x * x)
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::FRAC_1_SQRT_2;
+
+    const CUTOFFS: [f32; 7] = [20.0, 100.0, 500.0, 2_000.0, 5_000.0, 10_000.0, 20_000.0];
+
+    fn ladder(cutoff: f32, resonance: f32, slope: Slope) -> Ladder {
+        let mut ladder = Ladder::init();
+        ladder.cutoff = cutoff;
+        ladder.resonance = resonance;
+        ladder.slope = slope;
+        ladder
+    }
+
+    /// Steady state gain for a sine at `freq`, small enough to stay linear
+    fn gain_at(ladder: &mut Ladder, freq: f32) -> f32 {
+        let amplitude = 0.01;
+        // in f64, f32 phase jitter alone would leak past the 24 dB slope
+        let sine = |i: usize| {
+            let phase = core::f64::consts::TAU * freq as f64 * i as f64 / SAMPLE_RATE as f64;
+            amplitude * phase.sin() as f32
+        };
+        let settle = SAMPLE_RATE as usize / 2;
+
+        for i in 0..settle {
+            ladder.process(sine(i));
+        }
+
+        let peak = (settle..settle + SAMPLE_RATE as usize / 5)
+            .fold(0.0, |peak: f32, i| peak.max(ladder.process(sine(i)).abs()));
+
+        peak / amplitude
+    }
+
+    #[test]
+    fn cutoff_is_accurate() {
+        // each pole is -3 dB at the cutoff
+        for (slope, expected) in [
+            (Slope::Db6, FRAC_1_SQRT_2),
+            (Slope::Db12, 0.5),
+            (Slope::Db24, 0.25),
+        ] {
+            for cutoff in CUTOFFS {
+                let gain = gain_at(&mut ladder(cutoff, 0.0, slope), cutoff);
+                assert!(
+                    (gain / expected - 1.0).abs() < 0.03,
+                    "{cutoff} Hz: {gain}, expected {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn slopes_roll_off_by_their_poles() {
+        let cutoff = 500.0;
+
+        for (slope, poles) in [
+            (Slope::Db6, 1),
+            (Slope::Db12, 2),
+            (Slope::Db18, 3),
+            (Slope::Db24, 4),
+        ] {
+            let gain = gain_at(&mut ladder(cutoff, 0.0, slope), cutoff * 8.0);
+            // three octaves up
+            let expected = (1.0 / 65f32.sqrt()).powi(poles);
+            assert!((gain / expected - 1.0).abs() < 0.1, "{poles} poles: {gain}");
+        }
+    }
+
+    #[test]
+    fn resonance_peaks_at_the_cutoff() {
+        for cutoff in CUTOFFS {
+            let at = gain_at(&mut ladder(cutoff, 0.8, Slope::Db24), cutoff);
+            let below = gain_at(&mut ladder(cutoff, 0.8, Slope::Db24), cutoff * 0.8);
+            let above = gain_at(&mut ladder(cutoff, 0.8, Slope::Db24), cutoff * 1.25);
+
+            assert!(
+                at > below && at > above,
+                "{cutoff} Hz: {below} {at} {above}"
+            );
+        }
+    }
+
+    #[test]
+    fn passband_is_compensated() {
+        let flat = gain_at(&mut ladder(2_000.0, 0.0, Slope::Db24), 50.0);
+        let resonant = gain_at(&mut ladder(2_000.0, 0.9, Slope::Db24), 50.0);
+
+        assert!((resonant / flat - 1.0).abs() < 0.05, "{flat} {resonant}");
+    }
+
+    #[test]
+    fn stable_across_the_range() {
+        for cutoff in CUTOFFS {
+            for resonance in [0.0, 0.5, 1.0] {
+                let mut ladder = ladder(cutoff, resonance, Slope::Db24);
+                ladder.drive = 4.0;
+
+                let samples: Vec<f32> = (0..SAMPLE_RATE as usize / 2)
+                    .map(|i| ladder.process(if (i / 200) % 2 == 0 { 1.0 } else { -1.0 }))
+                    .collect();
+
+                assert!(
+                    samples.iter().all(|s| s.is_finite() && s.abs() < 2.0),
+                    "{cutoff} Hz, resonance {resonance}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn self_oscillates_at_full_resonance() {
+        for cutoff in [100.0, 1_000.0, 10_000.0] {
+            let mut ladder = ladder(cutoff, 1.0, Slope::Db24);
+            ladder.process(0.1);
+
+            let samples: Vec<f32> = (0..SAMPLE_RATE as usize)
+                .map(|_| ladder.process(0.0))
+                .collect();
+            let tail = crate::golden::peak(&samples[samples.len() / 2..]);
+
+            assert!(tail > 0.05, "{cutoff} Hz: {tail}");
+        }
+    }
+}
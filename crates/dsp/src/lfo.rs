@@ -0,0 +1,519 @@
+use defmt::{Format, info};
+
+use crate::{
+    adsr::TimeMs,
+    consts::SAMPLE_RATE,
+    encoder::Rotation,
+    noise::Rng,
+    wavetable::{self, Interpolation},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    SawUp,
+    SawDown,
+    Square,
+    /// A new random value every cycle
+    SampleAndHold,
+    /// Glides from one random value to the next over a cycle
+    SmoothRandom,
+}
+
+impl LfoShape {
+    pub fn next(&self) -> Self {
+        use LfoShape::*;
+
+        match self {
+            Sine => Triangle,
+            Triangle => SawUp,
+            SawUp => SawDown,
+            SawDown => Square,
+            Square => SampleAndHold,
+            SampleAndHold => SmoothRandom,
+            SmoothRandom => Sine,
+        }
+    }
+}
+
+/// Note lengths an LFO cycle can sync to
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Division {
+    FourBars,
+    TwoBars,
+    Bar,
+    Half,
+    Quarter,
+    QuarterTriplet,
+    Eighth,
+    EighthTriplet,
+    Sixteenth,
+    ThirtySecond,
+}
+
+impl Division {
+    /// Length in quarter notes
+    pub const fn beats(&self) -> f32 {
+        use Division::*;
+
+        match self {
+            FourBars => 16.0,
+            TwoBars => 8.0,
+            Bar => 4.0,
+            Half => 2.0,
+            Quarter => 1.0,
+            QuarterTriplet => 2.0 / 3.0,
+            Eighth => 0.5,
+            EighthTriplet => 1.0 / 3.0,
+            Sixteenth => 0.25,
+            ThirtySecond => 0.125,
+        }
+    }
+
+    /// One step shorter
+    pub(crate) fn faster(&self) -> Self {
+        use Division::*;
+
+        match self {
+            FourBars => TwoBars,
+            TwoBars => Bar,
+            Bar => Half,
+            Half => Quarter,
+            Quarter => QuarterTriplet,
+            QuarterTriplet => Eighth,
+            Eighth => EighthTriplet,
+            EighthTriplet => Sixteenth,
+            Sixteenth | ThirtySecond => ThirtySecond,
+        }
+    }
+
+    /// One step longer
+    pub(crate) fn slower(&self) -> Self {
+        use Division::*;
+
+        match self {
+            FourBars | TwoBars => FourBars,
+            Bar => TwoBars,
+            Half => Bar,
+            Quarter => Half,
+            QuarterTriplet => Quarter,
+            Eighth => QuarterTriplet,
+            EighthTriplet => Eighth,
+            Sixteenth => EighthTriplet,
+            ThirtySecond => Sixteenth,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum LfoRate {
+    Hz(f32),
+    /// A cycle per division at the current tempo
+    Sync(Division),
+}
+
+impl LfoRate {
+    pub fn hz(&self, tempo: f32) -> f32 {
+        match self {
+            LfoRate::Hz(hz) => *hz,
+            LfoRate::Sync(division) => tempo / 60.0 / division.beats(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum LfoMode {
+    /// One LFO shared by all the voices
+    Global,
+    /// An LFO of its own in every voice
+    PerVoice,
+}
+
+#[derive(Clone)]
+pub struct LfoSettings {
+    pub shape: LfoShape,
+    pub rate: LfoRate,
+    pub mode: LfoMode,
+    /// Restarts the cycle on note on. Free-running per-voice LFOs start
+    /// at a random point instead, so voices don't move in lockstep
+    pub retrigger: bool,
+    /// Holds the depth at 0 from the note on, or from the start when
+    /// free-running, before the fade in
+    pub delay: TimeMs,
+    /// Ramps the depth in after the delay
+    pub fade_in: TimeMs,
+    /// `-1.0..=1.0`, or `0.0..=1.0` when unipolar
+    pub bipolar: bool,
+}
+
+impl LfoSettings {
+    pub const fn init() -> Self {
+        Self {
+            shape: LfoShape::Sine,
+            rate: LfoRate::Hz(5.0),
+            mode: LfoMode::PerVoice,
+            retrigger: true,
+            delay: TimeMs(0),
+            fade_in: TimeMs(0),
+            bipolar: true,
+        }
+    }
+
+    pub fn adjust(&mut self, param: &LfoParams, rotation: Rotation) {
+        match param {
+            LfoParams::Shape => {
+                self.shape = self.shape.next();
+                info!("Set LFO shape: {}", self.shape);
+            }
+            LfoParams::Rate => {
+                self.rate = match (self.rate, rotation) {
+                    (LfoRate::Hz(hz), Rotation::Right) => LfoRate::Hz((hz * 1.05).min(50.0)),
+                    (LfoRate::Hz(hz), Rotation::Left) => LfoRate::Hz((hz / 1.05).max(0.01)),
+                    (LfoRate::Sync(division), Rotation::Right) => LfoRate::Sync(division.faster()),
+                    (LfoRate::Sync(division), Rotation::Left) => LfoRate::Sync(division.slower()),
+                };
+                info!("Set LFO rate: {}", self.rate);
+            }
+            LfoParams::Sync => {
+                self.rate = match rotation {
+                    Rotation::Right => LfoRate::Sync(Division::Quarter),
+                    Rotation::Left => LfoRate::Hz(5.0),
+                };
+                info!("Set LFO rate: {}", self.rate);
+            }
+            LfoParams::Mode => {
+                self.mode = match self.mode {
+                    LfoMode::Global => LfoMode::PerVoice,
+                    LfoMode::PerVoice => LfoMode::Global,
+                };
+                info!("Set LFO mode: {}", self.mode);
+            }
+            LfoParams::Retrigger => {
+                self.retrigger = rotation == Rotation::Right;
+                info!("Set LFO retrigger: {}", self.retrigger);
+            }
+            LfoParams::Delay => {
+                self.delay.0 = match rotation {
+                    Rotation::Right => self.delay.0.saturating_add(50).min(5_000),
+                    Rotation::Left => self.delay.0.saturating_sub(50),
+                };
+                info!("Set LFO delay: {} ms", self.delay.0);
+            }
+            LfoParams::FadeIn => {
+                self.fade_in.0 = match rotation {
+                    Rotation::Right => self.fade_in.0.saturating_add(50).min(5_000),
+                    Rotation::Left => self.fade_in.0.saturating_sub(50),
+                };
+                info!("Set LFO fade in: {} ms", self.fade_in.0);
+            }
+            LfoParams::Polarity => {
+                self.bipolar = rotation == Rotation::Right;
+                info!("Set LFO bipolar: {}", self.bipolar);
+            }
+        }
+    }
+}
+
+/// Phase and random state of an LFO. It's advanced in control periods
+/// rather than samples, which is plenty for a low frequency
+pub struct Lfo {
+    phase: f32,
+    rng: Rng,
+    /// The random value of this cycle and the one before it
+    random: f32,
+    last_random: f32,
+    /// Samples since the delay and fade in started
+    elapsed: u32,
+}
+
+impl Lfo {
+    pub const fn new(seed: u32) -> Self {
+        Self {
+            phase: 0.0,
+            rng: Rng::new(seed),
+            random: 0.0,
+            last_random: 0.0,
+            elapsed: 0,
+        }
+    }
+
+    /// Restarts the cycle, the delay and the fade in
+    pub fn retrigger(&mut self) {
+        self.phase = 0.0;
+        self.elapsed = 0;
+        self.next_random();
+    }
+
+    /// Starts at a random point of the cycle, restarting the delay and fade in
+    pub fn scatter(&mut self) {
+        self.phase = (self.rng.next_bipolar() + 1.0) / 2.0;
+        self.elapsed = 0;
+        self.next_random();
+    }
+
+    /// Moves `samples` ahead at `tempo` (in BPM, for synced rates) with the
+    /// rate scaled by `rate_mod`, and returns the output for the new position
+    pub fn advance(
+        &mut self,
+        settings: &LfoSettings,
+        tempo: f32,
+        rate_mod: f32,
+        samples: u32,
+    ) -> f32 {
+        self.phase += settings.rate.hz(tempo) * rate_mod * samples as f32 / SAMPLE_RATE;
+
+        if self.phase >= 1.0 {
+            // slower than a control period per cycle, so at most one wrap
+            self.phase %= 1.0;
+            self.next_random();
+        }
+
+        self.elapsed = self.elapsed.saturating_add(samples);
+
+        // faded after the offset, so a unipolar one rises from 0 too
+        let value = self.value(settings.shape);
+        if settings.bipolar {
+            value * self.fade(settings)
+        } else {
+            (value + 1.0) / 2.0 * self.fade(settings)
+        }
+    }
+
+    fn value(&self, shape: LfoShape) -> f32 {
+        let phase = self.phase;
+
+        match shape {
+            LfoShape::Sine => wavetable::lookup(&wavetable::SINE, phase, Interpolation::Linear),
+            // starts at 0, rising, like the sine
+            LfoShape::Triangle => 1.0 - 4.0 * (wrap(phase + 0.25) - 0.5).abs(),
+            LfoShape::SawUp => 2.0 * phase - 1.0,
+            LfoShape::SawDown => 1.0 - 2.0 * phase,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleAndHold => self.random,
+            LfoShape::SmoothRandom => self.last_random + (self.random - self.last_random) * phase,
+        }
+    }
+
+    fn fade(&self, settings: &LfoSettings) -> f32 {
+        let delay_samples = settings.delay.0 as f32 * SAMPLE_RATE / 1000.0;
+        let fade_samples = settings.fade_in.0 as f32 * SAMPLE_RATE / 1000.0;
+        let elapsed = self.elapsed as f32 - delay_samples;
+
+        if elapsed < 0.0 {
+            0.0
+        } else if elapsed >= fade_samples {
+            1.0
+        } else {
+            elapsed / fade_samples
+        }
+    }
+
+    fn next_random(&mut self) {
+        self.last_random = self.random;
+        self.random = self.rng.next_bipolar();
+    }
+}
+
+fn wrap(phase: f32) -> f32 {
+    if phase >= 1.0 { phase - 1.0 } else { phase }
+}
+
+#[derive(Debug, Format)]
+pub enum LfoParams {
+    Shape,
+    Rate,
+    Sync,
+    Mode,
+    Retrigger,
+    Delay,
+    FadeIn,
+    Polarity,
+}
+
+impl LfoParams {
+    pub const fn init_param() -> Self {
+        Self::Shape
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use LfoParams::*;
+
+        match param {
+            Shape => Some(Rate),
+            Rate => Some(Sync),
+            Sync => Some(Mode),
+            Mode => Some(Retrigger),
+            Retrigger => Some(Delay),
+            Delay => Some(FadeIn),
+            FadeIn => Some(Polarity),
+            Polarity => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::CONTROL_PERIOD;
+
+    fn settings(shape: LfoShape, rate: LfoRate) -> LfoSettings {
+        LfoSettings {
+            shape,
+            rate,
+            ..LfoSettings::init()
+        }
+    }
+
+    /// A second of control periods
+    fn run(lfo: &mut Lfo, settings: &LfoSettings, tempo: f32) -> Vec<f32> {
+        (0..(SAMPLE_RATE / CONTROL_PERIOD as f32) as usize)
+            .map(|_| lfo.advance(settings, tempo, 1.0, CONTROL_PERIOD))
+            .collect()
+    }
+
+    fn rising_zero_crossings(values: &[f32]) -> usize {
+        values
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count()
+    }
+
+    #[test]
+    fn shapes_stay_in_range() {
+        let shapes = [
+            LfoShape::Sine,
+            LfoShape::Triangle,
+            LfoShape::SawUp,
+            LfoShape::SawDown,
+            LfoShape::Square,
+            LfoShape::SampleAndHold,
+            LfoShape::SmoothRandom,
+        ];
+
+        for shape in shapes {
+            let mut settings = settings(shape, LfoRate::Hz(7.0));
+            let values = run(&mut Lfo::new(1), &settings, 120.0);
+            assert!(values.iter().all(|v| (-1.0..=1.0).contains(v)));
+            assert!(
+                values.iter().any(|v| *v < -0.5) && values.iter().any(|v| *v > 0.5),
+                "{shape:?}"
+            );
+
+            settings.bipolar = false;
+            let values = run(&mut Lfo::new(1), &settings, 120.0);
+            assert!(values.iter().all(|v| (0.0..=1.0).contains(v)));
+        }
+    }
+
+    #[test]
+    fn rate_in_hz() {
+        let values = run(
+            &mut Lfo::new(1),
+            &settings(LfoShape::Sine, LfoRate::Hz(4.5)),
+            120.0,
+        );
+        assert_eq!(rising_zero_crossings(&values), 4);
+    }
+
+    #[test]
+    fn rate_synced_to_tempo() {
+        let settings = settings(LfoShape::Triangle, LfoRate::Sync(Division::Eighth));
+
+        // eighths at 126 BPM are 4.2 Hz, at 96 BPM 3.2 Hz
+        assert_eq!(
+            rising_zero_crossings(&run(&mut Lfo::new(1), &settings, 126.0)),
+            4
+        );
+        assert_eq!(
+            rising_zero_crossings(&run(&mut Lfo::new(1), &settings, 96.0)),
+            3
+        );
+    }
+
+    #[test]
+    fn retrigger_restarts_the_cycle() {
+        let settings = settings(LfoShape::SawUp, LfoRate::Hz(3.0));
+        let mut lfo = Lfo::new(1);
+        let first = run(&mut lfo, &settings, 120.0);
+
+        run(&mut lfo, &settings, 120.0);
+        lfo.advance(&settings, 120.0, 1.0, 1234);
+        lfo.retrigger();
+
+        assert_eq!(run(&mut lfo, &settings, 120.0), first);
+    }
+
+    #[test]
+    fn fade_in_ramps_the_depth() {
+        let mut settings = settings(LfoShape::Square, LfoRate::Hz(10.0));
+        settings.fade_in = TimeMs(500);
+
+        let values = run(&mut Lfo::new(1), &settings, 120.0);
+        let peak = |range: &[f32]| range.iter().fold(0.0f32, |acc, v| acc.max(v.abs()));
+        let quarter = values.len() / 4;
+
+        assert!(peak(&values[..quarter / 2]) < 0.3);
+        assert!(peak(&values[quarter..quarter * 3 / 2]) < 0.8);
+        assert_eq!(peak(&values[3 * quarter..]), 1.0);
+    }
+
+    #[test]
+    fn delay_holds_off_the_fade_in() {
+        let mut settings = settings(LfoShape::Square, LfoRate::Hz(10.0));
+        settings.delay = TimeMs(250);
+        settings.fade_in = TimeMs(250);
+
+        let values = run(&mut Lfo::new(1), &settings, 120.0);
+        let peak = |range: &[f32]| range.iter().fold(0.0f32, |acc, v| acc.max(v.abs()));
+        let quarter = values.len() / 4;
+
+        assert_eq!(peak(&values[..quarter - 1]), 0.0);
+        assert!(peak(&values[quarter..quarter * 3 / 2]) < 0.6);
+        assert_eq!(peak(&values[2 * quarter..]), 1.0);
+    }
+
+    #[test]
+    fn delay_param_steps_in_ms() {
+        let mut settings = LfoSettings::init();
+        settings.adjust(&LfoParams::Delay, Rotation::Right);
+        settings.adjust(&LfoParams::Delay, Rotation::Right);
+        assert_eq!(settings.delay.0, 100);
+
+        settings.adjust(&LfoParams::Delay, Rotation::Left);
+        settings.adjust(&LfoParams::Delay, Rotation::Left);
+        settings.adjust(&LfoParams::Delay, Rotation::Left);
+        assert_eq!(settings.delay.0, 0);
+    }
+
+    #[test]
+    fn unipolar_fade_in_rises_from_zero() {
+        let mut settings = settings(LfoShape::Square, LfoRate::Hz(10.0));
+        settings.fade_in = TimeMs(500);
+        settings.bipolar = false;
+
+        let values = run(&mut Lfo::new(1), &settings, 120.0);
+
+        assert!(values[0] < 0.01);
+        assert!(values[..values.len() / 8].iter().all(|v| *v < 0.3));
+        assert_eq!(values.iter().fold(0.0f32, |acc, v| acc.max(*v)), 1.0);
+    }
+
+    #[test]
+    fn random_shapes_are_reproducible() {
+        for shape in [LfoShape::SampleAndHold, LfoShape::SmoothRandom] {
+            let settings = settings(shape, LfoRate::Hz(20.0));
+            assert_eq!(
+                run(&mut Lfo::new(5), &settings, 120.0),
+                run(&mut Lfo::new(5), &settings, 120.0)
+            );
+        }
+    }
+}
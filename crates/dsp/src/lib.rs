@@ -1,27 +1,52 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_main)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(not(feature = "std"))]
 use defmt_brtt as _; // global logger
 
+#[cfg(not(feature = "std"))]
 use panic_halt as _;
 
+#[cfg(not(feature = "std"))]
 use stm32h7xx_hal as _; // memory layout
 
 pub mod adsr;
+pub mod arp;
 pub mod audio;
+pub mod breakpoint;
+pub mod clock;
 pub mod consts;
+pub mod delay;
+pub mod drive;
 pub mod encoder;
 pub mod filter;
+pub mod fm;
+#[cfg(all(test, feature = "std"))]
+mod golden;
 pub mod i2c_scanner;
+pub mod ladder;
 pub mod lcd;
+pub mod lfo;
 pub mod midi;
+pub mod mod_matrix;
+pub mod modulation;
+pub mod mono;
+pub mod noise;
 pub mod oscillator;
+pub mod patch;
+pub mod pedal;
+pub mod reverb;
+pub mod sequencer;
 pub mod state;
+pub mod unison;
+pub mod velocity;
 pub mod voice;
+pub mod wavetable;
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(not(feature = "std"))]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
@@ -35,7 +60,21 @@ defmt::timestamp!("{=usize}", {
     n
 });
 
+/// Host builds have no probe to log to
+#[cfg(feature = "std")]
+#[defmt::global_logger]
+struct HostLogger;
+
+#[cfg(feature = "std")]
+unsafe impl defmt::Logger for HostLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
 /// Terminates the application and makes `probe-rs` exit with exit-code = 0
+#[cfg(not(feature = "std"))]
 pub fn exit() -> ! {
     loop {
         cortex_m::asm::bkpt();
@@ -0,0 +1,330 @@
+use defmt::{Format, info};
+
+use crate::{
+    consts::{LFOS, MOD_SLOTS},
+    encoder::Rotation,
+};
+
+/// Semitones at full depth
+const PITCH_RANGE: f32 = 12.0;
+const DUTY_RANGE: f32 = 0.45;
+/// Octaves at full depth
+const CUTOFF_RANGE: f32 = 4.0;
+/// Octaves at full depth
+const LFO_RATE_RANGE: f32 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum ModSource {
+    Lfo1,
+    Lfo2,
+    AmpEnvelope,
+    FilterEnvelope,
+    /// The patch's breakpoint envelope
+    ModEnvelope,
+    Velocity,
+    /// Bipolar around middle C, ±1 five octaves away
+    Key,
+    ModWheel,
+    /// Channel or polyphonic, whichever is higher
+    Aftertouch,
+    PitchBend,
+    /// Picked at note on, bipolar
+    Random,
+}
+
+impl ModSource {
+    pub fn next(&self) -> Self {
+        use ModSource::*;
+
+        match self {
+            Lfo1 => Lfo2,
+            Lfo2 => AmpEnvelope,
+            AmpEnvelope => FilterEnvelope,
+            FilterEnvelope => ModEnvelope,
+            ModEnvelope => Velocity,
+            Velocity => Key,
+            Key => ModWheel,
+            ModWheel => Aftertouch,
+            Aftertouch => PitchBend,
+            PitchBend => Random,
+            Random => Lfo1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum ModDestination {
+    Pitch,
+    /// Pulse width of all the oscillators
+    Duty,
+    Cutoff,
+    Resonance,
+    Amp,
+    Pan,
+    Lfo1Rate,
+    Lfo2Rate,
+}
+
+impl ModDestination {
+    pub fn next(&self) -> Self {
+        use ModDestination::*;
+
+        match self {
+            Pitch => Duty,
+            Duty => Cutoff,
+            Cutoff => Resonance,
+            Resonance => Amp,
+            Amp => Pan,
+            Pan => Lfo1Rate,
+            Lfo1Rate => Lfo2Rate,
+            Lfo2Rate => Pitch,
+        }
+    }
+}
+
+/// Current values of the sources, for one voice or, with the per-voice
+/// ones left at zero, for the whole pool
+#[derive(Clone, Copy, Default)]
+pub struct ModSources {
+    pub lfos: [f32; LFOS],
+    pub amp_envelope: f32,
+    pub filter_envelope: f32,
+    pub mod_envelope: f32,
+    pub velocity: f32,
+    pub key: f32,
+    pub mod_wheel: f32,
+    pub aftertouch: f32,
+    pub pitch_bend: f32,
+    pub random: f32,
+}
+
+impl ModSources {
+    fn get(&self, source: ModSource) -> f32 {
+        match source {
+            ModSource::Lfo1 => self.lfos[0],
+            ModSource::Lfo2 => self.lfos[1],
+            ModSource::AmpEnvelope => self.amp_envelope,
+            ModSource::FilterEnvelope => self.filter_envelope,
+            ModSource::ModEnvelope => self.mod_envelope,
+            ModSource::Velocity => self.velocity,
+            ModSource::Key => self.key,
+            ModSource::ModWheel => self.mod_wheel,
+            ModSource::Aftertouch => self.aftertouch,
+            ModSource::PitchBend => self.pitch_bend,
+            ModSource::Random => self.random,
+        }
+    }
+}
+
+/// Summed modulation of every destination, in its own units
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct ModValues {
+    /// Semitones
+    pub pitch: f32,
+    pub duty: f32,
+    /// Octaves
+    pub cutoff: f32,
+    pub resonance: f32,
+    /// Added to a gain of 1
+    pub amp: f32,
+    /// Added to the pan position, `-1.0` is hard left
+    pub pan: f32,
+    /// Octaves
+    pub lfo_rates: [f32; LFOS],
+}
+
+/// One route of the matrix
+#[derive(Clone, Copy)]
+pub struct ModSlot {
+    pub source: ModSource,
+    pub destination: ModDestination,
+    /// `-1.0..=1.0`, the slot is off at 0
+    pub depth: f32,
+    /// Scales the route by a second source, like the mod wheel bringing
+    /// in vibrato
+    pub via: Option<ModSource>,
+}
+
+impl ModSlot {
+    pub const fn init() -> Self {
+        Self {
+            source: ModSource::Lfo1,
+            destination: ModDestination::Pitch,
+            depth: 0.0,
+            via: None,
+        }
+    }
+
+    pub fn adjust(&mut self, param: &ModParams, rotation: Rotation) {
+        match param {
+            ModParams::Source => {
+                self.source = self.source.next();
+                info!("Set mod source: {}", self.source);
+            }
+            ModParams::Destination => {
+                self.destination = self.destination.next();
+                info!("Set mod destination: {}", self.destination);
+            }
+            ModParams::Depth => {
+                let new = if rotation == Rotation::Right {
+                    self.depth + 0.01
+                } else {
+                    self.depth - 0.01
+                };
+
+                self.depth = new.clamp(-1.0, 1.0);
+                info!("Set mod depth: {}", self.depth);
+            }
+            ModParams::Via => {
+                self.via = match self.via {
+                    None => Some(ModSource::Lfo1),
+                    Some(ModSource::Random) => None,
+                    Some(source) => Some(source.next()),
+                };
+                info!("Set mod via: {}", self.via);
+            }
+        }
+    }
+}
+
+pub struct ModMatrix {
+    pub slots: [ModSlot; MOD_SLOTS],
+}
+
+impl ModMatrix {
+    pub const fn init() -> Self {
+        Self {
+            slots: [ModSlot::init(); MOD_SLOTS],
+        }
+    }
+
+    /// Meant for control rate, once per `CONTROL_PERIOD` samples
+    pub fn evaluate(&self, sources: &ModSources) -> ModValues {
+        let mut values = ModValues::default();
+
+        for slot in self.slots.iter().filter(|slot| slot.depth != 0.0) {
+            let mut amount = slot.depth * sources.get(slot.source);
+            if let Some(via) = slot.via {
+                amount *= sources.get(via);
+            }
+
+            match slot.destination {
+                ModDestination::Pitch => values.pitch += amount * PITCH_RANGE,
+                ModDestination::Duty => values.duty += amount * DUTY_RANGE,
+                ModDestination::Cutoff => values.cutoff += amount * CUTOFF_RANGE,
+                ModDestination::Resonance => values.resonance += amount,
+                ModDestination::Amp => values.amp += amount,
+                ModDestination::Pan => values.pan += amount,
+                ModDestination::Lfo1Rate => values.lfo_rates[0] += amount * LFO_RATE_RANGE,
+                ModDestination::Lfo2Rate => values.lfo_rates[1] += amount * LFO_RATE_RANGE,
+            }
+        }
+
+        values
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum ModParams {
+    Source,
+    Destination,
+    Depth,
+    Via,
+}
+
+impl ModParams {
+    pub const fn init_param() -> Self {
+        Self::Source
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use ModParams::*;
+
+        match param {
+            Source => Some(Destination),
+            Destination => Some(Depth),
+            Depth => Some(Via),
+            Via => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(source: ModSource, destination: ModDestination, depth: f32) -> ModSlot {
+        ModSlot {
+            source,
+            destination,
+            depth,
+            via: None,
+        }
+    }
+
+    #[test]
+    fn routes_sum_per_destination() {
+        let mut matrix = ModMatrix::init();
+        matrix.slots[0] = slot(ModSource::Lfo1, ModDestination::Pitch, 0.5);
+        matrix.slots[1] = slot(ModSource::Velocity, ModDestination::Pitch, -0.25);
+        matrix.slots[2] = slot(ModSource::FilterEnvelope, ModDestination::Cutoff, 1.0);
+
+        let sources = ModSources {
+            lfos: [1.0, 0.0],
+            velocity: 1.0,
+            filter_envelope: 0.5,
+            ..Default::default()
+        };
+        let values = matrix.evaluate(&sources);
+
+        assert_eq!(values.pitch, 0.25 * PITCH_RANGE);
+        assert_eq!(values.cutoff, 0.5 * CUTOFF_RANGE);
+        assert_eq!(values.amp, 0.0);
+    }
+
+    #[test]
+    fn depth_is_signed() {
+        let mut matrix = ModMatrix::init();
+        matrix.slots[0] = slot(ModSource::Random, ModDestination::Pan, -0.5);
+
+        let sources = ModSources {
+            random: 0.8,
+            ..Default::default()
+        };
+
+        assert_eq!(matrix.evaluate(&sources).pan, -0.4);
+    }
+
+    #[test]
+    fn via_scales_the_route() {
+        let mut matrix = ModMatrix::init();
+        matrix.slots[0] = ModSlot {
+            via: Some(ModSource::ModWheel),
+            ..slot(ModSource::Lfo2, ModDestination::Pitch, 1.0)
+        };
+
+        let mut sources = ModSources {
+            lfos: [0.0, 0.5],
+            ..Default::default()
+        };
+        assert_eq!(matrix.evaluate(&sources).pitch, 0.0);
+
+        sources.mod_wheel = 0.5;
+        assert_eq!(matrix.evaluate(&sources).pitch, 0.25 * PITCH_RANGE);
+    }
+
+    #[test]
+    fn empty_matrix_does_nothing() {
+        let sources = ModSources {
+            lfos: [1.0, 1.0],
+            amp_envelope: 1.0,
+            velocity: 1.0,
+            key: 1.0,
+            mod_wheel: 1.0,
+            ..Default::default()
+        };
+
+        assert_eq!(ModMatrix::init().evaluate(&sources), ModValues::default());
+    }
+}
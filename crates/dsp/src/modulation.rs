@@ -0,0 +1,518 @@
+use core::f32::consts::PI;
+
+use defmt::{Format, info};
+use libm::{exp2f, floorf, tanf};
+
+use crate::{
+    consts::{CONTROL_PERIOD, SAMPLE_RATE},
+    encoder::Rotation,
+    lfo::{Division, LfoRate},
+    wavetable::{self, Interpolation},
+};
+
+/// Chorus delay around which the voices sweep, and how far they can
+const CHORUS_CENTRE: f32 = SAMPLE_RATE * 0.012;
+const CHORUS_SWEEP: f32 = SAMPLE_RATE * 0.008;
+/// Shortest flanger delay, and the furthest it sweeps above it
+const FLANGER_MIN: f32 = SAMPLE_RATE * 0.000_5;
+const FLANGER_SWEEP: f32 = SAMPLE_RATE * 0.006;
+/// Lowest phaser notch frequency, and the octaves it sweeps above it
+const PHASER_MIN: f32 = 200.0;
+const PHASER_OCTAVES: f32 = 6.0;
+pub const MAX_STAGES: usize = 12;
+pub const MAX_CHORUS_VOICES: u8 = 3;
+const VOICES: usize = MAX_CHORUS_VOICES as usize;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Effect {
+    Off,
+    /// Up to 3 voices of a modulated delay, spread in stereo
+    Chorus,
+    /// A short modulated delay fed back on itself
+    Flanger,
+    /// Swept allpass stages mixed with the dry sound
+    Phaser,
+}
+
+impl Effect {
+    pub fn next(&self) -> Self {
+        use Effect::*;
+
+        match self {
+            Off => Chorus,
+            Chorus => Flanger,
+            Flanger => Phaser,
+            Phaser => Off,
+        }
+    }
+}
+
+pub struct EffectSettings {
+    pub effect: Effect,
+    pub rate: LfoRate,
+    /// `0.0..=1.0` of the sweep
+    pub depth: f32,
+    /// `-0.95..=0.95`, negative feedback flips the peaks and notches
+    pub feedback: f32,
+    /// `0.0..=1.0`, dry to wet
+    pub mix: f32,
+    /// Chorus voices, `1..=MAX_CHORUS_VOICES`
+    pub voices: u8,
+    /// Phaser allpass stages, even so each pair makes a notch
+    pub stages: u8,
+}
+
+impl EffectSettings {
+    pub const fn init() -> Self {
+        Self {
+            effect: Effect::Off,
+            rate: LfoRate::Hz(0.5),
+            depth: 0.5,
+            feedback: 0.0,
+            mix: 0.5,
+            voices: 2,
+            stages: 4,
+        }
+    }
+}
+
+/// One of the effect slots after the voice mix, running whichever effect
+/// is picked for it. The chorus and flanger run on a line given to it, so
+/// it can sit in whichever RAM has room for it
+pub struct EffectSlot {
+    pub settings: EffectSettings,
+    line: &'static mut [[f32; 2]],
+    write: usize,
+    /// `0.0..1.0` through the sweep
+    phase: f32,
+    tempo: f32,
+    /// Swept at control rate, gliding every sample in between
+    sweep: Sweep,
+    steps: Sweep,
+    control_timer: u32,
+    /// Allpass states, and the last output fed back, per side
+    allpass: [[f32; MAX_STAGES]; 2],
+    last: [f32; 2],
+}
+
+/// Where the sweep has the effect, per side
+#[derive(Clone, Copy)]
+struct Sweep {
+    /// Line delay in samples of each chorus voice, the flanger uses the first
+    delays: [[f32; VOICES]; 2],
+    /// Phaser allpass coefficient
+    coefficients: [f32; 2],
+}
+
+impl Sweep {
+    const fn init() -> Self {
+        Self {
+            delays: [[0.0; VOICES]; 2],
+            coefficients: [0.0; 2],
+        }
+    }
+}
+
+impl EffectSlot {
+    pub const fn init() -> Self {
+        Self {
+            settings: EffectSettings::init(),
+            line: &mut [],
+            write: 0,
+            phase: 0.0,
+            tempo: 120.0,
+            sweep: Sweep::init(),
+            steps: Sweep::init(),
+            control_timer: 0,
+            allpass: [[0.0; MAX_STAGES]; 2],
+            last: [0.0; 2],
+        }
+    }
+
+    /// Gives it the line for the chorus and flanger, its length sets the
+    /// longest delay. Until then they pass the sound through
+    pub fn set_line(&mut self, line: &'static mut [[f32; 2]]) {
+        line.fill([0.0; 2]);
+        self.line = line;
+        self.write = 0;
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.tempo = bpm;
+    }
+
+    /// Forgets what the last effect left behind
+    fn clear(&mut self) {
+        self.line.fill([0.0; 2]);
+        self.allpass = [[0.0; MAX_STAGES]; 2];
+        self.last = [0.0; 2];
+    }
+
+    pub fn process(&mut self, (left, right): (f32, f32)) -> (f32, f32) {
+        match self.settings.effect {
+            Effect::Off => return (left, right),
+            Effect::Chorus | Effect::Flanger if self.line.is_empty() => return (left, right),
+            _ => {}
+        }
+
+        if self.control_timer == 0 {
+            self.update();
+        }
+        self.control_timer = (self.control_timer + 1) % CONTROL_PERIOD;
+
+        let input = [left, right];
+        let wet = match self.settings.effect {
+            Effect::Chorus => self.chorus(input),
+            Effect::Flanger => self.flanger(input),
+            _ => self.phaser(input),
+        };
+        self.glide();
+
+        let mix = self.settings.mix;
+        (
+            left * (1.0 - mix) + wet[0] * mix,
+            right * (1.0 - mix) + wet[1] * mix,
+        )
+    }
+
+    /// Runs the sweep a control period on, the samples until then glide
+    /// to where it gets
+    fn update(&mut self) {
+        let period = CONTROL_PERIOD as f32;
+        let from = self.sweep_at(self.phase);
+
+        self.phase += self.settings.rate.hz(self.tempo) * period / SAMPLE_RATE;
+        self.phase -= floorf(self.phase);
+        let to = self.sweep_at(self.phase);
+
+        self.sweep = from;
+        for side in 0..2 {
+            for voice in 0..VOICES {
+                self.steps.delays[side][voice] =
+                    (to.delays[side][voice] - from.delays[side][voice]) / period;
+            }
+            self.steps.coefficients[side] =
+                (to.coefficients[side] - from.coefficients[side]) / period;
+        }
+    }
+
+    fn glide(&mut self) {
+        for side in 0..2 {
+            for voice in 0..VOICES {
+                self.sweep.delays[side][voice] += self.steps.delays[side][voice];
+            }
+            self.sweep.coefficients[side] += self.steps.coefficients[side];
+        }
+    }
+
+    /// Where the effect is at `phase` of the sweep. The right side sweeps a
+    /// quarter cycle behind the left
+    fn sweep_at(&self, phase: f32) -> Sweep {
+        let sine = |offset: f32| {
+            let phase = phase + offset;
+            wavetable::lookup(
+                &wavetable::SINE,
+                phase - floorf(phase),
+                Interpolation::Linear,
+            )
+        };
+        let depth = self.settings.depth;
+        let mut sweep = Sweep::init();
+
+        for side in 0..2 {
+            let offset = side as f32 * 0.25;
+
+            match self.settings.effect {
+                Effect::Chorus => {
+                    let voices = self.settings.voices.clamp(1, MAX_CHORUS_VOICES);
+                    for voice in 0..voices {
+                        let sine = sine(offset + voice as f32 / voices as f32);
+                        sweep.delays[side][voice as usize] =
+                            CHORUS_CENTRE + sine * CHORUS_SWEEP * depth;
+                    }
+                }
+                Effect::Flanger => {
+                    let sine = (sine(offset) + 1.0) / 2.0;
+                    sweep.delays[side][0] = FLANGER_MIN + sine * FLANGER_SWEEP * depth;
+                }
+                Effect::Phaser => {
+                    let sine = (sine(offset) + 1.0) / 2.0;
+                    let cutoff = PHASER_MIN * exp2f(sine * PHASER_OCTAVES * depth);
+                    let tan = tanf(PI * cutoff / SAMPLE_RATE);
+                    sweep.coefficients[side] = (tan - 1.0) / (tan + 1.0);
+                }
+                Effect::Off => {}
+            }
+        }
+
+        sweep
+    }
+
+    /// Line `delay` samples back, between samples, as far back as it goes
+    fn read(&self, side: usize, delay: f32) -> f32 {
+        let len = self.line.len();
+        let back = floorf(delay).min(len.saturating_sub(2) as f32);
+        let fraction = delay - back;
+        // the sample written last is already a sample back
+        let newer = self.line[(self.write + 1 + len - back as usize) % len][side];
+        let older = self.line[(self.write + len - back as usize) % len][side];
+        newer + (older - newer) * fraction.min(1.0)
+    }
+
+    fn chorus(&mut self, input: [f32; 2]) -> [f32; 2] {
+        let voices = self.settings.voices.clamp(1, MAX_CHORUS_VOICES) as usize;
+        let mut wet = [0.0; 2];
+        for (side, wet) in wet.iter_mut().enumerate() {
+            for voice in 0..voices {
+                *wet += self.read(side, self.sweep.delays[side][voice]);
+            }
+            *wet /= voices as f32;
+        }
+
+        self.write_line(input, wet);
+        wet
+    }
+
+    fn flanger(&mut self, input: [f32; 2]) -> [f32; 2] {
+        let mut wet = [0.0; 2];
+        for (side, wet) in wet.iter_mut().enumerate() {
+            *wet = self.read(side, self.sweep.delays[side][0]);
+        }
+
+        self.write_line(input, wet);
+        wet
+    }
+
+    fn write_line(&mut self, input: [f32; 2], wet: [f32; 2]) {
+        self.write = (self.write + 1) % self.line.len();
+        for side in 0..2 {
+            self.line[self.write][side] = input[side] + wet[side] * self.settings.feedback;
+        }
+    }
+
+    fn phaser(&mut self, input: [f32; 2]) -> [f32; 2] {
+        let stages = (self.settings.stages as usize).min(MAX_STAGES);
+        let mut wet = [0.0; 2];
+        for (side, wet) in wet.iter_mut().enumerate() {
+            let coefficient = self.sweep.coefficients[side];
+            let mut signal = input[side] + self.last[side] * self.settings.feedback;
+            for state in &mut self.allpass[side][..stages] {
+                let out = coefficient * signal + *state;
+                *state = signal - coefficient * out;
+                signal = out;
+            }
+            self.last[side] = signal;
+            *wet = signal;
+        }
+
+        wet
+    }
+
+    pub fn adjust(&mut self, param: &EffectParams, rotation: Rotation) {
+        let settings = &mut self.settings;
+        let up = rotation == Rotation::Right;
+
+        match param {
+            EffectParams::Effect => {
+                settings.effect = settings.effect.next();
+                self.clear();
+                self.control_timer = 0;
+                info!("Set effect: {}", self.settings.effect);
+            }
+            EffectParams::Rate => {
+                settings.rate = match (settings.rate, rotation) {
+                    (LfoRate::Hz(hz), Rotation::Right) => LfoRate::Hz((hz * 1.05).min(10.0)),
+                    (LfoRate::Hz(hz), Rotation::Left) => LfoRate::Hz((hz / 1.05).max(0.01)),
+                    (LfoRate::Sync(division), Rotation::Right) => LfoRate::Sync(division.faster()),
+                    (LfoRate::Sync(division), Rotation::Left) => LfoRate::Sync(division.slower()),
+                };
+                info!("Set effect rate: {}", settings.rate);
+            }
+            EffectParams::Sync => {
+                settings.rate = match rotation {
+                    Rotation::Right => LfoRate::Sync(Division::Bar),
+                    Rotation::Left => LfoRate::Hz(0.5),
+                };
+                info!("Set effect rate: {}", settings.rate);
+            }
+            EffectParams::Depth => {
+                settings.depth = if up {
+                    (settings.depth + 0.05).min(1.0)
+                } else {
+                    (settings.depth - 0.05).max(0.0)
+                };
+                info!("Set effect depth: {}", settings.depth);
+            }
+            EffectParams::Feedback => {
+                settings.feedback = if up {
+                    (settings.feedback + 0.05).min(0.95)
+                } else {
+                    (settings.feedback - 0.05).max(-0.95)
+                };
+                info!("Set effect feedback: {}", settings.feedback);
+            }
+            EffectParams::Mix => {
+                settings.mix = if up {
+                    (settings.mix + 0.05).min(1.0)
+                } else {
+                    (settings.mix - 0.05).max(0.0)
+                };
+                info!("Set effect mix: {}", settings.mix);
+            }
+            EffectParams::Voices => {
+                settings.voices = if up {
+                    (settings.voices + 1).min(MAX_CHORUS_VOICES)
+                } else {
+                    (settings.voices - 1).max(1)
+                };
+                info!("Set chorus voices: {}", settings.voices);
+            }
+            EffectParams::Stages => {
+                settings.stages = if up {
+                    (settings.stages + 2).min(MAX_STAGES as u8)
+                } else {
+                    (settings.stages - 2).max(2)
+                };
+                info!("Set phaser stages: {}", settings.stages);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum EffectParams {
+    Effect,
+    Rate,
+    Sync,
+    Depth,
+    Feedback,
+    Mix,
+    Voices,
+    Stages,
+}
+
+impl EffectParams {
+    pub const fn init_param() -> Self {
+        Self::Effect
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use EffectParams::*;
+
+        match param {
+            Effect => Some(Rate),
+            Rate => Some(Sync),
+            Sync => Some(Depth),
+            Depth => Some(Feedback),
+            Feedback => Some(Mix),
+            Mix => Some(Voices),
+            Voices => Some(Stages),
+            Stages => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::EFFECT_LINE_FRAMES;
+
+    fn slot(effect: Effect) -> EffectSlot {
+        let mut slot = EffectSlot::init();
+        slot.settings.effect = effect;
+        slot.settings.mix = 1.0;
+        slot.set_line(std::vec![[0.0; 2]; EFFECT_LINE_FRAMES].leak());
+        slot
+    }
+
+    /// Left output for an impulse on both sides
+    fn impulse(slot: &mut EffectSlot, samples: usize) -> std::vec::Vec<f32> {
+        (0..samples)
+            .map(|i| slot.process(if i == 0 { (1.0, 1.0) } else { (0.0, 0.0) }).0)
+            .collect()
+    }
+
+    #[test]
+    fn chorus_without_depth_is_a_plain_delay() {
+        let mut slot = slot(Effect::Chorus);
+        slot.settings.depth = 0.0;
+        let out = impulse(&mut slot, EFFECT_LINE_FRAMES);
+
+        let centre = CHORUS_CENTRE as usize;
+        assert!(
+            out.iter()
+                .enumerate()
+                .all(|(i, sample)| (i == centre) == (*sample != 0.0))
+        );
+        assert!((out[centre] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sweep_glides_between_control_updates() {
+        let mut slot = slot(Effect::Chorus);
+        slot.settings.depth = 1.0;
+        slot.settings.rate = LfoRate::Hz(5.0);
+
+        let delays: std::vec::Vec<f32> = (0..(SAMPLE_RATE / 5.0) as usize)
+            .map(|_| {
+                slot.process((0.0, 0.0));
+                slot.sweep.delays[0][0]
+            })
+            .collect();
+
+        // no faster than the sine it follows, and all the way across
+        let fastest = core::f32::consts::TAU * 5.0 * CHORUS_SWEEP / SAMPLE_RATE;
+        assert!(
+            delays
+                .windows(2)
+                .all(|w| (w[1] - w[0]).abs() <= fastest * 1.01)
+        );
+        let low = delays.iter().copied().fold(f32::MAX, f32::min);
+        let high = delays.iter().copied().fold(f32::MIN, f32::max);
+        assert!(((high - low) / (2.0 * CHORUS_SWEEP) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn flanger_feedback_dies_away() {
+        let mut slot = slot(Effect::Flanger);
+        slot.settings.feedback = 0.95;
+        slot.settings.depth = 1.0;
+        slot.settings.rate = LfoRate::Sync(Division::Quarter);
+        let out = impulse(&mut slot, SAMPLE_RATE as usize * 2);
+
+        assert!(out.iter().all(|sample| sample.abs() <= 1.0));
+        assert!(
+            out[out.len() - 1_000..]
+                .iter()
+                .all(|sample| sample.abs() < 1e-3)
+        );
+    }
+
+    #[test]
+    fn phaser_passes_every_frequency() {
+        let mut slot = slot(Effect::Phaser);
+        slot.settings.stages = MAX_STAGES as u8;
+        slot.settings.rate = LfoRate::Hz(0.0);
+        let out = impulse(&mut slot, SAMPLE_RATE as usize);
+
+        // allpass stages only shift the phase, the impulse keeps its energy
+        let energy: f32 = out.iter().map(|sample| sample * sample).sum();
+        assert!((energy - 1.0).abs() < 1e-3, "{energy}");
+    }
+
+    #[test]
+    fn off_passes_through() {
+        let mut slot = slot(Effect::Off);
+        assert_eq!(slot.process((0.3, -0.2)), (0.3, -0.2));
+    }
+
+    #[test]
+    fn delays_pass_through_until_they_have_a_line() {
+        for effect in [Effect::Chorus, Effect::Flanger] {
+            let mut slot = EffectSlot::init();
+            slot.settings.effect = effect;
+            assert_eq!(slot.process((0.3, -0.2)), (0.3, -0.2));
+        }
+    }
+}
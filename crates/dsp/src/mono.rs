@@ -0,0 +1,256 @@
+use defmt::{Format, info};
+use heapless::Vec;
+use midi_parser::parser::Note;
+
+use crate::{adsr::TimeMs, consts::MAX_HELD_NOTES, encoder::Rotation};
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum VoiceMode {
+    Poly,
+    /// One note at a time, every note restarts the envelopes
+    Mono,
+    /// One note at a time, overlapping notes carry on the envelopes
+    Legato,
+}
+
+impl VoiceMode {
+    pub fn next(&self) -> Self {
+        match self {
+            VoiceMode::Poly => VoiceMode::Mono,
+            VoiceMode::Mono => VoiceMode::Legato,
+            VoiceMode::Legato => VoiceMode::Poly,
+        }
+    }
+}
+
+/// Which of the held notes plays in mono
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+impl NotePriority {
+    pub fn next(&self) -> Self {
+        match self {
+            NotePriority::Last => NotePriority::Low,
+            NotePriority::Low => NotePriority::High,
+            NotePriority::High => NotePriority::Last,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum GlideMode {
+    /// Every glide takes the glide time
+    ConstantTime,
+    /// The glide time is per octave
+    ConstantRate,
+}
+
+pub struct MonoSettings {
+    pub mode: VoiceMode,
+    pub priority: NotePriority,
+    /// Portamento, off at 0
+    pub glide: TimeMs,
+    pub glide_mode: GlideMode,
+}
+
+impl MonoSettings {
+    pub const fn init() -> Self {
+        Self {
+            mode: VoiceMode::Poly,
+            priority: NotePriority::Last,
+            glide: TimeMs(0),
+            glide_mode: GlideMode::ConstantTime,
+        }
+    }
+
+    /// Samples a glide over `semitones` takes
+    pub fn glide_samples(&self, semitones: f32, sample_rate: f32) -> f32 {
+        let samples = self.glide.0 as f32 * sample_rate / 1000.0;
+
+        match self.glide_mode {
+            GlideMode::ConstantTime => samples,
+            GlideMode::ConstantRate => samples * semitones.abs() / 12.0,
+        }
+    }
+
+    pub fn adjust(&mut self, param: &MonoParams, rotation: Rotation) {
+        match param {
+            MonoParams::Mode => {
+                self.mode = self.mode.next();
+                info!("Set voice mode: {}", self.mode);
+            }
+            MonoParams::Priority => {
+                self.priority = self.priority.next();
+                info!("Set note priority: {}", self.priority);
+            }
+            MonoParams::Glide => {
+                self.glide = match rotation {
+                    Rotation::Right => TimeMs(self.glide.0.saturating_add(5).min(5_000)),
+                    Rotation::Left => TimeMs(self.glide.0.saturating_sub(5)),
+                };
+                info!("Set glide: {} ms", self.glide.0);
+            }
+            MonoParams::GlideMode => {
+                self.glide_mode = match self.glide_mode {
+                    GlideMode::ConstantTime => GlideMode::ConstantRate,
+                    GlideMode::ConstantRate => GlideMode::ConstantTime,
+                };
+                info!("Set glide mode: {}", self.glide_mode);
+            }
+        }
+    }
+}
+
+/// Keys held down in mono, oldest first, with their velocities
+#[derive(Clone)]
+pub struct NoteStack {
+    notes: Vec<(Note, f32), MAX_HELD_NOTES>,
+}
+
+impl Default for NoteStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NoteStack {
+    pub const fn new() -> Self {
+        Self { notes: Vec::new() }
+    }
+
+    /// Pressing a held key again moves it to the top; when full, the oldest
+    /// key is forgotten
+    pub fn push(&mut self, note: &Note, velocity: f32) {
+        self.remove(note);
+        if self.notes.is_full() {
+            self.notes.remove(0);
+        }
+        let _ = self.notes.push((*note, velocity));
+    }
+
+    pub fn remove(&mut self, note: &Note) {
+        self.notes.retain(|(held, _)| held != note);
+    }
+
+    pub fn clear(&mut self) {
+        self.notes.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.is_empty()
+    }
+
+    pub fn notes(&self) -> &[(Note, f32)] {
+        &self.notes
+    }
+
+    /// The note that should be playing, with its velocity
+    pub fn current(&self, priority: NotePriority) -> Option<(Note, f32)> {
+        let notes = self.notes.iter();
+
+        match priority {
+            NotePriority::Last => notes.last(),
+            NotePriority::Low => notes.min_by_key(|(note, _)| note.num),
+            NotePriority::High => notes.max_by_key(|(note, _)| note.num),
+        }
+        .copied()
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum MonoParams {
+    Mode,
+    Priority,
+    Glide,
+    GlideMode,
+}
+
+impl MonoParams {
+    pub const fn init_param() -> Self {
+        Self::Mode
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use MonoParams::*;
+
+        match param {
+            Mode => Some(Priority),
+            Priority => Some(Glide),
+            Glide => Some(GlideMode),
+            GlideMode => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(notes: &[u8]) -> NoteStack {
+        let mut stack = NoteStack::new();
+        for &num in notes {
+            stack.push(&Note::new(num), num as f32 / 127.0);
+        }
+
+        stack
+    }
+
+    fn current(stack: &NoteStack, priority: NotePriority) -> Option<u8> {
+        stack.current(priority).map(|(note, _)| note.num)
+    }
+
+    #[test]
+    fn priorities_pick_their_note() {
+        let stack = stack(&[60, 55, 67, 62]);
+
+        assert_eq!(current(&stack, NotePriority::Last), Some(62));
+        assert_eq!(current(&stack, NotePriority::Low), Some(55));
+        assert_eq!(current(&stack, NotePriority::High), Some(67));
+    }
+
+    #[test]
+    fn release_returns_to_the_previous_note() {
+        let mut stack = stack(&[60, 64, 67]);
+
+        stack.remove(&Note::new(67));
+        assert_eq!(current(&stack, NotePriority::Last), Some(64));
+        stack.remove(&Note::new(60));
+        assert_eq!(current(&stack, NotePriority::Last), Some(64));
+        stack.remove(&Note::new(64));
+        assert_eq!(current(&stack, NotePriority::Last), None);
+    }
+
+    #[test]
+    fn repressed_key_goes_on_top() {
+        let mut stack = stack(&[60, 64]);
+        stack.push(&Note::new(60), 1.0);
+
+        assert_eq!(current(&stack, NotePriority::Last), Some(60));
+        stack.remove(&Note::new(60));
+        assert!(!stack.is_empty());
+    }
+
+    #[test]
+    fn full_stack_forgets_the_oldest() {
+        let notes: std::vec::Vec<u8> = (40..40 + MAX_HELD_NOTES as u8 + 1).collect();
+        let stack = stack(&notes);
+
+        assert_eq!(current(&stack, NotePriority::Low), Some(41));
+    }
+
+    #[test]
+    fn glide_time_by_mode() {
+        let mut settings = MonoSettings::init();
+        settings.glide = TimeMs(100);
+
+        assert_eq!(settings.glide_samples(24.0, 1_000.0), 100.0);
+        settings.glide_mode = GlideMode::ConstantRate;
+        assert_eq!(settings.glide_samples(-24.0, 1_000.0), 200.0);
+        assert_eq!(settings.glide_samples(6.0, 1_000.0), 50.0);
+    }
+}
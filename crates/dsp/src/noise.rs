@@ -0,0 +1,161 @@
+use defmt::Format;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum NoiseType {
+    White,
+    /// -3 dB/oct
+    Pink,
+    /// -6 dB/oct
+    Brown,
+}
+
+impl NoiseType {
+    pub fn next(&self) -> Self {
+        match self {
+            NoiseType::White => NoiseType::Pink,
+            NoiseType::Pink => NoiseType::Brown,
+            NoiseType::Brown => NoiseType::White,
+        }
+    }
+}
+
+/// xorshift32, cheap and good enough for audio. The same seed always gives
+/// the same sequence, which keeps renders reproducible
+pub struct Rng(u32);
+
+impl Rng {
+    pub const fn new(seed: u32) -> Self {
+        // spread the bits of small seeds, xorshift takes a while to get
+        // going from a state with few bits set
+        let seed = seed.wrapping_mul(0x9E37_79B9);
+        // zero is the only state xorshift never leaves
+        Self(if seed == 0 { 0x9E37_79B9 } else { seed })
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// Uniform in `-1.0..1.0`
+    pub fn next_bipolar(&mut self) -> f32 {
+        // the top 24 bits fit the f32 mantissa exactly
+        (self.next_u32() >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+}
+
+pub struct Noise {
+    rng: Rng,
+    /// Paul Kellet's pink noise filter state
+    pink: [f32; 7],
+    brown: f32,
+}
+
+impl Noise {
+    pub const fn new(seed: u32) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            pink: [0.0; 7],
+            brown: 0.0,
+        }
+    }
+
+    pub fn next_sample(&mut self, noise_type: NoiseType) -> f32 {
+        let white = self.rng.next_bipolar();
+
+        match noise_type {
+            NoiseType::White => white,
+            NoiseType::Pink => {
+                let b = &mut self.pink;
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.153852;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                b[6] = white * 0.115926;
+
+                pink * 0.11
+            }
+            NoiseType::Brown => {
+                // leaky integrator, so it doesn't wander off to DC
+                self.brown = (self.brown + 0.02 * white) / 1.02;
+                self.brown * 3.5
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::SAMPLE_RATE, golden::magnitude_spectrum};
+
+    const FFT_SIZE: usize = 4096;
+
+    fn render(noise_type: NoiseType, seed: u32, len: usize) -> Vec<f32> {
+        let mut noise = Noise::new(seed);
+        (0..len).map(|_| noise.next_sample(noise_type)).collect()
+    }
+
+    /// Energy of the 4-8 kHz octave relative to the 500-1000 Hz one
+    fn octave_ratio(samples: &[f32]) -> f32 {
+        let spectrum = magnitude_spectrum(samples, FFT_SIZE);
+        let bin_width = SAMPLE_RATE / FFT_SIZE as f32;
+
+        let energy = |from: f32, to: f32| -> f32 {
+            spectrum[(from / bin_width) as usize..(to / bin_width) as usize]
+                .iter()
+                .map(|m| m * m)
+                .sum()
+        };
+
+        energy(4_000.0, 8_000.0) / energy(500.0, 1_000.0)
+    }
+
+    #[test]
+    fn same_seed_same_noise() {
+        for noise_type in [NoiseType::White, NoiseType::Pink, NoiseType::Brown] {
+            assert_eq!(render(noise_type, 42, 1_000), render(noise_type, 42, 1_000));
+            assert_ne!(render(noise_type, 42, 1_000), render(noise_type, 43, 1_000));
+        }
+    }
+
+    #[test]
+    fn white_noise_is_bipolar_and_centered() {
+        let samples = render(NoiseType::White, 1, 100_000);
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+
+        assert!(samples.iter().all(|s| (-1.0..1.0).contains(s)));
+        assert!(mean.abs() < 0.01, "mean {mean}");
+    }
+
+    #[test]
+    fn spectral_slopes() {
+        let len = FFT_SIZE * 64;
+
+        // an octave holds twice the bins of the one below it, so white noise
+        // has 8 times the energy three octaves up, pink the same, brown 1/8
+        let white = octave_ratio(&render(NoiseType::White, 7, len));
+        let pink = octave_ratio(&render(NoiseType::Pink, 7, len));
+        let brown = octave_ratio(&render(NoiseType::Brown, 7, len));
+
+        assert!((6.0..10.0).contains(&white), "white {white}");
+        assert!((0.7..1.4).contains(&pink), "pink {pink}");
+        assert!((0.08..0.2).contains(&brown), "brown {brown}");
+    }
+
+    #[test]
+    fn colored_noise_stays_in_range() {
+        for noise_type in [NoiseType::Pink, NoiseType::Brown] {
+            let peak = crate::golden::peak(&render(noise_type, 3, 500_000));
+            assert!(peak < 1.5, "peak {peak}");
+        }
+    }
+}
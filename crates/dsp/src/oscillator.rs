@@ -1,23 +1,44 @@
-use core::f32::consts::TAU;
-
 use defmt::{Format, info};
-use libm::sinf;
+use libm::exp2f;
 use midi_parser::parser::Note;
 
-use crate::{consts::SAMPLE_RATE, encoder::Rotation};
+use crate::{
+    consts::SAMPLE_RATE,
+    encoder::Rotation,
+    noise::{Noise, NoiseType},
+    wavetable::{self, Interpolation, TableSource, WavetableSet},
+};
 
-#[derive(Format)]
+#[derive(Clone, Copy, PartialEq, Format)]
 pub enum WaveType {
     Sine,
     SawTooth,
     Square,
     PWM,
+    /// Band-limited (PolyBLEP) versions of the waves above
+    BlSawTooth,
+    BlSquare,
+    BlPWM,
+    /// Band-limited (PolyBLAMP) triangle
+    BlTriangle,
+    /// Mipmapped wavetable, morphing across the frames of a table set
+    Wavetable,
+    WhiteNoise,
+    PinkNoise,
+    BrownNoise,
 }
 
 #[derive(Debug, Format)]
 pub enum OscParams {
     NextWave,
     Duty,
+    Morph,
+    Table,
+    Interpolation,
+    Octave,
+    Semitone,
+    Cents,
+    Level,
 }
 
 impl OscParams {
@@ -30,7 +51,130 @@ impl OscParams {
 
         match param {
             NextWave => Some(Duty),
-            Duty => None,
+            Duty => Some(Morph),
+            Morph => Some(Table),
+            Table => Some(Interpolation),
+            Interpolation => Some(Octave),
+            Octave => Some(Semitone),
+            Semitone => Some(Cents),
+            Cents => Some(Level),
+            Level => None,
+        }
+    }
+}
+
+/// Patch settings of one of the oscillators of a voice
+#[derive(Clone, Copy)]
+pub struct OscSettings {
+    pub wave: WaveType,
+    pub duty: f32,
+    pub wavetable: TableSource,
+    pub morph: f32,
+    pub interpolation: Interpolation,
+    pub octave: i8,
+    pub semitone: i8,
+    pub cents: i8,
+    pub level: f32,
+}
+
+impl OscSettings {
+    pub const fn init() -> Self {
+        Self {
+            wave: WaveType::SawTooth,
+            duty: 0.5,
+            wavetable: TableSource::Basic,
+            morph: 0.0,
+            interpolation: Interpolation::Linear,
+            octave: 0,
+            semitone: 0,
+            cents: 0,
+            level: 1.0,
+        }
+    }
+
+    /// Frequency ratio of the octave, semitone and cent offsets
+    pub fn pitch_ratio(&self) -> f32 {
+        let semitones =
+            self.octave as f32 * 12.0 + self.semitone as f32 + self.cents as f32 / 100.0;
+        exp2f(semitones / 12.0)
+    }
+
+    pub fn adjust(&mut self, param: &OscParams, rotation: Rotation) {
+        use OscParams::*;
+
+        match param {
+            NextWave => {
+                use WaveType::*;
+
+                self.wave = match self.wave {
+                    Sine => SawTooth,
+                    SawTooth => Square,
+                    Square => PWM,
+                    PWM => BlSawTooth,
+                    BlSawTooth => BlSquare,
+                    BlSquare => BlPWM,
+                    BlPWM => BlTriangle,
+                    BlTriangle => Wavetable,
+                    Wavetable => WhiteNoise,
+                    WhiteNoise => PinkNoise,
+                    PinkNoise => BrownNoise,
+                    BrownNoise => Sine,
+                };
+                info!("Set osc type: {}", self.wave);
+            }
+            Duty => {
+                let new = if rotation == Rotation::Right {
+                    self.duty + 0.05
+                } else {
+                    self.duty - 0.05
+                };
+
+                self.duty = new.clamp(0.05, 0.95);
+                info!("Set duty: {}", self.duty);
+            }
+            Octave => {
+                let delta = if rotation == Rotation::Right { 1 } else { -1 };
+                self.octave = (self.octave + delta).clamp(-3, 3);
+                info!("Set octave: {}", self.octave);
+            }
+            Semitone => {
+                let delta = if rotation == Rotation::Right { 1 } else { -1 };
+                self.semitone = (self.semitone + delta).clamp(-12, 12);
+                info!("Set semitone: {}", self.semitone);
+            }
+            Cents => {
+                let delta = if rotation == Rotation::Right { 1 } else { -1 };
+                self.cents = (self.cents + delta).clamp(-50, 50);
+                info!("Set cents: {}", self.cents);
+            }
+            Level => {
+                let new = if rotation == Rotation::Right {
+                    self.level + 0.05
+                } else {
+                    self.level - 0.05
+                };
+
+                self.level = new.clamp(0.0, 1.0);
+                info!("Set level: {}", self.level);
+            }
+            Morph => {
+                let new = if rotation == Rotation::Right {
+                    self.morph + 0.02
+                } else {
+                    self.morph - 0.02
+                };
+
+                self.morph = new.clamp(0.0, 1.0);
+                info!("Set morph: {}", self.morph);
+            }
+            Table => {
+                self.wavetable = self.wavetable.next();
+                info!("Set wavetable: {}", self.wavetable);
+            }
+            Interpolation => {
+                self.interpolation = self.interpolation.next();
+                info!("Set wavetable interpolation: {}", self.interpolation);
+            }
         }
     }
 }
@@ -42,6 +186,17 @@ pub struct Oscillator {
     pub sample_rate: f32,
     pub phase_inc: f32,
     pub duty: f32,
+    pub wavetable: TableSource,
+    /// Position across the frames of the wavetable, `0.0..=1.0`
+    pub morph: f32,
+    pub interpolation: Interpolation,
+    /// Frequency ratio to the note
+    pub detune: f32,
+    /// Pitch modulation, as a frequency ratio on top of `detune`
+    pitch_mod: f32,
+    mip_level: usize,
+    wrapped: bool,
+    noise: Noise,
     active: bool,
 }
 
@@ -54,6 +209,14 @@ impl Oscillator {
             sample_rate: SAMPLE_RATE,
             phase_inc: 0.0,
             duty: 0.5,
+            wavetable: TableSource::Basic,
+            morph: 0.0,
+            interpolation: Interpolation::Linear,
+            detune: 1.0,
+            pitch_mod: 1.0,
+            mip_level: 0,
+            wrapped: false,
+            noise: Noise::new(note.num as u32 + 1),
             active: false,
         };
 
@@ -75,41 +238,58 @@ impl Oscillator {
         return self.active;
     }
 
-    pub fn adjust(&mut self, param: &OscParams, rotation: Rotation) {
-        use OscParams::*;
+    /// `detune` is a frequency ratio on top of the pitch offsets of `settings`
+    pub fn apply(&mut self, settings: &OscSettings, detune: f32) {
+        self.osc_type = settings.wave;
+        self.duty = settings.duty;
+        self.wavetable = settings.wavetable;
+        self.morph = settings.morph;
+        self.interpolation = settings.interpolation;
+        self.detune = settings.pitch_ratio() * detune;
 
-        match param {
-            NextWave => {
-                use WaveType::*;
+        self.update_phase_inc();
+    }
 
-                self.osc_type = match self.osc_type {
-                    Sine => SawTooth,
-                    SawTooth => Square,
-                    Square => PWM,
-                    PWM => Sine,
-                };
-                info!("Set osc type: {}", self.osc_type);
-            }
-            Duty => {
-                let new = if rotation == Rotation::Right {
-                    self.duty + 0.05
-                } else {
-                    self.duty - 0.05
-                };
+    /// Changes the pitch without restarting the cycle
+    pub fn set_note(&mut self, note: &Note) {
+        self.note = *note;
+        self.update_phase_inc();
+    }
 
-                self.duty = new.clamp(0.05, 0.95);
-                info!("Set duty: {}", self.duty);
-            }
+    pub fn set_pitch_mod(&mut self, ratio: f32) {
+        if ratio != self.pitch_mod {
+            self.pitch_mod = ratio;
+            self.update_phase_inc();
         }
     }
 
-    pub fn next_sample(&mut self) -> f32 {
+    /// Noise waves are reproducible for the same seed
+    pub fn seed(&mut self, seed: u32) {
+        self.noise = Noise::new(seed);
+    }
+
+    /// Whether the last sample finished a cycle
+    pub fn wrapped(&self) -> bool {
+        self.wrapped
+    }
+
+    /// Hard sync: restarts the cycle together with `master`, keeping the
+    /// fraction of the sample `master` has already run past its wrap
+    pub fn sync_to(&mut self, master: &Oscillator) {
+        let samples_since_wrap = master.phase / master.phase_inc;
+        self.phase = wrap(samples_since_wrap * self.phase_inc);
+    }
+
+    /// `user` is the table set of the patch, for a wavetable on it
+    pub fn next_sample(&mut self, user: &WavetableSet) -> f32 {
         if !self.active {
             return 0.0;
         }
 
         let sample = match self.osc_type {
-            WaveType::Sine => sinf(self.phase * TAU),
+            WaveType::Sine => {
+                wavetable::lookup(&wavetable::SINE, self.phase, Interpolation::Linear)
+            }
             WaveType::SawTooth => self.phase * 2.0 - 1.0,
             WaveType::Square => {
                 if self.phase < 0.5 {
@@ -125,18 +305,219 @@ impl Oscillator {
                     -1.0
                 }
             }
+            WaveType::BlSawTooth => self.phase * 2.0 - 1.0 - poly_blep(self.phase, self.phase_inc),
+            WaveType::BlSquare => self.bl_pulse(0.5),
+            WaveType::BlPWM => self.bl_pulse(self.duty),
+            WaveType::BlTriangle => {
+                let naive = 1.0 - 4.0 * (self.phase - 0.5).abs();
+                let falling = wrap(self.phase + 0.5);
+
+                // the slope changes by 8 per cycle at both corners
+                naive
+                    + 8.0
+                        * self.phase_inc
+                        * (poly_blamp(self.phase, self.phase_inc)
+                            - poly_blamp(falling, self.phase_inc))
+            }
+            WaveType::Wavetable => self.wavetable.set(user).sample(
+                self.phase,
+                self.morph,
+                self.mip_level,
+                self.interpolation,
+            ),
+            WaveType::WhiteNoise => self.noise.next_sample(NoiseType::White),
+            WaveType::PinkNoise => self.noise.next_sample(NoiseType::Pink),
+            WaveType::BrownNoise => self.noise.next_sample(NoiseType::Brown),
         };
 
         self.phase += self.phase_inc;
+        self.wrapped = self.phase >= 1.0;
 
-        if self.phase >= 1.0 {
+        if self.wrapped {
             self.phase -= 1.0;
         }
 
         sample
     }
 
+    fn bl_pulse(&self, duty: f32) -> f32 {
+        let naive = if self.phase < duty { 1.0 } else { -1.0 };
+        let falling = wrap(self.phase + 1.0 - duty);
+
+        naive + poly_blep(self.phase, self.phase_inc) - poly_blep(falling, self.phase_inc)
+    }
+
     const fn update_phase_inc(&mut self) {
-        self.phase_inc = self.note.freq / self.sample_rate;
+        self.phase_inc = self.note.freq * self.detune * self.pitch_mod / self.sample_rate;
+        self.mip_level = WavetableSet::mip_level(self.phase_inc);
+    }
+}
+
+fn wrap(phase: f32) -> f32 {
+    if phase >= 1.0 { phase - 1.0 } else { phase }
+}
+
+/// Two-sample polynomial residual of a band-limited step of height 2 at phase 0
+fn poly_blep(phase: f32, phase_inc: f32) -> f32 {
+    if phase < phase_inc {
+        let x = phase / phase_inc;
+        x + x - x * x - 1.0
+    } else if phase > 1.0 - phase_inc {
+        let x = (phase - 1.0) / phase_inc;
+        x * x + x + x + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// Two-sample polynomial residual of a band-limited unit slope change (in
+/// samples) at phase 0, i.e. the integral of the PolyBLEP residual
+fn poly_blamp(phase: f32, phase_inc: f32) -> f32 {
+    if phase < phase_inc {
+        let x = 1.0 - phase / phase_inc;
+        x * x * x / 6.0
+    } else if phase > 1.0 - phase_inc {
+        let x = (phase - 1.0) / phase_inc + 1.0;
+        x * x * x / 6.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::magnitude_spectrum;
+
+    const FFT_SIZE: usize = 8192;
+
+    fn render(osc_type: WaveType, note_num: u8) -> Vec<f32> {
+        let mut osc = Oscillator::new(&Note::new(note_num));
+        osc.osc_type = osc_type;
+        osc.duty = 0.3;
+        osc.start();
+
+        let user = std::boxed::Box::new(WavetableSet::empty());
+        (0..FFT_SIZE * 8).map(|_| osc.next_sample(&user)).collect()
+    }
+
+    /// Ratio of the energy outside the true harmonics of `freq` (which is
+    /// where the aliases get folded to) to the energy of the harmonics
+    fn alias_ratio(samples: &[f32], freq: f32) -> f32 {
+        let spectrum = magnitude_spectrum(samples, FFT_SIZE);
+        let bin_width = SAMPLE_RATE / FFT_SIZE as f32;
+
+        let (mut harmonic, mut alias) = (0.0, 0.0);
+
+        for (bin, mag) in spectrum.iter().enumerate().skip(1) {
+            let f = bin as f32 * bin_width;
+            let nearest = (f / freq).round() * freq;
+            let power = mag * mag;
+
+            // DC counts as a harmonic
+            if (f - nearest).abs() <= 3.0 * bin_width {
+                harmonic += power;
+            } else {
+                alias += power;
+            }
+        }
+
+        alias / harmonic
+    }
+
+    fn assert_less_aliasing(naive: WaveType, band_limited: WaveType) {
+        for note_num in [84, 96, 108] {
+            let freq = Note::new(note_num).freq;
+            let naive_ratio = alias_ratio(&render(naive, note_num), freq);
+            let bl_ratio = alias_ratio(&render(band_limited, note_num), freq);
+
+            // at least 10 dB less alias energy
+            assert!(
+                bl_ratio * 10.0 < naive_ratio,
+                "note {note_num}: naive {naive_ratio}, band-limited {bl_ratio}"
+            );
+        }
+    }
+
+    #[test]
+    fn bl_saw_aliases_less() {
+        assert_less_aliasing(WaveType::SawTooth, WaveType::BlSawTooth);
+    }
+
+    #[test]
+    fn bl_square_aliases_less() {
+        assert_less_aliasing(WaveType::Square, WaveType::BlSquare);
+    }
+
+    #[test]
+    fn bl_pwm_aliases_less() {
+        assert_less_aliasing(WaveType::PWM, WaveType::BlPWM);
+    }
+
+    #[test]
+    fn bl_triangle_aliases_less() {
+        // a triangle barely aliases in the first place, so only the highest
+        // notes have something to measure
+        for note_num in [96, 108, 120] {
+            let note = Note::new(note_num);
+            let phase_inc = note.freq / SAMPLE_RATE;
+            let mut phase: f32 = 0.0;
+            let naive: Vec<f32> = (0..FFT_SIZE * 8)
+                .map(|_| {
+                    let sample = 1.0 - 4.0 * (phase - 0.5).abs();
+                    phase = wrap(phase + phase_inc);
+                    sample
+                })
+                .collect();
+
+            let naive_ratio = alias_ratio(&naive, note.freq);
+            let bl_ratio = alias_ratio(&render(WaveType::BlTriangle, note_num), note.freq);
+
+            assert!(
+                bl_ratio * 2.0 < naive_ratio,
+                "note {note_num}: naive {naive_ratio}, band-limited {bl_ratio}"
+            );
+        }
+    }
+
+    #[test]
+    fn table_params_pick_the_set_and_interpolation() {
+        let mut settings = OscSettings::init();
+        settings.wave = WaveType::Wavetable;
+        settings.morph = 1.0;
+        let user = std::boxed::Box::new(WavetableSet::empty());
+
+        let render = |settings: &OscSettings| {
+            let mut osc = Oscillator::new(&Note::new(45));
+            osc.apply(settings, 1.0);
+            osc.start();
+            (0..1_000)
+                .map(|_| osc.next_sample(&user))
+                .collect::<Vec<f32>>()
+        };
+
+        let square = render(&settings);
+        settings.adjust(&OscParams::Table, Rotation::Right);
+        assert!(settings.wavetable == TableSource::Pulses);
+        let pulse = render(&settings);
+        assert_ne!(pulse, square);
+
+        settings.adjust(&OscParams::Interpolation, Rotation::Right);
+        assert!(settings.interpolation == Interpolation::Cubic);
+        assert_ne!(render(&settings), pulse);
+    }
+
+    #[test]
+    fn band_limited_waves_stay_in_range() {
+        for wave in [
+            WaveType::BlSawTooth,
+            WaveType::BlSquare,
+            WaveType::BlPWM,
+            WaveType::BlTriangle,
+        ] {
+            let peak = crate::golden::peak(&render(wave, 108));
+            assert!(peak <= 1.1, "peak {peak}");
+        }
     }
 }
@@ -0,0 +1,282 @@
+use defmt::{Format, info};
+
+use crate::{
+    breakpoint::{BreakpointParams, Breakpoints},
+    consts::{LFOS, MAX_OSCILLATORS},
+    drive::{DriveParams, DriveSettings},
+    encoder::Rotation,
+    filter::{Filter, FilterModParams, FilterModulation, FilterParam},
+    fm::{FmParams, FmSettings},
+    lfo::{LfoParams, LfoSettings},
+    mod_matrix::{ModDestination, ModMatrix, ModParams, ModSlot, ModSource},
+    mono::{MonoParams, MonoSettings},
+    noise::NoiseType,
+    oscillator::{OscParams, OscSettings},
+    unison::{UnisonParams, UnisonSettings},
+    velocity::{VelocityParams, VelocitySettings},
+    wavetable::{WavetableError, WavetableSet},
+};
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Engine {
+    /// Oscillators, mixed
+    Subtractive,
+    /// Operators, routed by an algorithm
+    Fm,
+}
+
+/// Which voice gives way to a new note once `MAX_VOICES` are sounding
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum StealPolicy {
+    /// The one that started first
+    Oldest,
+    /// The one with the lowest amp envelope
+    Quietest,
+    /// The oldest released one, or the oldest of all if none are
+    ReleasedFirst,
+}
+
+/// Sound settings shared by all the voices
+pub struct Patch {
+    pub engine: Engine,
+    pub oscillators: [OscSettings; MAX_OSCILLATORS],
+    /// Osc 2 restarts its cycle with every cycle of osc 1
+    pub hard_sync: bool,
+    /// Level of the osc 1 × osc 2 product in the mix
+    pub ring_mod: f32,
+    /// Noise mixed into every voice, on top of its oscillators or operators
+    pub noise: NoiseType,
+    pub noise_level: f32,
+    pub fm: FmSettings,
+    pub unison: UnisonSettings,
+    /// Settings the filter of every voice follows, its own state is unused
+    pub filter: Filter,
+    pub filter_mod: FilterModulation,
+    pub lfos: [LfoSettings; LFOS],
+    pub matrix: ModMatrix,
+    /// Replaces the ADSR amp envelope when set
+    pub amp_stages: Option<Breakpoints>,
+    /// Per-voice modulation source
+    pub mod_envelope: Breakpoints,
+    pub steal: StealPolicy,
+    pub mono: MonoSettings,
+    /// Semitones at full pitch bend up
+    pub bend_up: f32,
+    /// Semitones at full pitch bend down
+    pub bend_down: f32,
+    pub velocity: VelocitySettings,
+    pub drive: DriveSettings,
+    /// Frames the oscillators on `TableSource::User` morph across
+    pub user_wavetable: WavetableSet,
+}
+
+impl Patch {
+    pub const fn init() -> Self {
+        let mut oscillators = [OscSettings::init(); MAX_OSCILLATORS];
+        let mut i = 1;
+        while i < MAX_OSCILLATORS {
+            oscillators[i].level = 0.0;
+            i += 1;
+        }
+
+        // the mod wheel brings in vibrato, half a semitone at the top
+        let mut matrix = ModMatrix::init();
+        matrix.slots[0] = ModSlot {
+            source: ModSource::Lfo1,
+            destination: ModDestination::Pitch,
+            depth: 0.5 / 12.0,
+            via: Some(ModSource::ModWheel),
+        };
+
+        Self {
+            engine: Engine::Subtractive,
+            oscillators,
+            hard_sync: false,
+            ring_mod: 0.0,
+            noise: NoiseType::White,
+            noise_level: 0.0,
+            fm: FmSettings::init(),
+            unison: UnisonSettings::init(),
+            filter: Filter::new(),
+            filter_mod: FilterModulation::init(),
+            lfos: [const { LfoSettings::init() }; LFOS],
+            matrix,
+            amp_stages: None,
+            mod_envelope: Breakpoints::init(),
+            steal: StealPolicy::ReleasedFirst,
+            mono: MonoSettings::init(),
+            bend_up: 2.0,
+            bend_down: 2.0,
+            velocity: VelocitySettings::init(),
+            drive: DriveSettings::init(),
+            user_wavetable: WavetableSet::empty(),
+        }
+    }
+
+    pub fn adjust_osc(&mut self, osc: usize, param: &OscParams, rotation: Rotation) {
+        self.oscillators[osc].adjust(param, rotation);
+    }
+
+    /// See [`WavetableSet::load_patch_data`] for the format
+    pub fn load_wavetable(&mut self, data: &[u8]) -> Result<(), WavetableError> {
+        self.user_wavetable.load_patch_data(data)
+    }
+
+    pub fn adjust_fm(&mut self, param: &FmParams, rotation: Rotation) {
+        self.fm.adjust(param, rotation);
+    }
+
+    pub fn adjust_filter(&mut self, param: &FilterParam, rotation: Rotation) {
+        self.filter.adjust(param, rotation);
+    }
+
+    pub fn adjust_filter_mod(&mut self, param: &FilterModParams, rotation: Rotation) {
+        self.filter_mod.adjust(param, rotation);
+    }
+
+    pub fn adjust_lfo(&mut self, lfo: usize, param: &LfoParams, rotation: Rotation) {
+        self.lfos[lfo].adjust(param, rotation);
+    }
+
+    pub fn adjust_mod(&mut self, slot: usize, param: &ModParams, rotation: Rotation) {
+        self.matrix.slots[slot].adjust(param, rotation);
+    }
+
+    /// Adding a stage switches the amp envelope over from the ADSR,
+    /// removing the last one switches it back
+    pub fn adjust_amp_stages(
+        &mut self,
+        stage: usize,
+        param: &BreakpointParams,
+        rotation: Rotation,
+    ) {
+        let stages = self.amp_stages.get_or_insert(Breakpoints::init());
+        stages.adjust(stage, param, rotation);
+
+        if stages.stages.is_empty() {
+            self.amp_stages = None;
+        }
+    }
+
+    pub fn adjust_mod_envelope(
+        &mut self,
+        stage: usize,
+        param: &BreakpointParams,
+        rotation: Rotation,
+    ) {
+        self.mod_envelope.adjust(stage, param, rotation);
+    }
+
+    pub fn adjust_unison(&mut self, param: &UnisonParams, rotation: Rotation) {
+        self.unison.adjust(param, rotation);
+    }
+
+    pub fn switch_engine(&mut self) {
+        self.engine = match self.engine {
+            Engine::Subtractive => Engine::Fm,
+            Engine::Fm => Engine::Subtractive,
+        };
+        info!("Set engine: {}", self.engine);
+    }
+
+    pub fn adjust_velocity(&mut self, param: &VelocityParams, rotation: Rotation) {
+        self.velocity.adjust(param, rotation);
+    }
+
+    pub fn adjust_drive(&mut self, param: &DriveParams, rotation: Rotation) {
+        self.drive.adjust(param, rotation);
+    }
+
+    pub fn adjust_mono(&mut self, param: &MonoParams, rotation: Rotation) {
+        self.mono.adjust(param, rotation);
+    }
+
+    pub fn switch_steal_policy(&mut self) {
+        self.steal = match self.steal {
+            StealPolicy::Oldest => StealPolicy::Quietest,
+            StealPolicy::Quietest => StealPolicy::ReleasedFirst,
+            StealPolicy::ReleasedFirst => StealPolicy::Oldest,
+        };
+        info!("Set voice stealing: {}", self.steal);
+    }
+
+    pub fn adjust(&mut self, param: &MixParams, rotation: Rotation) {
+        match param {
+            MixParams::HardSync => {
+                self.hard_sync = rotation == Rotation::Right;
+                info!("Set hard sync: {}", self.hard_sync);
+            }
+            MixParams::RingMod => {
+                let new = if rotation == Rotation::Right {
+                    self.ring_mod + 0.05
+                } else {
+                    self.ring_mod - 0.05
+                };
+
+                self.ring_mod = new.clamp(0.0, 1.0);
+                info!("Set ring mod: {}", self.ring_mod);
+            }
+            MixParams::Noise => {
+                self.noise = self.noise.next();
+                info!("Set noise: {}", self.noise);
+            }
+            MixParams::NoiseLevel => {
+                let new = if rotation == Rotation::Right {
+                    self.noise_level + 0.05
+                } else {
+                    self.noise_level - 0.05
+                };
+
+                self.noise_level = new.clamp(0.0, 1.0);
+                info!("Set noise level: {}", self.noise_level);
+            }
+            MixParams::BendUp => {
+                self.bend_up = bend_step(self.bend_up, rotation);
+                info!("Set bend up: {} semitones", self.bend_up);
+            }
+            MixParams::BendDown => {
+                self.bend_down = bend_step(self.bend_down, rotation);
+                info!("Set bend down: {} semitones", self.bend_down);
+            }
+        }
+    }
+}
+
+fn bend_step(semitones: f32, rotation: Rotation) -> f32 {
+    let new = if rotation == Rotation::Right {
+        semitones + 1.0
+    } else {
+        semitones - 1.0
+    };
+
+    new.clamp(0.0, 24.0)
+}
+
+#[derive(Debug, Format)]
+pub enum MixParams {
+    HardSync,
+    RingMod,
+    Noise,
+    NoiseLevel,
+    BendUp,
+    BendDown,
+}
+
+impl MixParams {
+    pub const fn init_param() -> Self {
+        Self::HardSync
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use MixParams::*;
+
+        match param {
+            HardSync => Some(RingMod),
+            RingMod => Some(Noise),
+            Noise => Some(NoiseLevel),
+            NoiseLevel => Some(BendUp),
+            BendUp => Some(BendDown),
+            BendDown => None,
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use libm::exp2f;
+
+/// Sustain pedal positions from here on hold the notes like a fully
+/// pressed pedal
+const FULL_SUSTAIN: f32 = 0.9;
+/// Octaves a sustain pedal just short of fully down stretches the release
+/// of the notes it lets go
+const HALF_PEDAL_STRETCH: f32 = 4.0;
+/// Velocity of the notes played with the soft pedal down
+const SOFT_VELOCITY: f32 = 0.7;
+/// Cutoff ratio of the notes played with the soft pedal down
+pub const SOFT_CUTOFF: f32 = 0.6;
+
+/// Sustain (CC 64), sostenuto (CC 66) and soft (CC 67) pedals
+pub struct Pedals {
+    /// `0.0..=1.0`, continuous for half pedaling
+    pub sustain: f32,
+    pub sostenuto: bool,
+    pub soft: bool,
+}
+
+impl Pedals {
+    pub const fn init() -> Self {
+        Self {
+            sustain: 0.0,
+            sostenuto: false,
+            soft: false,
+        }
+    }
+
+    /// Whether the sustain pedal keeps released keys from releasing
+    pub fn holds(&self) -> bool {
+        self.sustain >= FULL_SUSTAIN
+    }
+
+    /// How much longer notes let go by a partly pressed sustain pedal ring
+    pub fn stretch(&self) -> f32 {
+        exp2f(HALF_PEDAL_STRETCH * (self.sustain / FULL_SUSTAIN).min(1.0))
+    }
+
+    /// Velocity of a note played now
+    pub fn soften(&self, velocity: f32) -> f32 {
+        if self.soft {
+            velocity * SOFT_VELOCITY
+        } else {
+            velocity
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_pedal_stretches_the_release() {
+        let mut pedals = Pedals::init();
+        assert_eq!(pedals.stretch(), 1.0);
+        assert!(!pedals.holds());
+
+        pedals.sustain = FULL_SUSTAIN / 2.0;
+        assert_eq!(pedals.stretch(), 4.0);
+        assert!(!pedals.holds());
+
+        pedals.sustain = 1.0;
+        assert!(pedals.holds());
+    }
+}
@@ -0,0 +1,450 @@
+use core::f32::consts::{LN_10, TAU};
+
+use defmt::{Format, info};
+use libm::{expf, roundf};
+
+use crate::{consts::SAMPLE_RATE, encoder::Rotation};
+
+/// Delay lines of the feedback delay network, a power of 2 for the
+/// Hadamard mixing
+const LINES: usize = 8;
+/// `1 / sqrt(LINES)`, keeps the mixing lossless
+const HADAMARD_SCALE: f32 = 0.353_553_4;
+/// Relative line lengths, primes so the echoes don't line up
+const RATIOS: [f32; LINES] = [743.0, 797.0, 881.0, 937.0, 1009.0, 1087.0, 1163.0, 1237.0];
+/// Longest pre-delay, 100 ms
+const MAX_PRE_DELAY: usize = SAMPLE_RATE as usize / 10;
+/// Least memory that leaves every line a few samples
+const MIN_MEMORY: usize = 16 * LINES;
+/// Level of the tail against the dry sound
+const WET_GAIN: f32 = 0.5;
+/// Samples a change of size or decay crossfades over
+const CROSSFADE: f32 = SAMPLE_RATE * 0.03;
+
+pub struct ReverbSettings {
+    /// `0.25..=1.0` of the longest lines the memory has room for
+    pub size: f32,
+    /// Seconds to fall by 60 dB
+    pub decay: f32,
+    /// Cutoff of the low-pass in each line, the highs die out first
+    pub damping: f32,
+    pub pre_delay_ms: u16,
+    /// `0.0..=1.0`, dry to wet
+    pub mix: f32,
+}
+
+impl ReverbSettings {
+    pub const fn init() -> Self {
+        Self {
+            size: 0.7,
+            decay: 2.0,
+            damping: 5_000.0,
+            pre_delay_ms: 10,
+            mix: 0.0,
+        }
+    }
+}
+
+/// Feedback delay network reverb on memory given to it, so it can sit in
+/// whichever RAM has room for it
+pub struct Reverb {
+    settings: ReverbSettings,
+    /// The pre-delay line, then the lines of the network
+    memory: &'static mut [f32],
+    pre_len: usize,
+    pre_write: usize,
+    pre_delay: usize,
+    starts: [usize; LINES],
+    max_lens: [usize; LINES],
+    /// Write positions, the lines wrap at their longest
+    positions: [usize; LINES],
+    /// Delay and gain per line heard, the one being crossfaded to and the
+    /// one for the settings. The gain sets the decay time at the length
+    taps: LineTaps,
+    next_taps: LineTaps,
+    target_taps: LineTaps,
+    /// `0.0..=1.0` through the crossfade to `next_taps`
+    fade: f32,
+    /// Damping filter coefficient and state, per line
+    damping: f32,
+    lowpass: [f32; LINES],
+}
+
+impl Reverb {
+    pub const fn init() -> Self {
+        Self {
+            settings: ReverbSettings::init(),
+            memory: &mut [],
+            pre_len: 0,
+            pre_write: 0,
+            pre_delay: 0,
+            starts: [0; LINES],
+            max_lens: [0; LINES],
+            positions: [0; LINES],
+            taps: LineTaps::init(),
+            next_taps: LineTaps::init(),
+            target_taps: LineTaps::init(),
+            fade: 1.0,
+            damping: 0.0,
+            lowpass: [0.0; LINES],
+        }
+    }
+
+    /// Gives it the memory to reverberate in, its length sets the largest
+    /// size. Until then, or with less than `MIN_MEMORY`, the reverb passes
+    /// the sound through
+    pub fn set_memory(&mut self, memory: &'static mut [f32]) {
+        if memory.len() < MIN_MEMORY {
+            self.memory = &mut [];
+            return;
+        }
+        memory.fill(0.0);
+
+        self.pre_len = (memory.len() / 5).min(MAX_PRE_DELAY);
+        self.pre_write = 0;
+        let total: f32 = RATIOS.iter().sum();
+        let room = (memory.len() - self.pre_len) as f32;
+        let mut start = self.pre_len;
+        for (line, ratio) in RATIOS.iter().enumerate() {
+            self.starts[line] = start;
+            self.max_lens[line] = (room * ratio / total) as usize;
+            start += self.max_lens[line];
+        }
+        self.positions = [0; LINES];
+        self.lowpass = [0.0; LINES];
+        self.memory = memory;
+        self.update();
+        self.taps = self.target_taps;
+        self.next_taps = self.target_taps;
+        self.fade = 1.0;
+    }
+
+    /// Works the settings out into line lengths and coefficients
+    fn update(&mut self) {
+        let settings = &self.settings;
+
+        let target = &mut self.target_taps;
+        for line in 0..LINES {
+            let len = ((self.max_lens[line] as f32 * settings.size) as usize).max(1);
+            target.lens[line] = len.min(self.max_lens[line]);
+            // -60 dB over the decay time, whatever the length
+            target.gains[line] =
+                expf(-3.0 * LN_10 * target.lens[line] as f32 / (settings.decay * SAMPLE_RATE));
+        }
+        self.damping = 1.0 - expf(-TAU * settings.damping / SAMPLE_RATE);
+        let pre_delay = roundf(settings.pre_delay_ms as f32 * SAMPLE_RATE / 1000.0) as usize;
+        self.pre_delay = pre_delay.min(self.pre_len.saturating_sub(1));
+    }
+
+    pub fn process(&mut self, (left, right): (f32, f32)) -> (f32, f32) {
+        if self.memory.is_empty() {
+            return (left, right);
+        }
+
+        let memory = &mut *self.memory;
+        memory[self.pre_write] = (left + right) / 2.0;
+        let input = memory[(self.pre_write + self.pre_len - self.pre_delay) % self.pre_len];
+        self.pre_write = (self.pre_write + 1) % self.pre_len;
+
+        // a new size or decay crossfades in like the times of the delay,
+        // the taps never jump to what is left in the lines
+        if self.fade >= 1.0 {
+            self.taps = self.next_taps;
+            if self.target_taps != self.taps {
+                self.next_taps = self.target_taps;
+                self.fade = 0.0;
+            }
+        }
+
+        let mut taps = [0.0; LINES];
+        for (line, tap) in taps.iter_mut().enumerate() {
+            let (start, max_len, position) =
+                (self.starts[line], self.max_lens[line], self.positions[line]);
+            let read = |taps: &LineTaps| {
+                memory[start + (position + max_len - taps.lens[line]) % max_len] * taps.gains[line]
+            };
+            let (current, next) = (read(&self.taps), read(&self.next_taps));
+            let delayed = current + (next - current) * self.fade;
+
+            let lowpass = &mut self.lowpass[line];
+            *lowpass += (delayed - *lowpass) * self.damping;
+            *tap = *lowpass;
+        }
+        if self.fade < 1.0 {
+            self.fade = (self.fade + 1.0 / CROSSFADE).min(1.0);
+        }
+
+        // alternate signs keep the two sides apart
+        let (mut wet_left, mut wet_right) = (0.0, 0.0);
+        for pair in taps.as_chunks::<4>().0 {
+            wet_left += pair[0] - pair[2];
+            wet_right += pair[1] - pair[3];
+        }
+
+        hadamard(&mut taps);
+        for (line, tap) in taps.iter().enumerate() {
+            memory[self.starts[line] + self.positions[line]] = tap + input;
+            self.positions[line] = (self.positions[line] + 1) % self.max_lens[line];
+        }
+
+        let (wet, dry) = (self.settings.mix * WET_GAIN, 1.0 - self.settings.mix);
+        (left * dry + wet_left * wet, right * dry + wet_right * wet)
+    }
+
+    pub fn adjust(&mut self, param: &ReverbParams, rotation: Rotation) {
+        let settings = &mut self.settings;
+        let up = rotation == Rotation::Right;
+
+        match param {
+            ReverbParams::Size => {
+                settings.size = if up {
+                    (settings.size + 0.05).min(1.0)
+                } else {
+                    (settings.size - 0.05).max(0.25)
+                };
+                info!("Set reverb size: {}", settings.size);
+            }
+            ReverbParams::Decay => {
+                settings.decay = if up {
+                    (settings.decay * 1.1).min(20.0)
+                } else {
+                    (settings.decay / 1.1).max(0.2)
+                };
+                info!("Set reverb decay: {} s", settings.decay);
+            }
+            ReverbParams::Damping => {
+                settings.damping = if up {
+                    (settings.damping * 1.1).min(20_000.0)
+                } else {
+                    (settings.damping / 1.1).max(500.0)
+                };
+                info!("Set reverb damping: {} Hz", settings.damping);
+            }
+            ReverbParams::PreDelay => {
+                settings.pre_delay_ms = if up {
+                    (settings.pre_delay_ms + 5).min(100)
+                } else {
+                    settings.pre_delay_ms.saturating_sub(5)
+                };
+                info!("Set reverb pre-delay: {} ms", settings.pre_delay_ms);
+            }
+            ReverbParams::Mix => {
+                settings.mix = if up {
+                    (settings.mix + 0.05).min(1.0)
+                } else {
+                    (settings.mix - 0.05).max(0.0)
+                };
+                info!("Set reverb mix: {}", settings.mix);
+            }
+        }
+        self.update();
+    }
+}
+
+/// Where each line is read, and how much of it goes round again
+#[derive(Clone, Copy, PartialEq)]
+struct LineTaps {
+    lens: [usize; LINES],
+    gains: [f32; LINES],
+}
+
+impl LineTaps {
+    const fn init() -> Self {
+        Self {
+            lens: [1; LINES],
+            gains: [0.0; LINES],
+        }
+    }
+}
+
+/// Fast Walsh-Hadamard transform, scaled to keep the energy
+fn hadamard(taps: &mut [f32; LINES]) {
+    let mut half = 1;
+    while half < LINES {
+        for block in (0..LINES).step_by(2 * half) {
+            for i in block..block + half {
+                let (a, b) = (taps[i], taps[i + half]);
+                taps[i] = a + b;
+                taps[i + half] = a - b;
+            }
+        }
+        half *= 2;
+    }
+    for tap in taps {
+        *tap *= HADAMARD_SCALE;
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum ReverbParams {
+    Size,
+    Decay,
+    Damping,
+    PreDelay,
+    Mix,
+}
+
+impl ReverbParams {
+    pub const fn init_param() -> Self {
+        Self::Size
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use ReverbParams::*;
+
+        match param {
+            Size => Some(Decay),
+            Decay => Some(Damping),
+            Damping => Some(PreDelay),
+            PreDelay => Some(Mix),
+            Mix => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reverb(decay: f32) -> Reverb {
+        let mut reverb = Reverb::init();
+        reverb.settings.decay = decay;
+        reverb.settings.pre_delay_ms = 0;
+        reverb.settings.mix = 1.0;
+        reverb.set_memory(std::vec![0.0; 48_000].leak());
+        reverb
+    }
+
+    #[test]
+    fn short_memory_passes_the_sound_through() {
+        let mut reverb = reverb(1.0);
+        reverb.set_memory(std::vec![0.0; MIN_MEMORY - 1].leak());
+
+        assert!(reverb.process((0.5, -0.25)) == (0.5, -0.25));
+
+        // just enough for every line
+        reverb.set_memory(std::vec![0.0; MIN_MEMORY].leak());
+        assert!(
+            impulse(&mut reverb, 1_000)
+                .iter()
+                .all(|sample| sample.is_finite())
+        );
+    }
+
+    /// Mono output for an impulse
+    fn impulse(reverb: &mut Reverb, samples: usize) -> std::vec::Vec<f32> {
+        (0..samples)
+            .map(|i| {
+                let (left, right) = reverb.process(if i == 0 { (1.0, 1.0) } else { (0.0, 0.0) });
+                (left + right) / 2.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tail_decays_in_the_set_time() {
+        let mut reverb = reverb(1.0);
+        // without damping every frequency dies out at the same rate
+        reverb.damping = 1.0;
+        let out = impulse(&mut reverb, SAMPLE_RATE as usize * 3 / 2);
+
+        // Schroeder backward integration, T30 from -5 to -35 dB
+        let mut energy = std::vec![0.0f64; out.len()];
+        let mut sum = 0.0;
+        for (i, sample) in out.iter().enumerate().rev() {
+            sum += (*sample as f64).powi(2);
+            energy[i] = sum;
+        }
+        let db = |i: usize| 10.0 * (energy[i] / energy[0]).log10();
+        let at = |level: f64| (0..out.len()).find(|i| db(*i) <= level).unwrap() as f32;
+        let decay = 2.0 * (at(-35.0) - at(-5.0)) / SAMPLE_RATE;
+
+        assert!((decay - 1.0).abs() < 0.1, "{decay}");
+    }
+
+    #[test]
+    fn longest_decay_stays_stable() {
+        let mut reverb = reverb(20.0);
+        reverb.settings.damping = 20_000.0;
+        reverb.settings.size = 1.0;
+        reverb.update();
+
+        // full scale noise, then the tail on its own
+        let mut seed = 1u32;
+        let mut loudest: f32 = 0.0;
+        for i in 0..SAMPLE_RATE as usize * 4 {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let noise = if i < SAMPLE_RATE as usize {
+                seed as f32 / u32::MAX as f32 * 2.0 - 1.0
+            } else {
+                0.0
+            };
+            let (left, right) = reverb.process((noise, noise));
+            assert!(left.is_finite() && right.is_finite());
+            if i > SAMPLE_RATE as usize * 2 {
+                loudest = loudest.max(left.abs()).max(right.abs());
+            }
+        }
+
+        // it only dies away after the noise stops
+        assert!(loudest < 4.0, "{loudest}");
+        let energy = |reverb: &Reverb| {
+            reverb
+                .memory
+                .iter()
+                .map(|sample| sample * sample)
+                .sum::<f32>()
+        };
+        let before = energy(&reverb);
+        for _ in 0..SAMPLE_RATE as usize {
+            reverb.process((0.0, 0.0));
+        }
+        assert!(energy(&reverb) < before);
+    }
+
+    #[test]
+    fn pre_delay_holds_the_tail_back() {
+        let mut reverb = reverb(1.0);
+        reverb.settings.pre_delay_ms = 50;
+        reverb.update();
+        let out = impulse(&mut reverb, SAMPLE_RATE as usize / 5);
+
+        let first = out.iter().position(|sample| *sample != 0.0).unwrap();
+        let shortest = reverb.taps.lens.iter().min().unwrap();
+        assert_eq!(first, 4_800 + shortest);
+    }
+
+    #[test]
+    fn size_changes_fade_in() {
+        // the same tail, with and without the size going down
+        let tail = |smaller: bool| {
+            let mut reverb = reverb(2.0);
+            let mut seed = 1u32;
+            for _ in 0..SAMPLE_RATE as usize / 2 {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let noise = seed as f32 / u32::MAX as f32 * 2.0 - 1.0;
+                reverb.process((noise, noise));
+            }
+            if smaller {
+                for _ in 0..4 {
+                    reverb.adjust(&ReverbParams::Size, Rotation::Left);
+                }
+            }
+
+            (0..CROSSFADE as usize)
+                .map(|_| reverb.process((0.0, 0.0)).0)
+                .collect::<std::vec::Vec<f32>>()
+        };
+
+        let (same, smaller) = (tail(false), tail(true));
+        let level = same.iter().fold(0.0f32, |acc, s| acc.max(s.abs()));
+
+        // no further apart than the crossfade has come
+        for (i, (a, b)) in same.iter().zip(smaller.iter()).enumerate() {
+            let fade = (i + 1) as f32 / CROSSFADE;
+            assert!((a - b).abs() <= 2.0 * level * fade, "{i}: {a} {b}");
+        }
+        assert_ne!(same, smaller);
+    }
+}
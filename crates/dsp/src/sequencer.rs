@@ -0,0 +1,644 @@
+use defmt::{Format, info};
+use midi_parser::parser::{MidiMessage, Note, Velocity};
+
+use crate::{
+    clock::StepClock,
+    consts::{MAX_STEPS, PATTERNS},
+    encoder::Rotation,
+    lfo::Division,
+    noise::Rng,
+};
+
+/// Notes played on the keyboard transpose the pattern by how far they are
+/// from this one
+const ROOT: u8 = 60;
+/// Saved patterns start with it, the last byte is the format version
+const HEADER: [u8; 4] = *b"SEQ\x01";
+const STEP_BYTES: usize = 5;
+const PATTERN_BYTES: usize = 1 + MAX_STEPS * STEP_BYTES;
+/// Size of the saved patterns: the header, the pattern playing, then every
+/// pattern as its length followed by its steps
+pub const SAVED_BYTES: usize = HEADER.len() + 1 + PATTERNS * PATTERN_BYTES;
+
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum PatternLength {
+    Sixteen,
+    ThirtyTwo,
+    SixtyFour,
+}
+
+impl PatternLength {
+    pub const fn steps(&self) -> usize {
+        match self {
+            PatternLength::Sixteen => 16,
+            PatternLength::ThirtyTwo => 32,
+            PatternLength::SixtyFour => 64,
+        }
+    }
+
+    fn from_steps(steps: u8) -> Option<Self> {
+        match steps {
+            16 => Some(PatternLength::Sixteen),
+            32 => Some(PatternLength::ThirtyTwo),
+            64 => Some(PatternLength::SixtyFour),
+            _ => None,
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        match self {
+            PatternLength::Sixteen => PatternLength::ThirtyTwo,
+            PatternLength::ThirtyTwo => PatternLength::SixtyFour,
+            PatternLength::SixtyFour => PatternLength::Sixteen,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub struct Step {
+    /// A rest when off
+    pub on: bool,
+    pub note: u8,
+    pub velocity: u8,
+    /// `1..=100`, percent of the step the note sounds for
+    pub gate: u8,
+    /// Carries on the note of the step before instead of playing its own
+    pub tie: bool,
+    /// Holds the note until the next one starts, which glides over in
+    /// legato mode
+    pub slide: bool,
+    /// `0..=100`, percent chance of the step playing
+    pub probability: u8,
+}
+
+impl Step {
+    pub const fn init() -> Self {
+        Self {
+            on: false,
+            note: ROOT,
+            velocity: 100,
+            gate: 50,
+            tie: false,
+            slide: false,
+            probability: 100,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; STEP_BYTES] {
+        let flags = self.on as u8 | (self.tie as u8) << 1 | (self.slide as u8) << 2;
+        [flags, self.note, self.velocity, self.gate, self.probability]
+    }
+
+    fn from_bytes(bytes: &[u8; STEP_BYTES]) -> Option<Self> {
+        let [flags, note, velocity, gate, probability] = *bytes;
+        if flags > 0b111
+            || note > 127
+            || velocity > 127
+            || !(1..=100).contains(&gate)
+            || probability > 100
+        {
+            return None;
+        }
+
+        Some(Self {
+            on: flags & 1 != 0,
+            note,
+            velocity,
+            gate,
+            tie: flags & 0b10 != 0,
+            slide: flags & 0b100 != 0,
+            probability,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Pattern {
+    pub steps: [Step; MAX_STEPS],
+    pub length: PatternLength,
+}
+
+impl Pattern {
+    pub const fn init() -> Self {
+        Self {
+            steps: [Step::init(); MAX_STEPS],
+            length: PatternLength::Sixteen,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Format)]
+pub enum SequencerError {
+    /// Not saved patterns, or saved in another format
+    BadHeader,
+    BadLength,
+    /// A step or a pattern length out of range
+    BadData,
+}
+
+/// Monophonic step sequencer, playing one of its patterns into the voice
+/// pool
+pub struct Sequencer {
+    patterns: [Pattern; PATTERNS],
+    /// Pattern playing
+    current: usize,
+    enabled: bool,
+    clock: StepClock,
+    /// Step of the pattern playing next
+    position: usize,
+    /// Semitones from the last key played
+    transpose: i16,
+    /// Note it has on in the voice pool
+    sounding: Option<Note>,
+    /// Samples until the note ends, endless while a tie or slide holds it
+    until_release: f32,
+    /// The note playing slides into the next one
+    sliding: bool,
+    rng: Rng,
+}
+
+impl Sequencer {
+    pub const fn init() -> Self {
+        const EMPTY: Pattern = Pattern::init();
+
+        Self {
+            patterns: [EMPTY; PATTERNS],
+            current: 0,
+            enabled: false,
+            clock: StepClock::init(Division::Sixteenth),
+            position: 0,
+            transpose: 0,
+            sounding: None,
+            until_release: 0.0,
+            sliding: false,
+            rng: Rng::new(1),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.clock.set_tempo(bpm);
+    }
+
+    /// BPM of the MIDI clock, when it steps on it
+    pub fn midi_tempo(&self) -> Option<f32> {
+        self.clock.midi_tempo()
+    }
+
+    /// A key played while it runs, transposing the pattern
+    pub fn note_on(&mut self, note: &Note) {
+        self.transpose = note.num as i16 - ROOT as i16;
+    }
+
+    /// Runs a sample on, sending the notes of the steps to `play`
+    pub fn next_sample(&mut self, mut play: impl FnMut(MidiMessage)) {
+        if self.sounding.is_some() && self.until_release <= 0.0 {
+            self.release(&mut play);
+        }
+
+        if let Some(samples) = self.clock.next_sample()
+            && self.enabled
+        {
+            self.advance(samples, &mut play);
+        }
+
+        self.until_release -= 1.0;
+    }
+
+    /// A MIDI clock tick
+    pub fn clock(&mut self, mut play: impl FnMut(MidiMessage)) {
+        if let Some(samples) = self.clock.tick()
+            && self.enabled
+        {
+            self.advance(samples, &mut play);
+        }
+    }
+
+    /// MIDI start, from the first step
+    pub fn start(&mut self) {
+        self.position = 0;
+        self.clock.start();
+    }
+
+    /// MIDI continue, from where it stopped
+    pub fn resume(&mut self) {
+        self.clock.resume();
+    }
+
+    /// MIDI stop, the note ends right away
+    pub fn stop(&mut self) {
+        self.clock.stop();
+        self.until_release = 0.0;
+    }
+
+    /// Plays the next step, `samples` long
+    fn advance(&mut self, samples: f32, play: &mut impl FnMut(MidiMessage)) {
+        let pattern = &self.patterns[self.current];
+        let length = pattern.length.steps();
+        let index = self.position % length;
+        let step = pattern.steps[index];
+        let next = pattern.steps[(index + 1) % length];
+        self.position = (index + 1) % length;
+
+        // a tie or slide holds the note into the next step
+        let hold = next.tie || step.slide;
+        let gate = if hold {
+            f32::INFINITY
+        } else {
+            step.gate as f32 / 100.0 * samples
+        };
+
+        if step.tie && self.sounding.is_some() {
+            self.until_release = gate;
+            self.sliding = step.slide;
+            return;
+        }
+
+        let plays = step.on && (self.rng.next_u32() % 100) < step.probability as u32;
+        if !plays {
+            self.release(play);
+            return;
+        }
+
+        let note = Note::new((step.note as i16 + self.transpose).clamp(0, 127) as u8);
+        let velocity = Velocity(step.velocity);
+        if self.sliding {
+            // the next note starts before the one sliding into it ends
+            play(MidiMessage::NoteOn(note, velocity));
+            if let Some(sliding) = self.sounding.take()
+                && sliding != note
+            {
+                play(MidiMessage::NoteOff(sliding, Velocity(64)));
+            }
+        } else {
+            self.release(play);
+            play(MidiMessage::NoteOn(note, velocity));
+        }
+
+        self.sounding = Some(note);
+        self.sliding = step.slide;
+        self.until_release = gate;
+    }
+
+    fn release(&mut self, play: &mut impl FnMut(MidiMessage)) {
+        if let Some(note) = self.sounding.take() {
+            play(MidiMessage::NoteOff(note, Velocity(64)));
+        }
+        self.sliding = false;
+    }
+
+    /// Writes the patterns to `out`, for keeping them over power cycles
+    pub fn save(&self, out: &mut [u8; SAVED_BYTES]) {
+        let (header, rest) = out.split_at_mut(HEADER.len());
+        header.copy_from_slice(&HEADER);
+        rest[0] = self.current as u8;
+
+        for (pattern, bytes) in self
+            .patterns
+            .iter()
+            .zip(rest[1..].as_chunks_mut::<PATTERN_BYTES>().0)
+        {
+            bytes[0] = pattern.length.steps() as u8;
+            for (step, bytes) in pattern
+                .steps
+                .iter()
+                .zip(bytes[1..].as_chunks_mut::<STEP_BYTES>().0)
+            {
+                bytes.copy_from_slice(&step.to_bytes());
+            }
+        }
+    }
+
+    /// Reads back patterns written by [`Self::save`]. If anything doesn't
+    /// check out, the patterns are left as they were
+    pub fn load(&mut self, data: &[u8]) -> Result<(), SequencerError> {
+        let Some(rest) = data.strip_prefix(&HEADER) else {
+            return Err(SequencerError::BadHeader);
+        };
+        if rest.len() != SAVED_BYTES - HEADER.len() {
+            return Err(SequencerError::BadLength);
+        }
+
+        let (&current, patterns) = rest.split_first().ok_or(SequencerError::BadLength)?;
+        let valid = (current as usize) < PATTERNS
+            && patterns.as_chunks::<PATTERN_BYTES>().0.iter().all(|bytes| {
+                PatternLength::from_steps(bytes[0]).is_some()
+                    && bytes[1..]
+                        .as_chunks::<STEP_BYTES>()
+                        .0
+                        .iter()
+                        .all(|step| Step::from_bytes(step).is_some())
+            });
+        if !valid {
+            return Err(SequencerError::BadData);
+        }
+
+        for (pattern, bytes) in self
+            .patterns
+            .iter_mut()
+            .zip(patterns.as_chunks::<PATTERN_BYTES>().0)
+        {
+            pattern.length = PatternLength::from_steps(bytes[0]).ok_or(SequencerError::BadData)?;
+            for (step, bytes) in pattern
+                .steps
+                .iter_mut()
+                .zip(bytes[1..].as_chunks::<STEP_BYTES>().0)
+            {
+                *step = Step::from_bytes(bytes).ok_or(SequencerError::BadData)?;
+            }
+        }
+        self.current = current as usize;
+
+        Ok(())
+    }
+
+    /// Edits `step` of the pattern playing for the step params, the
+    /// sequencer for the others
+    pub fn adjust(&mut self, step: usize, param: &SequencerParams, rotation: Rotation) {
+        let pattern = &mut self.patterns[self.current];
+        let step = &mut pattern.steps[step % MAX_STEPS];
+        let up = rotation == Rotation::Right;
+
+        match param {
+            SequencerParams::Enabled => {
+                self.enabled = up;
+                if self.enabled {
+                    self.position = 0;
+                    self.clock.restart();
+                } else {
+                    self.until_release = 0.0;
+                }
+                info!("Set sequencer: {}", self.enabled);
+            }
+            SequencerParams::Pattern => {
+                self.current = match rotation {
+                    Rotation::Right => (self.current + 1).min(PATTERNS - 1),
+                    Rotation::Left => self.current.saturating_sub(1),
+                };
+                info!("Set sequencer pattern: {}", self.current + 1);
+            }
+            SequencerParams::Length => {
+                pattern.length = pattern.length.next();
+                info!("Set pattern length: {}", pattern.length.steps());
+            }
+            SequencerParams::Division => self.clock.adjust_division(rotation),
+            SequencerParams::Swing => self.clock.adjust_swing(rotation),
+            SequencerParams::Clock => self.clock.switch_source(),
+            SequencerParams::On => {
+                step.on = up;
+                info!("Set step on: {}", step.on);
+            }
+            SequencerParams::Note => {
+                step.note = if up {
+                    (step.note + 1).min(127)
+                } else {
+                    step.note.saturating_sub(1)
+                };
+                step.on = true;
+                info!("Set step note: {}", step.note);
+            }
+            SequencerParams::Velocity => {
+                step.velocity = if up {
+                    (step.velocity + 1).min(127)
+                } else {
+                    step.velocity.saturating_sub(1)
+                };
+                info!("Set step velocity: {}", step.velocity);
+            }
+            SequencerParams::Gate => {
+                step.gate = if up {
+                    (step.gate + 5).min(100)
+                } else {
+                    step.gate.saturating_sub(5).max(5)
+                };
+                info!("Set step gate: {}%", step.gate);
+            }
+            SequencerParams::Tie => {
+                step.tie = up;
+                info!("Set step tie: {}", step.tie);
+            }
+            SequencerParams::Slide => {
+                step.slide = up;
+                info!("Set step slide: {}", step.slide);
+            }
+            SequencerParams::Probability => {
+                step.probability = if up {
+                    (step.probability + 5).min(100)
+                } else {
+                    step.probability.saturating_sub(5)
+                };
+                info!("Set step probability: {}%", step.probability);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum SequencerParams {
+    Enabled,
+    Pattern,
+    Length,
+    Division,
+    Swing,
+    Clock,
+    On,
+    Note,
+    Velocity,
+    Gate,
+    Tie,
+    Slide,
+    Probability,
+}
+
+impl SequencerParams {
+    pub const fn init_param() -> Self {
+        Self::Enabled
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use SequencerParams::*;
+
+        match param {
+            Enabled => Some(Pattern),
+            Pattern => Some(Length),
+            Length => Some(Division),
+            Division => Some(Swing),
+            Swing => Some(Clock),
+            Clock => Some(On),
+            On => Some(Note),
+            Note => Some(Velocity),
+            Velocity => Some(Gate),
+            Gate => Some(Tie),
+            Tie => Some(Slide),
+            Slide => Some(Probability),
+            Probability => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 96 kHz at 120 BPM
+    const SIXTEENTH: usize = 12_000;
+
+    fn sequencer(steps: &[Step]) -> Sequencer {
+        let mut seq = Sequencer::init();
+        seq.enabled = true;
+        seq.patterns[0].steps[..steps.len()].copy_from_slice(steps);
+        seq
+    }
+
+    fn note(num: u8) -> Step {
+        Step {
+            on: true,
+            note: num,
+            ..Step::init()
+        }
+    }
+
+    /// Sample, note and whether it's a note on, for every note on and off
+    fn run(seq: &mut Sequencer, samples: usize) -> std::vec::Vec<(usize, u8, bool)> {
+        let mut events = std::vec::Vec::new();
+        for i in 0..samples {
+            seq.next_sample(|msg| match msg {
+                MidiMessage::NoteOn(note, _) => events.push((i, note.num, true)),
+                MidiMessage::NoteOff(note, _) => events.push((i, note.num, false)),
+                _ => {}
+            });
+        }
+        events
+    }
+
+    #[test]
+    fn steps_play_for_their_gate() {
+        let short = Step {
+            gate: 25,
+            ..note(62)
+        };
+        let mut seq = sequencer(&[note(60), Step::init(), short]);
+
+        assert_eq!(
+            run(&mut seq, 3 * SIXTEENTH),
+            [
+                (0, 60, true),
+                (SIXTEENTH / 2, 60, false),
+                (2 * SIXTEENTH, 62, true),
+                (2 * SIXTEENTH + SIXTEENTH / 4, 62, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_and_slides_hold_the_note() {
+        let slide = Step {
+            slide: true,
+            ..note(60)
+        };
+        let tie = Step {
+            tie: true,
+            ..Step::init()
+        };
+        let mut seq = sequencer(&[slide, note(67), tie]);
+
+        assert_eq!(
+            run(&mut seq, 3 * SIXTEENTH),
+            [
+                (0, 60, true),
+                // the slide overlaps the next note
+                (SIXTEENTH, 67, true),
+                (SIXTEENTH, 60, false),
+                (2 * SIXTEENTH + SIXTEENTH / 2, 67, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn probability_skips_steps() {
+        let never = Step {
+            probability: 0,
+            ..note(60)
+        };
+        let sometimes = Step {
+            probability: 50,
+            ..note(60)
+        };
+
+        let mut seq = sequencer(&[never; 16]);
+        assert!(run(&mut seq, 16 * SIXTEENTH).is_empty());
+
+        let mut seq = sequencer(&[sometimes; 16]);
+        let played = run(&mut seq, 16 * SIXTEENTH)
+            .iter()
+            .filter(|(_, _, on)| *on)
+            .count();
+        assert!((3..=13).contains(&played), "{played}");
+    }
+
+    #[test]
+    fn keys_transpose_and_the_length_wraps() {
+        let mut seq = sequencer(&[note(60)]);
+        seq.patterns[0].steps[16] = note(72);
+        seq.note_on(&Note::new(65));
+
+        let ons: std::vec::Vec<(usize, u8, bool)> = run(&mut seq, 17 * SIXTEENTH)
+            .into_iter()
+            .filter(|(_, _, on)| *on)
+            .collect();
+        assert_eq!(ons, [(0, 65, true), (16 * SIXTEENTH, 65, true)]);
+    }
+
+    #[test]
+    fn stop_ends_the_note_and_start_goes_back_to_the_top() {
+        let mut seq = sequencer(&[note(60), note(62), note(64)]);
+        run(&mut seq, SIXTEENTH + 10);
+
+        seq.stop();
+        assert_eq!(run(&mut seq, 3 * SIXTEENTH), [(0, 62, false)]);
+
+        seq.start();
+        assert_eq!(run(&mut seq, 1), [(0, 60, true)]);
+    }
+
+    #[test]
+    fn patterns_survive_a_save_and_load() {
+        let mut seq = sequencer(&[
+            note(60),
+            Step {
+                tie: true,
+                slide: true,
+                gate: 80,
+                ..note(48)
+            },
+        ]);
+        seq.patterns[3].length = PatternLength::SixtyFour;
+        seq.patterns[3].steps[63] = Step {
+            probability: 30,
+            velocity: 5,
+            ..note(127)
+        };
+        seq.current = 3;
+
+        let mut saved = [0; SAVED_BYTES];
+        seq.save(&mut saved);
+
+        let mut loaded = Sequencer::init();
+        assert_eq!(loaded.load(&saved), Ok(()));
+        assert_eq!(loaded.patterns, seq.patterns);
+        assert_eq!(loaded.current, 3);
+
+        assert_eq!(loaded.load(&saved[1..]), Err(SequencerError::BadHeader));
+        assert_eq!(loaded.load(&saved[..100]), Err(SequencerError::BadLength));
+        let mut corrupt = saved;
+        corrupt[HEADER.len() + 1] = 20;
+        assert_eq!(
+            Sequencer::init().load(&corrupt),
+            Err(SequencerError::BadData)
+        );
+    }
+}
@@ -1,15 +1,26 @@
-use midi_parser::parser::MidiMessage;
+use midi_parser::parser::{ControlNum, ControlVal, MidiMessage, Velocity};
 
 use crate::{
-    adsr::{self, TimeMs},
-    consts::SAMPLE_RATE,
-    filter::Filter,
+    adsr::{self, Curve, TimeMs},
+    arp::{ArpParams, Arpeggiator},
+    consts::{EFFECT_SLOTS, SAMPLE_RATE},
+    delay::{DelayFrame, DelayParams, StereoDelay},
+    drive::DriveParams,
+    encoder::Rotation,
+    modulation::{EffectParams, EffectSlot},
+    reverb::{Reverb, ReverbParams},
+    sequencer::{SAVED_BYTES, Sequencer, SequencerError, SequencerParams},
     voice::VoicePool,
+    wavetable::WavetableError,
 };
 
 pub struct State {
-    pub filter: Filter,
     voice_pool: VoicePool,
+    arp: Arpeggiator,
+    sequencer: Sequencer,
+    effect_slots: [EffectSlot; EFFECT_SLOTS],
+    delay: StereoDelay,
+    reverb: Reverb,
 }
 
 impl State {
@@ -19,29 +30,188 @@ impl State {
             decay: TimeMs(20),
             release: TimeMs(200),
             sustain_level: 0.8,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
         };
 
         let envelope = adsr::Envelope::new(config, SAMPLE_RATE);
 
         Self {
-            filter: Filter::new(),
             voice_pool: VoicePool::new(envelope),
+            arp: Arpeggiator::init(),
+            sequencer: Sequencer::init(),
+            effect_slots: [const { EffectSlot::init() }; EFFECT_SLOTS],
+            delay: StereoDelay::init(),
+            reverb: Reverb::init(),
         }
     }
 
+    /// Mono downmix of [`Self::next_sample_stereo`]
     pub fn next_sample(&mut self) -> f32 {
-        self.voice_pool.next_sample()
+        let (left, right) = self.next_sample_stereo();
+        (left + right) / 2.0
+    }
+
+    pub fn next_sample_stereo(&mut self) -> (f32, f32) {
+        let pool = &mut self.voice_pool;
+        self.sequencer.next_sample(|msg| play(pool, &msg));
+        self.arp.next_sample(|msg| play(pool, &msg));
+        let mut voices = pool.next_sample_stereo();
+        for slot in &mut self.effect_slots {
+            voices = slot.process(voices);
+        }
+
+        self.reverb.process(self.delay.process(voices))
+    }
+
+    /// BPM that synced LFOs, the arpeggiator and the effects follow
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.voice_pool.set_tempo(bpm);
+        self.arp.set_tempo(bpm);
+        self.sequencer.set_tempo(bpm);
+        self.effect_slots
+            .iter_mut()
+            .for_each(|slot| slot.set_tempo(bpm));
+        self.delay.set_tempo(bpm);
+    }
+
+    /// The delay stays off until it has a line, which can be put in any RAM
+    /// with room for it, `DELAY_FRAMES` long
+    pub fn set_delay_line(&mut self, line: &'static mut [DelayFrame]) {
+        self.delay.set_line(line);
+    }
+
+    /// The chorus and flanger of a slot pass the sound through until it
+    /// has a line, `EFFECT_LINE_FRAMES` long
+    pub fn set_effect_line(&mut self, slot: usize, line: &'static mut [[f32; 2]]) {
+        if let Some(slot) = self.effect_slots.get_mut(slot) {
+            slot.set_line(line);
+        }
+    }
+
+    pub fn adjust_effect_slot(&mut self, slot: usize, param: &EffectParams, rotation: Rotation) {
+        if let Some(slot) = self.effect_slots.get_mut(slot) {
+            slot.adjust(param, rotation);
+        }
+    }
+
+    pub fn adjust_delay(&mut self, param: &DelayParams, rotation: Rotation) {
+        self.delay.adjust(param, rotation);
+    }
+
+    /// The reverb stays off until it has memory, `REVERB_SAMPLES` long
+    pub fn set_reverb_memory(&mut self, memory: &'static mut [f32]) {
+        self.reverb.set_memory(memory);
+    }
+
+    pub fn adjust_reverb(&mut self, param: &ReverbParams, rotation: Rotation) {
+        self.reverb.adjust(param, rotation);
+    }
+
+    pub fn adjust_drive(&mut self, param: &DriveParams, rotation: Rotation) {
+        self.voice_pool.adjust_drive(param, rotation);
+    }
+
+    pub fn adjust_arp(&mut self, param: &ArpParams, rotation: Rotation) {
+        let was_enabled = self.arp.is_enabled();
+        self.arp.adjust(param, rotation);
+
+        // the keys down so far played the voices, and their note-offs will
+        // go to the arpeggiator now
+        if !was_enabled && self.arp.is_enabled() && !self.sequencer.is_enabled() {
+            self.voice_pool.release_keys();
+        }
+    }
+
+    pub fn adjust_sequencer(&mut self, step: usize, param: &SequencerParams, rotation: Rotation) {
+        let was_enabled = self.sequencer.is_enabled();
+        self.sequencer.adjust(step, param, rotation);
+
+        // keys the arpeggiator took still reach it, see `process_midi_msg`
+        if !was_enabled && self.sequencer.is_enabled() && !self.arp.is_enabled() {
+            self.voice_pool.release_keys();
+        }
+    }
+
+    /// Sequencer patterns, for keeping them over power cycles
+    pub fn save_patterns(&self, out: &mut [u8; SAVED_BYTES]) {
+        self.sequencer.save(out);
+    }
+
+    pub fn load_patterns(&mut self, data: &[u8]) -> Result<(), SequencerError> {
+        self.sequencer.load(data)
+    }
+
+    /// Frames for the oscillators on the user wavetable, in the format of
+    /// [`WavetableSet::load_patch_data`](crate::wavetable::WavetableSet::load_patch_data)
+    pub fn load_wavetable(&mut self, data: &[u8]) -> Result<(), WavetableError> {
+        self.voice_pool.load_wavetable(data)
     }
 
     pub fn is_active(&self) -> bool {
         self.voice_pool.is_active()
     }
 
+    /// BPM of the MIDI clock, if the arpeggiator or the sequencer is on it
+    fn midi_tempo(&self) -> Option<f32> {
+        self.arp.midi_tempo().or(self.sequencer.midi_tempo())
+    }
+
     pub fn process_midi_msg(&mut self, msg: &MidiMessage) {
         use MidiMessage::*;
         match msg {
-            NoteOn(note, _velocity) => self.voice_pool.on_note_on(note), // todo velocity
-            NoteOff(note, _velocity) => self.voice_pool.on_note_off(note), // todo velocity
+            // keys transpose the sequence rather than play
+            NoteOn(note, _) if self.sequencer.is_enabled() => self.sequencer.note_on(note),
+            // only the arpeggiator may still hold keys from before
+            NoteOff(note, _) if self.sequencer.is_enabled() => {
+                if self.arp.is_enabled() {
+                    self.arp.note_off(note);
+                }
+            }
+            NoteOn(note, velocity) if self.arp.is_enabled() => self.arp.note_on(note, velocity),
+            NoteOff(note, _) if self.arp.is_enabled() => self.arp.note_off(note),
+            NoteOn(note, velocity) => self.voice_pool.on_note_on(note, velocity),
+            NoteOff(note, velocity) => self.voice_pool.on_note_off(note, velocity),
+            PithBend(value) => self.voice_pool.set_pitch_bend(value.bipolar()),
+            CC(ControlNum(1), ControlVal(value)) => {
+                self.voice_pool.set_mod_wheel(*value as f32 / 127.0)
+            }
+            CC(ControlNum(64), ControlVal(value)) => {
+                self.voice_pool.set_sustain(*value as f32 / 127.0)
+            }
+            CC(ControlNum(66), ControlVal(value)) => self.voice_pool.set_sostenuto(*value >= 64),
+            CC(ControlNum(67), ControlVal(value)) => self.voice_pool.set_soft(*value >= 64),
+            ChannelAT(Velocity(value)) => self.voice_pool.set_aftertouch(*value as f32 / 127.0),
+            PolyphonicAT(note, Velocity(value)) => self
+                .voice_pool
+                .on_poly_aftertouch(note, *value as f32 / 127.0),
+            Clock => {
+                let pool = &mut self.voice_pool;
+                self.sequencer.clock(|msg| play(pool, &msg));
+                self.arp.clock(|msg| play(pool, &msg));
+                // synced LFOs and the effects follow the MIDI clock when
+                // the arpeggiator or the sequencer does
+                if let Some(tempo) = self.midi_tempo() {
+                    self.voice_pool.set_tempo(tempo);
+                    self.effect_slots
+                        .iter_mut()
+                        .for_each(|slot| slot.set_tempo(tempo));
+                    self.delay.set_tempo(tempo);
+                }
+            }
+            Start => {
+                self.arp.start();
+                self.sequencer.start();
+            }
+            Continue => {
+                self.arp.resume();
+                self.sequencer.resume();
+            }
+            Stop => {
+                self.arp.stop();
+                self.sequencer.stop();
+            }
             // CC(num, val) => {
             //     match controller {
             //         74 => {
@@ -59,3 +229,92 @@ impl State {
         }
     }
 }
+
+/// Plays a note from the arpeggiator or the sequencer
+fn play(pool: &mut VoicePool, msg: &MidiMessage) {
+    match msg {
+        MidiMessage::NoteOn(note, velocity) => pool.on_note_on(note, velocity),
+        MidiMessage::NoteOff(note, velocity) => pool.on_note_off(note, velocity),
+        _ => {}
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use midi_parser::parser::Note;
+
+    use super::*;
+
+    /// A second at most, until the voices go quiet
+    fn run_until_quiet(state: &mut State) -> bool {
+        for _ in 0..SAMPLE_RATE as usize {
+            state.next_sample();
+            if !state.is_active() {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn run(state: &mut State, samples: usize) {
+        (0..samples).for_each(|_| {
+            state.next_sample();
+        });
+    }
+
+    #[test]
+    fn arp_lets_go_of_keys_held_before_it() {
+        let mut state = State::new();
+        let note = Note::new(60);
+
+        state.process_midi_msg(&MidiMessage::NoteOn(note, Velocity(100)));
+        run(&mut state, 1_000);
+        state.adjust_arp(&ArpParams::Enabled, Rotation::Right);
+        state.process_midi_msg(&MidiMessage::NoteOff(note, Velocity(64)));
+
+        assert!(run_until_quiet(&mut state));
+    }
+
+    #[test]
+    fn tempo_follows_whichever_is_on_the_midi_clock() {
+        let mut state = State::new();
+        state.adjust_sequencer(0, &SequencerParams::Clock, Rotation::Right);
+
+        for _ in 0..25 {
+            state.process_midi_msg(&MidiMessage::Clock);
+            run(&mut state, 1_000);
+        }
+
+        // a tick every 1000 samples is 240 BPM
+        assert_eq!(state.midi_tempo(), Some(240.0));
+    }
+
+    #[test]
+    fn sequencer_lets_go_of_keys_held_before_it() {
+        let mut state = State::new();
+        let note = Note::new(60);
+
+        state.process_midi_msg(&MidiMessage::NoteOn(note, Velocity(100)));
+        run(&mut state, 1_000);
+        state.adjust_sequencer(0, &SequencerParams::Enabled, Rotation::Right);
+        state.process_midi_msg(&MidiMessage::NoteOff(note, Velocity(64)));
+
+        assert!(run_until_quiet(&mut state));
+    }
+
+    #[test]
+    fn sequencer_lets_the_arp_go_of_its_keys() {
+        let mut state = State::new();
+        let note = Note::new(60);
+
+        state.adjust_arp(&ArpParams::Enabled, Rotation::Right);
+        state.process_midi_msg(&MidiMessage::NoteOn(note, Velocity(100)));
+        run(&mut state, 1_000);
+        state.adjust_sequencer(0, &SequencerParams::Enabled, Rotation::Right);
+        state.process_midi_msg(&MidiMessage::NoteOff(note, Velocity(64)));
+
+        assert!(run_until_quiet(&mut state));
+    }
+}
@@ -0,0 +1,238 @@
+use core::f32::consts::{FRAC_PI_4, SQRT_2};
+
+use defmt::{Format, info};
+use libm::{cosf, exp2f, sinf, sqrtf};
+
+use crate::{consts::MAX_UNISON, encoder::Rotation};
+
+/// How the detune of the copies is spread across the stack
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum SpreadCurve {
+    /// Evenly spaced
+    Linear,
+    /// Most copies close to the note, a few far out, like a supersaw
+    Center,
+    /// Most copies far out, for a wide chorus
+    Edges,
+}
+
+impl SpreadCurve {
+    pub fn next(&self) -> Self {
+        match self {
+            SpreadCurve::Linear => SpreadCurve::Center,
+            SpreadCurve::Center => SpreadCurve::Edges,
+            SpreadCurve::Edges => SpreadCurve::Linear,
+        }
+    }
+
+    /// Maps a position in `-1.0..=1.0` across the stack to a detune amount,
+    /// keeping both ends at full detune
+    fn shape(&self, position: f32) -> f32 {
+        match self {
+            SpreadCurve::Linear => position,
+            SpreadCurve::Center => position * position.abs(),
+            SpreadCurve::Edges => sqrtf(position.abs()).copysign(position),
+        }
+    }
+}
+
+/// Where one copy of a stack sits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UnisonCopy {
+    /// Frequency ratio to the note
+    pub detune: f32,
+    /// `-1.0` is hard left
+    pub pan: f32,
+    /// Share of the stack's level
+    pub level: f32,
+}
+
+impl UnisonCopy {
+    /// A lone voice, in tune and centered
+    pub const CENTER: Self = Self {
+        detune: 1.0,
+        pan: 0.0,
+        level: 1.0,
+    };
+
+    /// Left and right gains with the pan moved by `pan_mod`. Equal power,
+    /// scaled so a centered copy plays at unity; the copies are
+    /// uncorrelated, so the stack adds up by power as well
+    pub fn gains(&self, pan_mod: f32) -> [f32; 2] {
+        let angle = (1.0 + (self.pan + pan_mod).clamp(-1.0, 1.0)) * FRAC_PI_4;
+
+        [
+            self.level * SQRT_2 * cosf(angle),
+            self.level * SQRT_2 * sinf(angle),
+        ]
+    }
+}
+
+/// Stacks detuned copies of every note, each copy taking a voice
+#[derive(Clone, Copy)]
+pub struct UnisonSettings {
+    /// Copies per note, `1..=MAX_UNISON`
+    pub voices: usize,
+    /// Cents between the center and the outermost copies
+    pub detune: f32,
+    pub curve: SpreadCurve,
+    /// How far the outermost copies are panned, `0.0..=1.0`
+    pub stereo_spread: f32,
+}
+
+impl UnisonSettings {
+    pub const fn init() -> Self {
+        Self {
+            voices: 1,
+            detune: 20.0,
+            curve: SpreadCurve::Linear,
+            stereo_spread: 0.5,
+        }
+    }
+
+    /// Copy `index` of a stack of `count`, which can be less than `voices`
+    /// when the polyphony runs short
+    pub fn copy(&self, index: usize, count: usize) -> UnisonCopy {
+        if count <= 1 {
+            return UnisonCopy::CENTER;
+        }
+
+        let position = 2.0 * index as f32 / (count - 1) as f32 - 1.0;
+        let cents = self.detune * self.curve.shape(position);
+
+        UnisonCopy {
+            detune: exp2f(cents / 1200.0),
+            pan: self.stereo_spread * position,
+            level: 1.0 / sqrtf(count as f32),
+        }
+    }
+
+    pub fn adjust(&mut self, param: &UnisonParams, rotation: Rotation) {
+        match param {
+            UnisonParams::Voices => {
+                self.voices = match rotation {
+                    Rotation::Right => (self.voices + 1).min(MAX_UNISON),
+                    Rotation::Left => (self.voices - 1).max(1),
+                };
+                info!("Set unison voices: {}", self.voices);
+            }
+            UnisonParams::Detune => {
+                let new = if rotation == Rotation::Right {
+                    self.detune + 1.0
+                } else {
+                    self.detune - 1.0
+                };
+
+                self.detune = new.clamp(0.0, 100.0);
+                info!("Set unison detune: {}", self.detune);
+            }
+            UnisonParams::Curve => {
+                self.curve = self.curve.next();
+                info!("Set unison curve: {}", self.curve);
+            }
+            UnisonParams::StereoSpread => {
+                let new = if rotation == Rotation::Right {
+                    self.stereo_spread + 0.05
+                } else {
+                    self.stereo_spread - 0.05
+                };
+
+                self.stereo_spread = new.clamp(0.0, 1.0);
+                info!("Set stereo spread: {}", self.stereo_spread);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum UnisonParams {
+    Voices,
+    Detune,
+    Curve,
+    StereoSpread,
+}
+
+impl UnisonParams {
+    pub const fn init_param() -> Self {
+        Self::Voices
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use UnisonParams::*;
+
+        match param {
+            Voices => Some(Detune),
+            Detune => Some(Curve),
+            Curve => Some(StereoSpread),
+            StereoSpread => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(voices: usize) -> UnisonSettings {
+        UnisonSettings {
+            voices,
+            detune: 30.0,
+            curve: SpreadCurve::Linear,
+            stereo_spread: 1.0,
+        }
+    }
+
+    fn cents(copy: &UnisonCopy) -> f32 {
+        1200.0 * copy.detune.log2()
+    }
+
+    #[test]
+    fn stack_is_symmetric() {
+        let settings = settings(5);
+        let copies: Vec<UnisonCopy> = (0..5).map(|i| settings.copy(i, 5)).collect();
+
+        assert!((cents(&copies[0]) + 30.0).abs() < 1e-3);
+        assert!((cents(&copies[4]) - 30.0).abs() < 1e-3);
+        assert!(cents(&copies[2]).abs() < 1e-3);
+
+        for (low, high) in copies.iter().zip(copies.iter().rev()) {
+            assert!((cents(low) + cents(high)).abs() < 1e-3);
+            assert!((low.gains(0.0)[0] - high.gains(0.0)[1]).abs() < 1e-5);
+        }
+
+        // hard left and right at full spread
+        assert!(copies[0].gains(0.0)[1].abs() < 1e-6);
+        assert!(copies[4].gains(0.0)[0].abs() < 1e-6);
+    }
+
+    #[test]
+    fn stack_keeps_its_power() {
+        for count in 1..=MAX_UNISON {
+            let settings = settings(count);
+            let power: f32 = (0..count)
+                .map(|i| {
+                    let [l, r] = settings.copy(i, count).gains(0.0);
+                    (l * l + r * r) / 2.0
+                })
+                .sum();
+
+            assert!((power - 1.0).abs() < 1e-4, "{count} copies: {power}");
+        }
+    }
+
+    #[test]
+    fn curves_move_the_inner_copies() {
+        let mut settings = settings(5);
+        let inner = |settings: &UnisonSettings| cents(&settings.copy(3, 5));
+
+        let linear = inner(&settings);
+        settings.curve = SpreadCurve::Center;
+        let center = inner(&settings);
+        settings.curve = SpreadCurve::Edges;
+        let edges = inner(&settings);
+
+        assert!(center < linear && linear < edges);
+        assert!((linear - 15.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,215 @@
+use defmt::{Format, info};
+use libm::{exp2f, sqrtf};
+
+use crate::{adsr::TimeScale, encoder::Rotation};
+
+/// Release velocity of keyboards that don't send one
+const NEUTRAL: f32 = 64.0 / 127.0;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum VelocityCurve {
+    Linear,
+    /// Loud without hitting hard
+    Soft,
+    /// Takes a hard hit to get loud
+    Hard,
+    /// Every note at full velocity
+    Fixed,
+}
+
+impl VelocityCurve {
+    pub fn next(&self) -> Self {
+        match self {
+            VelocityCurve::Linear => VelocityCurve::Soft,
+            VelocityCurve::Soft => VelocityCurve::Hard,
+            VelocityCurve::Hard => VelocityCurve::Fixed,
+            VelocityCurve::Fixed => VelocityCurve::Linear,
+        }
+    }
+
+    pub fn apply(&self, velocity: f32) -> f32 {
+        match self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::Soft => sqrtf(velocity),
+            VelocityCurve::Hard => velocity * velocity,
+            VelocityCurve::Fixed => 1.0,
+        }
+    }
+}
+
+/// How the velocity of a note shapes its level and envelope times. The
+/// time amounts are in `-1.0..=1.0`: at 1, a full velocity halves the time
+/// and the softest note doubles it; negative amounts do the opposite
+pub struct VelocitySettings {
+    pub curve: VelocityCurve,
+    /// `0.0..=1.0`, how much quieter soft notes are
+    pub amp: f32,
+    pub attack: f32,
+    pub decay: f32,
+    /// By the note-off velocity, a quick release shortening it
+    pub release: f32,
+}
+
+impl VelocitySettings {
+    pub const fn init() -> Self {
+        Self {
+            curve: VelocityCurve::Linear,
+            amp: 0.5,
+            attack: 0.0,
+            decay: 0.0,
+            release: 0.0,
+        }
+    }
+
+    /// Gain of a note at `velocity` in `0.0..=1.0`
+    pub fn gain(&self, velocity: f32) -> f32 {
+        1.0 - self.amp * (1.0 - self.curve.apply(velocity))
+    }
+
+    /// Attack and decay times of a note at `velocity`, the release is left
+    /// to the note off
+    pub fn time_scale(&self, velocity: f32) -> TimeScale {
+        let velocity = self.curve.apply(velocity);
+
+        TimeScale {
+            attack: scale(self.attack, velocity),
+            decay: scale(self.decay, velocity),
+            release: 1.0,
+        }
+    }
+
+    /// Release time scale for a note-off `velocity` in `0.0..=1.0`
+    pub fn release_scale(&self, velocity: f32) -> f32 {
+        // around the default note-off velocity rather than the middle
+        let offset = if velocity > NEUTRAL {
+            (velocity - NEUTRAL) / (1.0 - NEUTRAL)
+        } else {
+            (velocity - NEUTRAL) / NEUTRAL
+        };
+
+        exp2f(-self.release * offset)
+    }
+
+    pub fn adjust(&mut self, param: &VelocityParams, rotation: Rotation) {
+        let step = |value: f32, min: f32| {
+            let new = if rotation == Rotation::Right {
+                value + 0.05
+            } else {
+                value - 0.05
+            };
+
+            new.clamp(min, 1.0)
+        };
+
+        match param {
+            VelocityParams::Curve => {
+                self.curve = self.curve.next();
+                info!("Set velocity curve: {}", self.curve);
+            }
+            VelocityParams::Amp => {
+                self.amp = step(self.amp, 0.0);
+                info!("Set velocity to amp: {}", self.amp);
+            }
+            VelocityParams::Attack => {
+                self.attack = step(self.attack, -1.0);
+                info!("Set velocity to attack: {}", self.attack);
+            }
+            VelocityParams::Decay => {
+                self.decay = step(self.decay, -1.0);
+                info!("Set velocity to decay: {}", self.decay);
+            }
+            VelocityParams::Release => {
+                self.release = step(self.release, -1.0);
+                info!("Set release velocity: {}", self.release);
+            }
+        }
+    }
+}
+
+/// `velocity` mapped to `-1.0..=1.0` around the middle, then to a time ratio
+fn scale(amount: f32, velocity: f32) -> f32 {
+    exp2f(-amount * (2.0 * velocity - 1.0))
+}
+
+#[derive(Debug, Format)]
+pub enum VelocityParams {
+    Curve,
+    Amp,
+    Attack,
+    Decay,
+    Release,
+}
+
+impl VelocityParams {
+    pub const fn init_param() -> Self {
+        Self::Curve
+    }
+
+    pub fn next_param(param: &Self) -> Option<Self> {
+        use VelocityParams::*;
+
+        match param {
+            Curve => Some(Amp),
+            Amp => Some(Attack),
+            Attack => Some(Decay),
+            Decay => Some(Release),
+            Release => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves_keep_the_ends() {
+        for curve in [
+            VelocityCurve::Linear,
+            VelocityCurve::Soft,
+            VelocityCurve::Hard,
+        ] {
+            assert_eq!(curve.apply(0.0), 0.0);
+            assert_eq!(curve.apply(1.0), 1.0);
+        }
+
+        assert!(VelocityCurve::Soft.apply(0.5) > 0.5);
+        assert!(VelocityCurve::Hard.apply(0.5) < 0.5);
+        assert_eq!(VelocityCurve::Fixed.apply(0.1), 1.0);
+    }
+
+    #[test]
+    fn sensitivity_sets_the_quietest_gain() {
+        let mut settings = VelocitySettings::init();
+
+        settings.amp = 0.0;
+        assert_eq!(settings.gain(0.0), 1.0);
+        settings.amp = 0.75;
+        assert_eq!(settings.gain(0.0), 0.25);
+        assert_eq!(settings.gain(1.0), 1.0);
+    }
+
+    #[test]
+    fn hard_notes_shorten_the_attack() {
+        let mut settings = VelocitySettings::init();
+        settings.attack = 1.0;
+        settings.decay = -1.0;
+
+        let hard = settings.time_scale(1.0);
+        let soft = settings.time_scale(0.0);
+        assert_eq!((hard.attack, soft.attack), (0.5, 2.0));
+        assert_eq!((hard.decay, soft.decay), (2.0, 0.5));
+        assert_eq!(settings.time_scale(0.5).attack, 1.0);
+    }
+
+    #[test]
+    fn release_velocity_is_neutral_by_default() {
+        let mut settings = VelocitySettings::init();
+        settings.release = 1.0;
+
+        assert_eq!(settings.release_scale(NEUTRAL), 1.0);
+        assert_eq!(settings.release_scale(1.0), 0.5);
+        assert_eq!(settings.release_scale(0.0), 2.0);
+    }
+}
@@ -1,98 +1,2041 @@
 use heapless::Vec;
-use midi_parser::parser::Note;
+use libm::exp2f;
+use midi_parser::parser::{Note, Velocity};
 
 use crate::{
-    adsr::{self, Envelope},
-    consts::MAX_TRACKING_VOICES,
-    oscillator::Oscillator,
+    adsr::{self, AdsrParams, Envelope, Generator, TimeScale},
+    breakpoint::{BreakpointEnvelope, BreakpointParams},
+    consts::{CONTROL_PERIOD, LFOS, MAX_OSCILLATORS, MAX_TRACKING_VOICES, MAX_VOICES, SAMPLE_RATE},
+    drive::{Drive, DriveParams, DrivePlacement},
+    encoder::Rotation,
+    filter::{Filter, FilterModParams, FilterParam},
+    fm::{FmParams, FmVoice, OperatorParams},
+    lfo::{Lfo, LfoMode, LfoParams},
+    mod_matrix::{ModParams, ModSources, ModValues},
+    mono::{MonoParams, MonoSettings, NoteStack, VoiceMode},
+    noise::{Noise, Rng},
+    oscillator::{OscParams, Oscillator},
+    patch::{Engine, MixParams, Patch, StealPolicy},
+    pedal::{Pedals, SOFT_CUTOFF},
+    unison::{UnisonCopy, UnisonParams},
+    velocity::VelocityParams,
+    wavetable::WavetableError,
 };
 
+/// Share of the way to the pitch bend and mod wheel positions covered per
+/// control period, a time constant of about 5 ms
+const CONTROLLER_SMOOTHING: f32 = 0.065;
+
+/// Samples a stolen voice takes to fade out, 2 ms
+const STEAL_FADE: f32 = SAMPLE_RATE * 0.002;
+
+/// Pool-wide values the voices read at control rate
+struct Globals {
+    /// BPM, for synced LFOs
+    tempo: f32,
+    /// Outputs of the global LFOs
+    lfos: [f32; LFOS],
+    /// `0.0..=1.0`
+    mod_wheel: f32,
+    /// Channel aftertouch, `0.0..=1.0`
+    aftertouch: f32,
+    /// `-1.0..=1.0`
+    pitch_bend: f32,
+}
+
+impl Globals {
+    /// Sources for modulating the pool as a whole, without the per-voice ones
+    fn sources(&self) -> ModSources {
+        ModSources {
+            lfos: self.lfos,
+            mod_wheel: self.mod_wheel,
+            aftertouch: self.aftertouch,
+            pitch_bend: self.pitch_bend,
+            ..Default::default()
+        }
+    }
+}
+
+enum Source {
+    Subtractive([Oscillator; MAX_OSCILLATORS]),
+    Fm(FmVoice),
+}
+
+/// ADSR unless the patch has amp stages
+enum AmpEnvelope {
+    Adsr(Envelope),
+    Breakpoint(BreakpointEnvelope),
+}
+
+impl AmpEnvelope {
+    fn generator(&self) -> &dyn Generator {
+        match self {
+            AmpEnvelope::Adsr(env) => env,
+            AmpEnvelope::Breakpoint(env) => env,
+        }
+    }
+
+    fn generator_mut(&mut self) -> &mut dyn Generator {
+        match self {
+            AmpEnvelope::Adsr(env) => env,
+            AmpEnvelope::Breakpoint(env) => env,
+        }
+    }
+}
+
+impl Generator for AmpEnvelope {
+    fn note_on(&mut self) {
+        self.generator_mut().note_on();
+    }
+
+    fn legato(&mut self) {
+        self.generator_mut().legato();
+    }
+
+    fn note_off(&mut self) {
+        self.generator_mut().note_off();
+    }
+
+    fn set_time_scale(&mut self, scale: TimeScale) {
+        self.generator_mut().set_time_scale(scale);
+    }
+
+    fn is_active(&self) -> bool {
+        self.generator().is_active()
+    }
+
+    fn is_released(&self) -> bool {
+        self.generator().is_released()
+    }
+
+    fn value(&self) -> f32 {
+        self.generator().value()
+    }
+
+    fn next(&mut self) -> f32 {
+        self.generator_mut().next()
+    }
+}
+
 struct Voice {
-    envelope: adsr::Envelope,
-    oscillator: Oscillator,
+    note: Note,
+    /// `0.0..=1.0`
+    velocity: f32,
+    /// Envelope times by velocity
+    time_scale: TimeScale,
+    /// Level by velocity, follows the patch at control rate
+    velocity_gain: f32,
+    envelope: AmpEnvelope,
+    source: Source,
+    noise: Noise,
+    filter: Filter,
+    /// Runs when the patch puts the drive in the voices
+    drive: Drive,
+    filter_envelope: Envelope,
+    /// Runs at control rate
+    mod_envelope: BreakpointEnvelope,
+    /// Only the ones in per-voice mode run
+    lfos: [Lfo; LFOS],
+    /// Polyphonic aftertouch, `0.0..=1.0`
+    aftertouch: f32,
+    /// Picked at note on, `-1.0..1.0`
+    random: f32,
+    /// Modulation matrix output of the current control period
+    mods: ModValues,
+    /// Cutoff of the current control period
+    cutoff: f32,
+    /// Left and right, with the pan modulation
+    gains: [f32; 2],
+    control_timer: u32,
+    /// Index in the unison stack of its note
+    copy: usize,
+    unison: UnisonCopy,
+    /// Pool note count at its note on, for telling the oldest
+    started: u32,
+    /// Gain of a stolen voice on its way out
+    fade: Option<f32>,
+    /// Portamento, semitones away from the note
+    glide: f32,
+    /// Semitones the glide moves per control period
+    glide_step: f32,
+    /// Release scale of its note off once the key is up, while the pedals
+    /// may still hold it
+    key_release: Option<f32>,
+    /// Held by the sostenuto pedal
+    latched: bool,
+    /// Played with the soft pedal down
+    soft: bool,
 }
 
 impl Voice {
-    fn new(envelope: Envelope, note: &Note) -> Self {
-        Self {
+    fn new(
+        envelope: Envelope,
+        note: &Note,
+        velocity: f32,
+        patch: &Patch,
+        seed: u32,
+        copy: usize,
+        unison: UnisonCopy,
+    ) -> Self {
+        let source = match patch.engine {
+            Engine::Subtractive => Source::Subtractive(core::array::from_fn(|i| {
+                let mut osc = Oscillator::new(note);
+                osc.apply(&patch.oscillators[i], unison.detune);
+                osc.seed(seed.wrapping_add(i as u32 + 1));
+                osc
+            })),
+            Engine::Fm => {
+                let mut fm = FmVoice::new(note, &patch.fm);
+                fm.detune(unison.detune);
+                Source::Fm(fm)
+            }
+        };
+
+        let lfos = core::array::from_fn(|i| {
+            let mut lfo = Lfo::new(seed.rotate_left(16).wrapping_add(i as u32));
+            if patch.lfos[i].retrigger {
+                lfo.retrigger();
+            } else {
+                lfo.scatter();
+            }
+            lfo
+        });
+
+        let envelope = match &patch.amp_stages {
+            Some(stages) => {
+                AmpEnvelope::Breakpoint(BreakpointEnvelope::new(stages.clone(), SAMPLE_RATE))
+            }
+            None => AmpEnvelope::Adsr(envelope),
+        };
+
+        let mut voice = Self {
+            note: *note,
+            velocity,
+            time_scale: TimeScale::ONE,
+            velocity_gain: patch.velocity.gain(velocity),
             envelope,
-            oscillator: Oscillator::new(note),
-        }
+            source,
+            noise: Noise::new(seed),
+            filter: Filter::new(),
+            drive: Drive::init(),
+            filter_envelope: Envelope::new(patch.filter_mod.envelope.clone(), SAMPLE_RATE),
+            mod_envelope: BreakpointEnvelope::new(
+                patch.mod_envelope.clone(),
+                SAMPLE_RATE / CONTROL_PERIOD as f32,
+            ),
+            lfos,
+            aftertouch: 0.0,
+            random: Rng::new(seed ^ 0x5BD1_E995).next_bipolar(),
+            mods: ModValues::default(),
+            cutoff: patch.filter.cutoff,
+            gains: unison.gains(0.0),
+            control_timer: 0,
+            copy,
+            unison,
+            started: 0,
+            fade: None,
+            glide: 0.0,
+            glide_step: 0.0,
+            key_release: None,
+            latched: false,
+            soft: false,
+        };
+
+        voice.set_velocity(velocity, patch);
+        voice
+    }
+
+    fn set_velocity(&mut self, velocity: f32, patch: &Patch) {
+        self.velocity = velocity;
+        self.time_scale = patch.velocity.time_scale(velocity);
+        self.envelope.set_time_scale(self.time_scale);
+        self.filter_envelope.set_time_scale(self.time_scale);
+    }
+
+    /// Stretches the release by `scale`, for the note-off velocity
+    fn scale_release(&mut self, scale: f32) {
+        self.time_scale.release = scale;
+        self.envelope.set_time_scale(self.time_scale);
+        self.filter_envelope.set_time_scale(self.time_scale);
     }
 
     fn note_on(&mut self) {
-        self.oscillator.start();
+        match &mut self.source {
+            Source::Subtractive(oscillators) => {
+                for osc in oscillators.iter_mut() {
+                    osc.start();
+                }
+            }
+            Source::Fm(fm) => fm.note_on(),
+        }
+        self.envelope.note_on();
+        self.filter_envelope.note_on();
+        self.mod_envelope.note_on();
+        self.control_timer = 0;
+    }
+
+    /// Starts the oscillators at random points of their cycle, so the
+    /// copies of a unison stack don't all start in phase
+    fn scatter_phases(&mut self, rng: &mut Rng) {
+        if let Source::Subtractive(oscillators) = &mut self.source {
+            for osc in oscillators.iter_mut() {
+                osc.phase = (rng.next_bipolar() + 1.0) / 2.0;
+            }
+        }
+    }
+
+    /// Plays its note again without starting over: the envelopes restart
+    /// from their current level and the oscillators run on, so it doesn't
+    /// click
+    fn retrigger(&mut self, velocity: f32, patch: &Patch) {
+        self.set_velocity(velocity, patch);
+        self.key_release = None;
+        if let Source::Fm(fm) = &mut self.source {
+            fm.retrigger();
+        }
+        for (lfo, settings) in self.lfos.iter_mut().zip(patch.lfos.iter()) {
+            if settings.retrigger {
+                lfo.retrigger();
+            }
+        }
         self.envelope.note_on();
+        self.filter_envelope.note_on();
+        self.mod_envelope.note_on();
+    }
+
+    /// Moves over to `note` without restarting, gliding from the pitch it
+    /// is at
+    fn glide_to(&mut self, note: &Note, settings: &MonoSettings) {
+        let from = self.note.num as f32 + self.glide;
+
+        self.note = *note;
+        match &mut self.source {
+            Source::Subtractive(oscillators) => {
+                for osc in oscillators.iter_mut() {
+                    osc.set_note(note);
+                }
+            }
+            Source::Fm(fm) => fm.set_note(note, self.unison.detune),
+        }
+
+        self.glide = from - note.num as f32;
+        let periods = settings.glide_samples(self.glide, SAMPLE_RATE) / CONTROL_PERIOD as f32;
+        if periods < 1.0 {
+            self.glide = 0.0;
+        }
+        self.glide_step = self.glide.abs() / periods.max(1.0);
     }
 
     fn is_active(&self) -> bool {
-        self.envelope.is_active()
+        self.envelope.is_active() && self.fade.is_none_or(|gain| gain > 0.0)
+    }
+
+    /// Sounding and not stolen, so it counts against `MAX_VOICES`
+    fn is_live(&self) -> bool {
+        self.envelope.is_active() && self.fade.is_none()
+    }
+
+    /// Sounding and not yet released
+    fn is_held(&self) -> bool {
+        self.is_live() && !self.envelope.is_released()
+    }
+
+    /// Fades out quickly to make room for another note
+    fn steal(&mut self) {
+        self.fade = Some(1.0);
+    }
+
+    /// Lets go of a key that is up as far as the pedals allow: sostenuto
+    /// and a fully pressed sustain pedal hold it, a partly pressed one
+    /// stretches its release
+    fn follow_pedals(&mut self, pedals: &Pedals) {
+        let Some(release) = self.key_release else {
+            return;
+        };
+        if self.latched || !self.is_live() || (pedals.holds() && !self.envelope.is_released()) {
+            return;
+        }
+
+        self.scale_release(release * pedals.stretch());
+        if !self.envelope.is_released() {
+            self.note_off();
+        }
     }
 
     fn note_off(&mut self) {
+        if let Source::Fm(fm) = &mut self.source {
+            fm.note_off();
+        }
         self.envelope.note_off();
+        self.filter_envelope.note_off();
+        self.mod_envelope.note_off();
+    }
+
+    fn next_sample(&mut self, patch: &Patch, globals: &Globals) -> f32 {
+        let mut sample = match &mut self.source {
+            Source::Subtractive(oscillators) => {
+                if !self.envelope.is_active() && oscillators[0].is_active() {
+                    for osc in oscillators.iter_mut() {
+                        osc.stop();
+                    }
+                }
+
+                mix_oscillators(oscillators, patch)
+            }
+            Source::Fm(_) if !self.envelope.is_active() => 0.0,
+            Source::Fm(fm) => fm.next_sample(&patch.fm),
+        };
+
+        if patch.noise_level != 0.0 && self.envelope.is_active() {
+            sample += patch.noise_level * self.noise.next_sample(patch.noise);
+        }
+
+        let filter_env = self.filter_envelope.next();
+        if self.control_timer == 0 {
+            self.control(patch, globals, filter_env);
+        }
+        self.control_timer = (self.control_timer + 1) % CONTROL_PERIOD;
+
+        let mut amp = self.velocity_gain * (1.0 + self.mods.amp).max(0.0);
+        if let Some(gain) = &mut self.fade {
+            *gain = (*gain - 1.0 / STEAL_FADE).max(0.0);
+            amp *= *gain;
+        }
+
+        let mut sample = self.filter.process_at(sample, self.cutoff);
+        if patch.drive.placement == DrivePlacement::Voice {
+            sample = self.drive.process(sample, &patch.drive);
+        }
+
+        sample * self.envelope.next() * amp
+    }
+
+    /// Evaluates the modulation matrix and applies it, once per control period
+    fn control(&mut self, patch: &Patch, globals: &Globals, filter_env: f32) {
+        let sources = ModSources {
+            lfos: self.next_lfos(patch, globals),
+            amp_envelope: self.envelope.value(),
+            filter_envelope: filter_env,
+            mod_envelope: self.mod_envelope.next(),
+            velocity: self.velocity,
+            key: (self.note.num as f32 - 60.0) / 60.0,
+            aftertouch: globals.aftertouch.max(self.aftertouch),
+            random: self.random,
+            ..globals.sources()
+        };
+        self.mods = patch.matrix.evaluate(&sources);
+        self.velocity_gain = patch.velocity.gain(self.velocity);
+
+        self.glide = if self.glide > 0.0 {
+            (self.glide - self.glide_step).max(0.0)
+        } else {
+            (self.glide + self.glide_step).min(0.0)
+        };
+
+        let bend = if globals.pitch_bend > 0.0 {
+            globals.pitch_bend * patch.bend_up
+        } else {
+            globals.pitch_bend * patch.bend_down
+        };
+
+        let pitch = exp2f((self.mods.pitch + self.glide + bend) / 12.0);
+        match &mut self.source {
+            Source::Subtractive(oscillators) => {
+                for (osc, settings) in oscillators.iter_mut().zip(patch.oscillators.iter()) {
+                    osc.set_pitch_mod(pitch);
+                    osc.duty = (settings.duty + self.mods.duty).clamp(0.05, 0.95);
+                }
+            }
+            Source::Fm(fm) => fm.set_pitch_mod(pitch),
+        }
+
+        self.filter.follow(&patch.filter);
+        self.filter.resonance = (patch.filter.resonance + self.mods.resonance).clamp(0.0, 1.0);
+        self.cutoff = patch.filter_mod.cutoff(
+            patch.filter.cutoff,
+            filter_env,
+            self.note.freq,
+            self.velocity,
+        ) * exp2f(self.mods.cutoff);
+        if self.soft {
+            self.cutoff *= SOFT_CUTOFF;
+        }
+
+        self.gains = self.unison.gains(self.mods.pan);
+    }
+
+    /// Runs the per-voice LFOs a control period on, at the rates of the
+    /// last period. The global ones come from the pool
+    fn next_lfos(&mut self, patch: &Patch, globals: &Globals) -> [f32; LFOS] {
+        core::array::from_fn(|i| match patch.lfos[i].mode {
+            LfoMode::PerVoice => {
+                let rate_mod = exp2f(self.mods.lfo_rates[i]);
+                self.lfos[i].advance(&patch.lfos[i], globals.tempo, rate_mod, CONTROL_PERIOD)
+            }
+            LfoMode::Global => globals.lfos[i],
+        })
     }
 
-    fn next_sample(&mut self) -> f32 {
-        if !self.envelope.is_active() && self.oscillator.is_active() {
-            self.oscillator.stop();
+    fn apply(&mut self, patch: &Patch) {
+        if let Source::Subtractive(oscillators) = &mut self.source {
+            for (osc, settings) in oscillators.iter_mut().zip(patch.oscillators.iter()) {
+                osc.apply(settings, self.unison.detune);
+            }
+        }
+    }
+}
+
+fn mix_oscillators(oscillators: &mut [Oscillator; MAX_OSCILLATORS], patch: &Patch) -> f32 {
+    // osc 1 and 2 have to run even when silent if they're synced or ring modulated
+    let coupled = patch.hard_sync || patch.ring_mod != 0.0;
+    let mut samples = [0.0; MAX_OSCILLATORS];
+
+    for (i, osc) in oscillators.iter_mut().enumerate() {
+        if patch.oscillators[i].level != 0.0 || (coupled && i < 2) {
+            samples[i] = osc.next_sample(&patch.user_wavetable);
         }
+    }
+
+    if patch.hard_sync && oscillators[0].wrapped() {
+        let (master, slave) = oscillators.split_at_mut(1);
+        slave[0].sync_to(&master[0]);
+    }
 
-        self.oscillator.next_sample() * self.envelope.next()
+    let mut mix = patch.ring_mod * samples[0] * samples[1];
+    for (sample, settings) in samples.iter().zip(patch.oscillators.iter()) {
+        mix += sample * settings.level;
     }
+
+    mix
 }
 
 pub struct VoicePool {
+    /// Up to `MAX_VOICES` live ones, the rest stolen ones fading out
     voices: Vec<Voice, MAX_TRACKING_VOICES>,
-    next_voice_index: usize,
+    /// Note ons so far, for telling the oldest voice
+    note_count: u32,
+    /// Keys down in mono and legato
+    held: NoteStack,
+    pedals: Pedals,
+    /// Where the pitch bend and mod wheel are, `globals` follows smoothly
+    pitch_bend: f32,
+    mod_wheel: f32,
     envelope: adsr::Envelope,
+    patch: Patch,
+    /// Seeds the noise of every new voice, so renders are reproducible
+    /// while voices don't share the same noise
+    next_seed: u32,
+    /// Only the ones in global mode run
+    lfos: [Lfo; LFOS],
+    globals: Globals,
+    control_timer: u32,
+    /// Left and right, when the patch puts the drive on the mix
+    master_drive: [Drive; 2],
 }
 
 impl VoicePool {
     pub const fn new(envelope: adsr::Envelope) -> Self {
         Self {
             voices: Vec::new(),
-            next_voice_index: 0,
+            note_count: 0,
+            held: NoteStack::new(),
+            pedals: Pedals::init(),
+            pitch_bend: 0.0,
+            mod_wheel: 0.0,
             envelope,
+            patch: Patch::init(),
+            next_seed: 1,
+            lfos: [Lfo::new(1), Lfo::new(2)],
+            globals: Globals {
+                tempo: 120.0,
+                lfos: [0.0; LFOS],
+                mod_wheel: 0.0,
+                aftertouch: 0.0,
+                pitch_bend: 0.0,
+            },
+            control_timer: 0,
+            master_drive: [const { Drive::init() }; 2],
+        }
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.globals.tempo = bpm;
+    }
+
+    /// `0.0..=1.0`
+    pub fn set_mod_wheel(&mut self, value: f32) {
+        self.mod_wheel = value;
+    }
+
+    /// Channel aftertouch, `0.0..=1.0`
+    pub fn set_aftertouch(&mut self, value: f32) {
+        self.globals.aftertouch = value;
+    }
+
+    /// Polyphonic aftertouch of `note`, `0.0..=1.0`
+    pub fn on_poly_aftertouch(&mut self, note: &Note, value: f32) {
+        for v in self.voices.iter_mut().filter(|v| v.note == *note) {
+            v.aftertouch = value;
+        }
+    }
+
+    /// `-1.0..=1.0`, scaled by the bend range of the patch
+    pub fn set_pitch_bend(&mut self, value: f32) {
+        self.pitch_bend = value;
+    }
+
+    /// Sustain pedal, `0.0..=1.0`. In between, the notes let go ring longer
+    /// the further down it is
+    pub fn set_sustain(&mut self, value: f32) {
+        self.pedals.sustain = value;
+
+        for v in self.voices.iter_mut() {
+            v.follow_pedals(&self.pedals);
+        }
+    }
+
+    /// Pressing it holds the notes down at that moment until it comes up
+    pub fn set_sostenuto(&mut self, down: bool) {
+        if down == self.pedals.sostenuto {
+            return;
+        }
+        self.pedals.sostenuto = down;
+
+        for v in self.voices.iter_mut() {
+            v.latched = down && v.is_held() && v.key_release.is_none();
+            v.follow_pedals(&self.pedals);
         }
     }
 
+    /// Notes played while it's down are quieter and darker
+    pub fn set_soft(&mut self, down: bool) {
+        self.pedals.soft = down;
+    }
+
     pub fn is_active(&self) -> bool {
         self.voices.iter().find(|v| v.is_active()).is_some()
     }
 
+    /// Mono downmix of [`Self::next_sample_stereo`]
     pub fn next_sample(&mut self) -> f32 {
-        let mut total: f32 = 0.0;
+        let (left, right) = self.next_sample_stereo();
+        (left + right) / 2.0
+    }
+
+    pub fn next_sample_stereo(&mut self) -> (f32, f32) {
+        if self.control_timer == 0 {
+            let globals = &mut self.globals;
+            globals.pitch_bend += (self.pitch_bend - globals.pitch_bend) * CONTROLLER_SMOOTHING;
+            globals.mod_wheel += (self.mod_wheel - globals.mod_wheel) * CONTROLLER_SMOOTHING;
+
+            // global LFO rates can only follow the global sources
+            let mods = self.patch.matrix.evaluate(&self.globals.sources());
+
+            for (i, lfo) in self.lfos.iter_mut().enumerate() {
+                let settings = &self.patch.lfos[i];
+                if settings.mode == LfoMode::Global {
+                    let rate_mod = exp2f(mods.lfo_rates[i]);
+                    self.globals.lfos[i] =
+                        lfo.advance(settings, self.globals.tempo, rate_mod, CONTROL_PERIOD);
+                }
+            }
+        }
+        self.control_timer = (self.control_timer + 1) % CONTROL_PERIOD;
+
+        let (mut left, mut right) = (0.0, 0.0);
 
         for v in self.voices.iter_mut() {
-            total += v.next_sample();
+            let sample = v.next_sample(&self.patch, &self.globals);
+            left += sample * v.gains[0];
+            right += sample * v.gains[1];
+        }
+
+        let drive = &self.patch.drive;
+        if drive.placement == DrivePlacement::Master {
+            let [drive_left, drive_right] = &mut self.master_drive;
+            left = drive_left.process(left, drive);
+            right = drive_right.process(right, drive);
         }
 
-        total
+        (left, right)
     }
 
-    pub fn on_note_on(&mut self, note: &Note) {
-        let mut voice = Voice::new(self.envelope.clone(), note);
-        voice.note_on();
+    pub fn on_note_on(&mut self, note: &Note, velocity: &Velocity) {
+        let velocity = self.pedals.soften(velocity.0 as f32 / 127.0);
 
-        if self.voices.len() <= self.next_voice_index {
-            self.voices.push(voice).ok(); // todo handle Result
-        } else {
-            self.voices[self.next_voice_index] = voice;
+        if self.patch.mono.mode == VoiceMode::Poly {
+            self.start_note(note, velocity);
+            return;
+        }
+
+        let overlapping = !self.held.is_empty();
+        self.held.push(note, velocity);
+        if let Some((current, velocity)) = self.held.current(self.patch.mono.priority) {
+            self.play_mono(&current, velocity, overlapping);
+        }
+    }
+
+    /// Moves the sounding voices over to `note`, or starts it if there are
+    /// none. `overlapping` is for notes played, or returned to, while other
+    /// keys are down, which legato doesn't retrigger
+    fn play_mono(&mut self, note: &Note, velocity: f32, overlapping: bool) {
+        if overlapping && self.voices.iter().any(|v| v.is_held() && v.note == *note) {
+            // already playing it, like a low note held under a higher one
+            return;
+        }
+
+        let legato = overlapping && self.patch.mono.mode == VoiceMode::Legato;
+        let mut sounding = false;
+
+        for v in self.voices.iter_mut().filter(|v| v.is_live()) {
+            v.glide_to(note, &self.patch.mono);
+            if !legato {
+                v.retrigger(velocity, &self.patch);
+                v.soft = self.pedals.soft;
+            }
+            sounding = true;
+        }
+
+        if !sounding {
+            self.start_note(note, velocity);
+        } else if !legato {
+            self.retrigger_lfos();
         }
+    }
+
+    fn retrigger_lfos(&mut self) {
+        for (lfo, settings) in self.lfos.iter_mut().zip(self.patch.lfos.iter()) {
+            if settings.mode == LfoMode::Global && settings.retrigger {
+                lfo.retrigger();
+            }
+        }
+    }
+
+    /// Starts a unison stack of `note`, or retriggers the one playing it
+    fn start_note(&mut self, note: &Note, velocity: f32) {
+        let count = self.unison_count(note);
+        self.shrink_stacks(note, count);
+        self.retrigger_lfos();
+
+        self.note_count = self.note_count.wrapping_add(1);
+
+        for copy in 0..count {
+            let unison = self.patch.unison.copy(copy, count);
 
-        self.next_voice_index += 1;
+            let playing = self
+                .voices
+                .iter_mut()
+                .find(|v| v.is_live() && v.note == *note && v.copy == copy);
+            if let Some(v) = playing {
+                if v.unison == unison {
+                    v.retrigger(velocity, &self.patch);
+                    v.soft = self.pedals.soft;
+                    v.started = self.note_count;
+                    continue;
+                }
+                // the stack changed size, this copy sits somewhere else now
+                v.steal();
+            }
+
+            // spaced apart so the oscillators of a voice get seeds of their own
+            self.next_seed = self.next_seed.wrapping_add(MAX_OSCILLATORS as u32 + 1);
+            let mut voice = Voice::new(
+                self.envelope.clone(),
+                note,
+                velocity,
+                &self.patch,
+                self.next_seed,
+                copy,
+                unison,
+            );
+            voice.started = self.note_count;
+            voice.soft = self.pedals.soft;
+            voice.note_on();
+
+            if count > 1 {
+                voice.scatter_phases(&mut Rng::new(!self.next_seed));
+            }
 
-        if self.next_voice_index >= MAX_TRACKING_VOICES {
-            self.next_voice_index -= MAX_TRACKING_VOICES;
+            self.allocate(voice);
+        }
+
+        // copies past the end of a smaller stack
+        for v in self.voices.iter_mut() {
+            if v.is_live() && v.note == *note && v.copy >= count {
+                v.steal();
+            }
         }
     }
 
-    pub fn on_note_off(&mut self, note: &Note) {
+    /// Unison copies for a new note. The held notes share `MAX_VOICES`, so
+    /// stacks get smaller as chords get bigger, down to a single voice per
+    /// note rather than dropping notes
+    fn unison_count(&self, note: &Note) -> usize {
+        let held_notes = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(|(i, v)| {
+                v.is_held()
+                    && v.note != *note
+                    && !self.voices[..*i]
+                        .iter()
+                        .any(|other| other.is_held() && other.note == v.note)
+            })
+            .count();
+
+        (MAX_VOICES / (held_notes + 1)).clamp(1, self.patch.unison.voices)
+    }
+
+    /// Releases the copies of held notes past `count`, making room for a
+    /// new stack. They fade out with the release of the amp envelope
+    fn shrink_stacks(&mut self, note: &Note, count: usize) {
         for v in self.voices.iter_mut() {
-            if v.oscillator.note == *note && v.is_active() {
+            if v.is_held() && v.note != *note && v.copy >= count {
                 v.note_off();
             }
         }
     }
+
+    /// Puts `voice` in an idle slot, first stealing a live voice if
+    /// `MAX_VOICES` are already sounding
+    fn allocate(&mut self, voice: Voice) {
+        if self.voices.iter().filter(|v| v.is_live()).count() >= MAX_VOICES
+            && let Some(victim) = self.victim()
+        {
+            self.voices[victim].steal();
+        }
+
+        if let Some(idle) = self.voices.iter().position(|v| !v.is_active()) {
+            self.voices[idle] = voice;
+        } else if let Err(voice) = self.voices.push(voice) {
+            // every slot is fading, cut short the one closest to silence
+            let quietest = self
+                .voices
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.fade.unwrap_or(1.0).total_cmp(&b.fade.unwrap_or(1.0)))
+                .map_or(0, |(i, _)| i);
+            self.voices[quietest] = voice;
+        }
+    }
+
+    /// The live voice to make room for a new note, by the steal policy
+    fn victim(&self) -> Option<usize> {
+        let live = self.voices.iter().enumerate().filter(|(_, v)| v.is_live());
+        let age = |v: &Voice| self.note_count.wrapping_sub(v.started);
+
+        let victim = match self.patch.steal {
+            StealPolicy::Oldest => live.max_by_key(|(_, v)| age(v)),
+            StealPolicy::Quietest => {
+                live.min_by(|(_, a), (_, b)| a.envelope.value().total_cmp(&b.envelope.value()))
+            }
+            StealPolicy::ReleasedFirst => {
+                live.max_by_key(|(_, v)| (v.envelope.is_released(), age(v)))
+            }
+        };
+
+        victim.map(|(i, _)| i)
+    }
+
+    pub fn on_note_off(&mut self, note: &Note, velocity: &Velocity) {
+        // a note on at velocity 0 is a note off without a velocity
+        let release = match velocity.0 {
+            0 => 1.0,
+            velocity => self.patch.velocity.release_scale(velocity as f32 / 127.0),
+        };
+
+        if self.patch.mono.mode != VoiceMode::Poly {
+            self.held.remove(note);
+            if let Some((current, velocity)) = self.held.current(self.patch.mono.priority) {
+                self.play_mono(&current, velocity, true);
+                return;
+            }
+        }
+
+        // in mono, whatever is sounding goes with the last key
+        let mono = self.patch.mono.mode != VoiceMode::Poly;
+        for v in self.voices.iter_mut() {
+            if (v.note == *note || mono) && v.is_live() {
+                v.key_release = Some(release);
+                v.follow_pedals(&self.pedals);
+            }
+        }
+    }
+
+    /// Lets go of every key down, as at the default note-off velocity. For
+    /// when the keys start going elsewhere and their note-offs won't come
+    pub fn release_keys(&mut self) {
+        self.held.clear();
+
+        for v in self.voices.iter_mut() {
+            if v.is_live() && v.key_release.is_none() {
+                v.key_release = Some(1.0);
+                v.follow_pedals(&self.pedals);
+            }
+        }
+    }
+
+    /// Edits an oscillator of the patch, sounding voices follow the change
+    pub fn adjust_osc(&mut self, osc: usize, param: &OscParams, rotation: Rotation) {
+        self.patch.adjust_osc(osc, param, rotation);
+
+        for v in self.voices.iter_mut() {
+            v.apply(&self.patch);
+        }
+    }
+
+    /// User frames for the oscillators on `TableSource::User`
+    pub fn load_wavetable(&mut self, data: &[u8]) -> Result<(), WavetableError> {
+        self.patch.load_wavetable(data)
+    }
+
+    pub fn adjust_mix(&mut self, param: &MixParams, rotation: Rotation) {
+        self.patch.adjust(param, rotation);
+    }
+
+    /// FM settings are read by the voices every sample, so there's nothing
+    /// to push to them. Operator envelopes apply from the next note on
+    pub fn adjust_fm(&mut self, param: &FmParams, rotation: Rotation) {
+        self.patch.adjust_fm(param, rotation);
+    }
+
+    pub fn adjust_operator(&mut self, op: usize, param: &OperatorParams, rotation: Rotation) {
+        self.patch.fm.adjust_operator(op, param, rotation);
+    }
+
+    pub fn adjust_filter(&mut self, param: &FilterParam, rotation: Rotation) {
+        self.patch.adjust_filter(param, rotation);
+    }
+
+    pub fn adjust_filter_mod(&mut self, param: &FilterModParams, rotation: Rotation) {
+        self.patch.adjust_filter_mod(param, rotation);
+    }
+
+    /// Applies to the notes already playing
+    pub fn adjust_envelope(&mut self, param: &AdsrParams, rotation: Rotation) {
+        let mut config = self.envelope.config().clone();
+        config.adjust(param, rotation);
+
+        self.envelope.set_config(&config);
+        for v in self.voices.iter_mut() {
+            if let AmpEnvelope::Adsr(env) = &mut v.envelope {
+                env.set_config(&config);
+            }
+        }
+    }
+
+    /// Applies to the notes already playing on amp stages, the switch
+    /// between them and the ADSR from the next note on
+    pub fn adjust_amp_stages(
+        &mut self,
+        stage: usize,
+        param: &BreakpointParams,
+        rotation: Rotation,
+    ) {
+        self.patch.adjust_amp_stages(stage, param, rotation);
+
+        if let Some(stages) = &self.patch.amp_stages {
+            for v in self.voices.iter_mut() {
+                if let AmpEnvelope::Breakpoint(env) = &mut v.envelope {
+                    env.set_config(stages);
+                }
+            }
+        }
+    }
+
+    /// Applies to the notes already playing
+    pub fn adjust_mod_envelope(
+        &mut self,
+        stage: usize,
+        param: &BreakpointParams,
+        rotation: Rotation,
+    ) {
+        self.patch.adjust_mod_envelope(stage, param, rotation);
+
+        for v in self.voices.iter_mut() {
+            v.mod_envelope.set_config(&self.patch.mod_envelope);
+        }
+    }
+
+    /// Applies to the notes already playing
+    pub fn adjust_filter_envelope(&mut self, param: &AdsrParams, rotation: Rotation) {
+        let config = &mut self.patch.filter_mod.envelope;
+        config.adjust(param, rotation);
+
+        for v in self.voices.iter_mut() {
+            v.filter_envelope.set_config(config);
+        }
+    }
+
+    pub fn adjust_lfo(&mut self, lfo: usize, param: &LfoParams, rotation: Rotation) {
+        self.patch.adjust_lfo(lfo, param, rotation);
+    }
+
+    pub fn adjust_mod(&mut self, slot: usize, param: &ModParams, rotation: Rotation) {
+        self.patch.adjust_mod(slot, param, rotation);
+    }
+
+    /// Takes effect from the next note on
+    pub fn adjust_unison(&mut self, param: &UnisonParams, rotation: Rotation) {
+        self.patch.adjust_unison(param, rotation);
+    }
+
+    /// Releases every sounding note, pedals or not
+    fn release_all(&mut self) {
+        for v in self.voices.iter_mut().filter(|v| v.is_live()) {
+            v.note_off();
+        }
+    }
+
+    /// Envelope times apply from the next note on, levels to the notes
+    /// playing
+    pub fn adjust_velocity(&mut self, param: &VelocityParams, rotation: Rotation) {
+        self.patch.adjust_velocity(param, rotation);
+    }
+
+    pub fn adjust_drive(&mut self, param: &DriveParams, rotation: Rotation) {
+        self.patch.adjust_drive(param, rotation);
+    }
+
+    /// Changing the mode releases the notes playing
+    pub fn adjust_mono(&mut self, param: &MonoParams, rotation: Rotation) {
+        self.patch.adjust_mono(param, rotation);
+
+        if let MonoParams::Mode = param {
+            self.held.clear();
+            self.release_all();
+        }
+    }
+
+    pub fn switch_steal_policy(&mut self) {
+        self.patch.switch_steal_policy();
+    }
+
+    pub fn switch_engine(&mut self) {
+        self.patch.switch_engine();
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use midi_parser::parser::{MidiChannel, MidiMessage, MidiParser};
+
+    use super::*;
+    use crate::{
+        adsr::{Adsr, Curve, TimeMs},
+        breakpoint::{Breakpoints, Stage},
+        consts::MAX_UNISON,
+        lfo::LfoShape,
+        mod_matrix::{ModDestination, ModSlot, ModSource},
+        mono::{GlideMode, NotePriority},
+        oscillator::WaveType,
+        wavetable::TableSource,
+    };
+
+    fn envelope() -> Envelope {
+        let config = Adsr {
+            attack: TimeMs(5),
+            decay: TimeMs(5),
+            release: TimeMs(50),
+            sustain_level: 0.5,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
+        };
+
+        Envelope::new(config, SAMPLE_RATE)
+    }
+
+    fn voice(patch: &Patch) -> Voice {
+        let mut voice = Voice::new(
+            envelope(),
+            &Note::new(45),
+            1.0,
+            patch,
+            1,
+            0,
+            UnisonCopy::CENTER,
+        );
+        voice.note_on();
+        voice
+    }
+
+    fn globals() -> Globals {
+        Globals {
+            tempo: 120.0,
+            lfos: [0.0; LFOS],
+            mod_wheel: 0.0,
+            aftertouch: 0.0,
+            pitch_bend: 0.0,
+        }
+    }
+
+    fn oscillators(voice: &Voice) -> &[Oscillator; MAX_OSCILLATORS] {
+        match &voice.source {
+            Source::Subtractive(oscillators) => oscillators,
+            Source::Fm(_) => panic!("not a subtractive voice"),
+        }
+    }
+
+    #[test]
+    fn pitch_offsets_set_osc_frequency() {
+        let mut patch = Patch::init();
+        patch.oscillators[1].octave = 1;
+        patch.oscillators[2].semitone = -12;
+        patch.oscillators[2].cents = 50;
+
+        let voice = voice(&patch);
+        let oscillators = oscillators(&voice);
+        let base = oscillators[0].phase_inc;
+
+        assert!((oscillators[1].phase_inc / base - 2.0).abs() < 1e-5);
+        assert!((oscillators[2].phase_inc / base - 0.5 * 2f32.powf(0.5 / 12.0)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn hard_sync_restarts_osc2_with_osc1() {
+        let mut patch = Patch::init();
+        patch.oscillators[0].level = 0.0;
+        patch.oscillators[1].wave = WaveType::Sine;
+        patch.oscillators[1].semitone = 7;
+        patch.oscillators[1].level = 1.0;
+        patch.oscillators[2].level = 0.0;
+        patch.hard_sync = true;
+
+        let mut voice = voice(&patch);
+        let Source::Subtractive(oscillators) = &mut voice.source else {
+            panic!("not a subtractive voice");
+        };
+
+        // osc 2 cut into cycles at the wraps of osc 1
+        let mut cycles = std::vec![std::vec::Vec::new()];
+        for _ in 0..10_000 {
+            let sample = mix_oscillators(oscillators, &patch);
+            cycles.last_mut().unwrap().push(sample);
+
+            if oscillators[0].wrapped() {
+                cycles.push(std::vec::Vec::new());
+            }
+        }
+
+        // the first and last ones are partial
+        let cycles = &cycles[1..cycles.len() - 1];
+        assert!(cycles.len() > 2);
+
+        // the wraps land between samples, so cycles match within the step
+        // of osc 2 over a sample
+        for pair in cycles.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            assert!(a.len().abs_diff(b.len()) <= 1);
+            assert!(a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 0.02));
+        }
+    }
+
+    #[test]
+    fn ring_mod_multiplies_osc1_and_osc2() {
+        let mut patch = Patch::init();
+        patch.oscillators[0].wave = WaveType::Sine;
+        patch.oscillators[0].level = 0.0;
+        patch.oscillators[1].wave = WaveType::Square;
+        patch.oscillators[1].octave = 1;
+        patch.ring_mod = 1.0;
+
+        let mut voice = voice(&patch);
+
+        let note = Note::new(45);
+        let mut osc1 = Oscillator::new(&note);
+        let mut osc2 = Oscillator::new(&note);
+        osc1.apply(&patch.oscillators[0], 1.0);
+        osc2.apply(&patch.oscillators[1], 1.0);
+        osc1.start();
+        osc2.start();
+        let mut env = envelope();
+        env.note_on();
+        let mut filter = Filter::new();
+
+        for _ in 0..2_000 {
+            let user = &patch.user_wavetable;
+            let ring = osc1.next_sample(user) * osc2.next_sample(user);
+            let expected = filter.process(ring) * env.next();
+            assert_eq!(voice.next_sample(&patch, &globals()), expected);
+        }
+    }
+
+    #[test]
+    fn filter_envelope_sweeps_the_cutoff() {
+        let mut patch = Patch::init();
+        patch.filter.cutoff = 500.0;
+        patch.filter_mod.env_amount = 3.0;
+
+        let mut opening = voice(&patch);
+        let mut cutoffs = std::vec::Vec::new();
+
+        for _ in 0..(SAMPLE_RATE * 0.4) as usize {
+            opening.next_sample(&patch, &globals());
+            cutoffs.push(opening.cutoff);
+        }
+
+        let peak = cutoffs.iter().fold(0.0f32, |acc, c| acc.max(*c));
+        let sustain = *cutoffs.last().unwrap();
+        let sustain_level = patch.filter_mod.envelope.sustain_level;
+
+        assert!((peak / 4_000.0 - 1.0).abs() < 0.05, "peak {peak}");
+        assert!((sustain / (500.0 * 8f32.powf(sustain_level)) - 1.0).abs() < 0.01);
+
+        // negative amounts close the filter instead
+        patch.filter_mod.env_amount = -3.0;
+        let mut closing = voice(&patch);
+        for _ in 0..(SAMPLE_RATE * 0.01) as usize {
+            closing.next_sample(&patch, &globals());
+        }
+        assert!(closing.cutoff < 100.0, "{}", closing.cutoff);
+    }
+
+    #[test]
+    fn velocity_and_key_open_the_filter() {
+        let cutoff_for = |num: u8, velocity: u8| {
+            let mut pool = VoicePool::new(envelope());
+            pool.patch.filter.cutoff = 1_000.0;
+            pool.patch.filter_mod.key_tracking = 1.0;
+            pool.patch.filter_mod.velocity = 2.0;
+
+            pool.on_note_on(&Note::new(num), &Velocity(velocity));
+            pool.next_sample();
+            pool.voices[0].cutoff
+        };
+
+        let soft = cutoff_for(60, 0);
+        assert!((soft - 1_000.0).abs() < 1.0, "{soft}");
+        assert!((cutoff_for(60, 127) / soft - 4.0).abs() < 0.01);
+        assert!((cutoff_for(72, 0) / soft - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn fm_voices_are_gated_by_the_amp_envelope() {
+        let mut pool = VoicePool::new(envelope());
+        pool.switch_engine();
+
+        let note = Note::new(60);
+        pool.on_note_on(&note, &Velocity(100));
+        assert!((0..1_000).map(|_| pool.next_sample()).any(|s| s != 0.0));
+
+        pool.on_note_off(&note, &Velocity(64));
+        // 50 ms release
+        for _ in 0..(SAMPLE_RATE * 0.06) as usize {
+            pool.next_sample();
+        }
+
+        assert!(!pool.is_active());
+        assert_eq!(pool.next_sample(), 0.0);
+    }
+
+    #[test]
+    fn noise_renders_are_reproducible() {
+        let render = || {
+            let mut pool = VoicePool::new(envelope());
+            pool.patch.oscillators[0].level = 0.0;
+            pool.patch.noise_level = 1.0;
+
+            pool.on_note_on(&Note::new(60), &Velocity(100));
+            pool.on_note_on(&Note::new(64), &Velocity(100));
+
+            (0..1_000)
+                .map(|_| pool.next_sample())
+                .collect::<std::vec::Vec<f32>>()
+        };
+
+        let samples = render();
+        assert!(samples.iter().any(|s| *s != 0.0));
+        assert_eq!(samples, render());
+    }
+
+    #[test]
+    fn user_wavetable_plays_once_loaded() {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.oscillators[0].wave = WaveType::Wavetable;
+        // basic, pulses, user
+        pool.adjust_osc(0, &OscParams::Table, Rotation::Right);
+        pool.adjust_osc(0, &OscParams::Table, Rotation::Right);
+        assert!(pool.patch.oscillators[0].wavetable == TableSource::User);
+
+        let render = |pool: &mut VoicePool| {
+            pool.on_note_on(&Note::new(57), &Velocity(100));
+            (0..1_000)
+                .map(|_| pool.next_sample())
+                .collect::<std::vec::Vec<f32>>()
+        };
+        assert!(render(&mut pool).iter().all(|s| *s == 0.0));
+
+        // a single frame of a saw
+        let mut data = std::vec![1];
+        for n in 0..64 {
+            let sample = (n as f32 / 32.0 - 1.0) * i16::MAX as f32;
+            data.extend_from_slice(&(sample as i16).to_le_bytes());
+        }
+        pool.load_wavetable(&data).unwrap();
+        assert!(render(&mut pool).iter().any(|s| *s != 0.0));
+    }
+
+    fn held_copies(pool: &VoicePool, note: &Note) -> usize {
+        pool.voices
+            .iter()
+            .filter(|v| v.is_held() && v.note == *note)
+            .count()
+    }
+
+    #[test]
+    fn unison_stacks_detuned_copies() {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.unison.voices = 4;
+
+        let note = Note::new(57);
+        pool.on_note_on(&note, &Velocity(100));
+        assert_eq!(held_copies(&pool, &note), 4);
+
+        let copies: std::vec::Vec<&Oscillator> =
+            pool.voices.iter().map(|v| &oscillators(v)[0]).collect();
+
+        for (i, a) in copies.iter().enumerate() {
+            for b in &copies[i + 1..] {
+                assert_ne!(a.phase_inc, b.phase_inc);
+                assert_ne!(a.phase, b.phase);
+            }
+        }
+
+        pool.on_note_off(&note, &Velocity(64));
+        assert_eq!(held_copies(&pool, &note), 0);
+    }
+
+    #[test]
+    fn chords_share_the_voice_budget() {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.unison.voices = 4;
+
+        let notes: std::vec::Vec<Note> = (60..60 + MAX_VOICES as u8).map(Note::new).collect();
+
+        for (i, note) in notes.iter().enumerate() {
+            pool.on_note_on(note, &Velocity(100));
+
+            let held = pool.voices.iter().filter(|v| v.is_held()).count();
+            assert!(held <= MAX_VOICES, "{held} voices held");
+            assert!(notes[..=i].iter().all(|n| held_copies(&pool, n) >= 1));
+        }
+
+        assert!(notes.iter().all(|n| held_copies(&pool, n) == 1));
+    }
+
+    #[test]
+    fn full_stacks_keep_every_held_note() {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.unison.voices = MAX_UNISON;
+
+        let mut held: std::vec::Vec<Note> = (60..64).map(Note::new).collect();
+        held.iter().for_each(|n| pool.on_note_on(n, &Velocity(100)));
+
+        // trilling the top note keeps 4 keys down
+        for next in 64..72 {
+            let released = held.pop().unwrap();
+            pool.on_note_off(&released, &Velocity(64));
+            let note = Note::new(next);
+            pool.on_note_on(&note, &Velocity(100));
+            held.push(note);
+
+            assert!(held.iter().all(|n| held_copies(&pool, n) >= 1));
+        }
+    }
+
+    #[test]
+    fn unison_spreads_the_stack_in_stereo() {
+        let render = |spread: f32| {
+            let mut pool = VoicePool::new(envelope());
+            pool.patch.unison.voices = 2;
+            pool.patch.unison.stereo_spread = spread;
+            pool.on_note_on(&Note::new(57), &Velocity(100));
+
+            (0..2_000)
+                .map(|_| pool.next_sample_stereo())
+                .collect::<std::vec::Vec<(f32, f32)>>()
+        };
+
+        assert!(render(0.0).iter().all(|(l, r)| l == r));
+        assert!(render(1.0).iter().any(|(l, r)| (l - r).abs() > 0.1));
+    }
+
+    /// Cutoffs of two voices started together, over a second
+    fn lfo_cutoffs(mode: LfoMode, retrigger: bool) -> std::vec::Vec<(f32, f32)> {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.filter.cutoff = 1_000.0;
+        // an octave either way
+        pool.patch.matrix.slots[0] = ModSlot {
+            source: ModSource::Lfo1,
+            destination: ModDestination::Cutoff,
+            depth: 0.25,
+            via: None,
+        };
+        pool.patch.lfos[0].mode = mode;
+        pool.patch.lfos[0].retrigger = retrigger;
+
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+        pool.on_note_on(&Note::new(64), &Velocity(100));
+
+        (0..SAMPLE_RATE as usize)
+            .map(|_| {
+                pool.next_sample();
+                (pool.voices[0].cutoff, pool.voices[1].cutoff)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lfo_modulates_the_cutoff() {
+        let cutoffs = lfo_cutoffs(LfoMode::PerVoice, true);
+        let (min, max) = cutoffs
+            .iter()
+            .fold((f32::MAX, 0.0f32), |(min, max), (c, _)| {
+                (min.min(*c), max.max(*c))
+            });
+
+        // an octave either way
+        assert!(
+            (min - 500.0).abs() < 5.0 && (max - 2_000.0).abs() < 20.0,
+            "{min} {max}"
+        );
+    }
+
+    #[test]
+    fn global_lfo_is_shared_by_the_voices() {
+        assert!(
+            lfo_cutoffs(LfoMode::Global, true)
+                .iter()
+                .all(|(a, b)| a == b)
+        );
+        assert!(
+            lfo_cutoffs(LfoMode::PerVoice, true)
+                .iter()
+                .all(|(a, b)| a == b)
+        );
+        // free-running voice LFOs start apart
+        assert!(
+            lfo_cutoffs(LfoMode::PerVoice, false)
+                .iter()
+                .any(|(a, b)| a != b)
+        );
+    }
+
+    #[test]
+    fn mod_wheel_brings_in_vibrato() {
+        let mut patch = Patch::init();
+        patch.lfos[0].shape = LfoShape::Square;
+        patch.matrix.slots[0] = ModSlot {
+            source: ModSource::Lfo1,
+            destination: ModDestination::Pitch,
+            depth: 1.0 / 12.0,
+            via: Some(ModSource::ModWheel),
+        };
+        let mut globals = globals();
+
+        let mut still = voice(&patch);
+        still.next_sample(&patch, &globals);
+        assert_eq!(still.mods.pitch, 0.0);
+
+        globals.mod_wheel = 1.0;
+        let mut vibrato = voice(&patch);
+        vibrato.next_sample(&patch, &globals);
+        // a semitone either way on a square
+        assert!(
+            (vibrato.mods.pitch.abs() - 1.0).abs() < 1e-5,
+            "{}",
+            vibrato.mods.pitch
+        );
+        let expected = Note::new(45).freq * exp2f(vibrato.mods.pitch / 12.0);
+        let freq = oscillators(&vibrato)[0].phase_inc * SAMPLE_RATE;
+        assert!((freq / expected - 1.0).abs() < 1e-3, "{freq} {expected}");
+    }
+
+    #[test]
+    fn poly_aftertouch_only_reaches_its_note() {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.matrix.slots[0] = ModSlot {
+            source: ModSource::Aftertouch,
+            destination: ModDestination::Amp,
+            depth: -1.0,
+            via: None,
+        };
+
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+        pool.on_note_on(&Note::new(64), &Velocity(100));
+        pool.on_poly_aftertouch(&Note::new(64), 0.5);
+        for _ in 0..CONTROL_PERIOD {
+            pool.next_sample();
+        }
+
+        assert_eq!(pool.voices[0].mods.amp, 0.0);
+        assert_eq!(pool.voices[1].mods.amp, -0.5);
+
+        pool.set_aftertouch(0.75);
+        for _ in 0..CONTROL_PERIOD {
+            pool.next_sample();
+        }
+        // the higher of the two
+        assert_eq!(pool.voices[0].mods.amp, -0.75);
+        assert_eq!(pool.voices[1].mods.amp, -0.75);
+    }
+
+    #[test]
+    fn amp_stages_replace_the_adsr() {
+        let mut pool = VoicePool::new(envelope());
+        let mut stages = Breakpoints::init();
+        for level in [1.0, 0.0] {
+            let _ = stages.stages.push(Stage {
+                level,
+                time: TimeMs(2),
+                curve: Curve::Linear,
+            });
+        }
+        pool.patch.amp_stages = Some(stages);
+
+        pool.on_note_on(&Note::new(57), &Velocity(100));
+        let samples: std::vec::Vec<f32> = (0..SAMPLE_RATE as usize / 100)
+            .map(|_| pool.next_sample())
+            .collect();
+
+        // a one-shot blip, over without a note off
+        assert!(crate::golden::peak(&samples) > 0.1);
+        assert!(samples[samples.len() / 2..].iter().all(|s| *s == 0.0));
+        assert!(!pool.voices[0].is_active());
+    }
+
+    #[test]
+    fn mod_envelope_feeds_the_matrix() {
+        let mut pool = VoicePool::new(envelope());
+        let _ = pool.patch.mod_envelope.stages.push(Stage {
+            level: 1.0,
+            time: TimeMs(10),
+            curve: Curve::Linear,
+        });
+        pool.patch.mod_envelope.sustain = Some(0);
+        pool.patch.matrix.slots[0] = ModSlot {
+            source: ModSource::ModEnvelope,
+            destination: ModDestination::Pitch,
+            depth: 1.0 / 12.0,
+            via: None,
+        };
+
+        pool.on_note_on(&Note::new(57), &Velocity(100));
+        pool.next_sample();
+        assert!(pool.voices[0].mods.pitch < 0.1);
+
+        // ten milliseconds in, with a control period to spare
+        for _ in 0..SAMPLE_RATE as usize / 100 + CONTROL_PERIOD as usize {
+            pool.next_sample();
+        }
+        assert!((pool.voices[0].mods.pitch - 1.0).abs() < 1e-5);
+    }
+
+    fn live_notes(pool: &VoicePool) -> std::vec::Vec<u8> {
+        pool.voices
+            .iter()
+            .filter(|v| v.is_live())
+            .map(|v| v.note.num)
+            .collect()
+    }
+
+    /// A pool full of notes 40 and up, a few samples apart
+    fn full_pool(steal: StealPolicy) -> VoicePool {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.steal = steal;
+
+        for i in 0..MAX_VOICES as u8 {
+            pool.on_note_on(&Note::new(40 + i), &Velocity(100));
+            for _ in 0..10 {
+                pool.next_sample();
+            }
+        }
+
+        pool
+    }
+
+    #[test]
+    fn same_note_reuses_its_voice() {
+        let mut pool = VoicePool::new(envelope());
+        pool.on_note_on(&Note::new(57), &Velocity(100));
+        for _ in 0..2_000 {
+            pool.next_sample();
+        }
+        let level = pool.voices[0].envelope.value();
+
+        pool.on_note_on(&Note::new(57), &Velocity(100));
+        pool.next_sample();
+
+        assert_eq!(pool.voices.len(), 1);
+        // picks up from where it was rather than from zero
+        assert!(pool.voices[0].envelope.value() >= level);
+    }
+
+    #[test]
+    fn no_more_than_max_voices_sound() {
+        let mut pool = VoicePool::new(envelope());
+
+        for i in 0..MAX_VOICES as u8 * 2 {
+            pool.on_note_on(&Note::new(40 + i), &Velocity(100));
+            pool.next_sample();
+            assert!(live_notes(&pool).len() <= MAX_VOICES);
+        }
+
+        // the newest notes all got a voice
+        let live = live_notes(&pool);
+        assert!((MAX_VOICES as u8..MAX_VOICES as u8 * 2).all(|i| live.contains(&(40 + i))));
+    }
+
+    #[test]
+    fn idle_voices_go_first() {
+        let mut pool = full_pool(StealPolicy::Oldest);
+        pool.on_note_off(&Note::new(43), &Velocity(64));
+        for _ in 0..SAMPLE_RATE as usize / 10 {
+            pool.next_sample();
+        }
+
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+        assert!(pool.voices.iter().all(|v| v.fade.is_none()));
+        assert!(live_notes(&pool).contains(&40));
+    }
+
+    #[test]
+    fn oldest_is_stolen() {
+        let mut pool = full_pool(StealPolicy::Oldest);
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+
+        let live = live_notes(&pool);
+        assert!(!live.contains(&40) && live.contains(&41) && live.contains(&60));
+    }
+
+    #[test]
+    fn quietest_is_stolen() {
+        let mut pool = full_pool(StealPolicy::Quietest);
+        // the last note is still in its 5 ms attack
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+
+        let live = live_notes(&pool);
+        assert!(!live.contains(&47) && live.contains(&40) && live.contains(&60));
+    }
+
+    #[test]
+    fn released_are_stolen_first() {
+        let mut pool = full_pool(StealPolicy::ReleasedFirst);
+        pool.on_note_off(&Note::new(45), &Velocity(64));
+        pool.next_sample();
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+
+        let live = live_notes(&pool);
+        assert!(!live.contains(&45) && live.contains(&40));
+
+        // then the oldest held one
+        pool.on_note_on(&Note::new(61), &Velocity(100));
+        assert!(!live_notes(&pool).contains(&40));
+    }
+
+    #[test]
+    fn stolen_voice_fades_out() {
+        let mut pool = full_pool(StealPolicy::Oldest);
+        for _ in 0..SAMPLE_RATE as usize / 10 {
+            pool.next_sample();
+        }
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+
+        let stolen = pool.voices.iter().position(|v| v.fade.is_some()).unwrap();
+        let mut gains = std::vec::Vec::new();
+        while pool.voices[stolen].is_active() {
+            pool.next_sample();
+            gains.push(pool.voices[stolen].fade.unwrap());
+        }
+
+        assert!(
+            (gains.len() as f32 - STEAL_FADE).abs() <= 1.0,
+            "{}",
+            gains.len()
+        );
+        assert!(gains.windows(2).all(|w| w[0] - w[1] < 2.0 / STEAL_FADE));
+    }
+
+    fn mono_pool(mode: VoiceMode) -> VoicePool {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.mono.mode = mode;
+        pool
+    }
+
+    fn run(pool: &mut VoicePool, ms: usize) {
+        for _ in 0..SAMPLE_RATE as usize * ms / 1000 {
+            pool.next_sample();
+        }
+    }
+
+    #[test]
+    fn mono_returns_to_the_held_note() {
+        let mut pool = mono_pool(VoiceMode::Mono);
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+        pool.on_note_on(&Note::new(64), &Velocity(100));
+        run(&mut pool, 20);
+        assert_eq!(live_notes(&pool), [64]);
+
+        pool.on_note_off(&Note::new(64), &Velocity(64));
+        run(&mut pool, 20);
+        assert_eq!(live_notes(&pool), [60]);
+        assert!(pool.voices.iter().any(|v| v.is_held()));
+
+        pool.on_note_off(&Note::new(60), &Velocity(64));
+        assert!(pool.voices.iter().all(|v| !v.is_held()));
+    }
+
+    #[test]
+    fn priority_picks_the_note() {
+        let mut pool = mono_pool(VoiceMode::Mono);
+        pool.patch.mono.priority = NotePriority::Low;
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+        pool.on_note_on(&Note::new(64), &Velocity(100));
+        assert_eq!(live_notes(&pool), [60]);
+
+        pool.on_note_on(&Note::new(55), &Velocity(100));
+        assert_eq!(live_notes(&pool), [55]);
+
+        pool.patch.mono.priority = NotePriority::High;
+        pool.on_note_off(&Note::new(55), &Velocity(64));
+        assert_eq!(live_notes(&pool), [64]);
+    }
+
+    #[test]
+    fn legato_carries_on_the_envelope() {
+        for (mode, retriggered) in [(VoiceMode::Mono, true), (VoiceMode::Legato, false)] {
+            let mut pool = mono_pool(mode);
+            pool.on_note_on(&Note::new(60), &Velocity(100));
+            run(&mut pool, 20);
+            let sustain = pool.voices[0].envelope.value();
+
+            pool.on_note_on(&Note::new(64), &Velocity(100));
+            run(&mut pool, 2);
+            let level = pool.voices[0].envelope.value();
+
+            assert_eq!(level > sustain, retriggered, "{sustain} {level}");
+            assert_eq!(live_notes(&pool), [64]);
+        }
+    }
+
+    #[test]
+    fn portamento_glides_to_the_note() {
+        for (glide_mode, ms) in [
+            (GlideMode::ConstantTime, 100),
+            (GlideMode::ConstantRate, 200),
+        ] {
+            let mut pool = mono_pool(VoiceMode::Legato);
+            pool.patch.mono.glide = TimeMs(100);
+            pool.patch.mono.glide_mode = glide_mode;
+
+            pool.on_note_on(&Note::new(48), &Velocity(100));
+            run(&mut pool, 10);
+            pool.on_note_on(&Note::new(72), &Velocity(100));
+            run(&mut pool, ms / 2);
+
+            let glide = pool.voices[0].glide;
+            assert!((glide + 12.0).abs() < 0.2, "{glide}");
+            let freq = oscillators(&pool.voices[0])[0].phase_inc * SAMPLE_RATE;
+            let expected = Note::new(60).freq;
+            assert!((freq / expected - 1.0).abs() < 0.02, "{freq} {expected}");
+
+            run(&mut pool, ms / 2 + 1);
+            assert_eq!(pool.voices[0].glide, 0.0);
+        }
+    }
+
+    fn osc_freq(pool: &VoicePool) -> f32 {
+        oscillators(&pool.voices[0])[0].phase_inc * SAMPLE_RATE
+    }
+
+    #[test]
+    fn pitch_bend_follows_the_range() {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.bend_down = 12.0;
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+        run(&mut pool, 1);
+        let center = osc_freq(&pool);
+
+        pool.set_pitch_bend(1.0);
+        run(&mut pool, 1);
+        // smoothed, not there yet
+        assert!(osc_freq(&pool) < Note::new(62).freq * 0.99);
+        run(&mut pool, 50);
+        assert!((osc_freq(&pool) / Note::new(62).freq - 1.0).abs() < 1e-3);
+
+        pool.set_pitch_bend(-1.0);
+        run(&mut pool, 60);
+        assert!((osc_freq(&pool) / Note::new(48).freq - 1.0).abs() < 1e-3);
+
+        pool.set_pitch_bend(0.0);
+        run(&mut pool, 60);
+        assert!((osc_freq(&pool) / center - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn bend_moves_in_small_steps() {
+        let mut pool = VoicePool::new(envelope());
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+        run(&mut pool, 1);
+
+        pool.set_pitch_bend(1.0);
+        let mut last = osc_freq(&pool);
+        for _ in 0..SAMPLE_RATE as usize / 20 {
+            pool.next_sample();
+            let freq = osc_freq(&pool);
+            // a full bend is 12%, no jump is more than a fifth of it
+            assert!(freq / last - 1.0 < 0.025, "{last} -> {freq}");
+            last = freq;
+        }
+    }
+
+    #[test]
+    fn mod_wheel_adds_vibrato_by_default() {
+        let vibrato = |wheel: f32| {
+            let mut pool = VoicePool::new(envelope());
+            pool.set_mod_wheel(wheel);
+            pool.on_note_on(&Note::new(60), &Velocity(100));
+            run(&mut pool, 50);
+
+            (0..SAMPLE_RATE as usize / 5)
+                .map(|_| {
+                    pool.next_sample();
+                    pool.voices[0].mods.pitch.abs()
+                })
+                .fold(0.0, f32::max)
+        };
+
+        assert_eq!(vibrato(0.0), 0.0);
+        let depth = vibrato(1.0);
+        assert!((depth - 0.5).abs() < 0.02, "{depth}");
+    }
+
+    #[test]
+    fn pan_route_moves_the_voice() {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.matrix.slots[0] = ModSlot {
+            source: ModSource::Velocity,
+            destination: ModDestination::Pan,
+            depth: -1.0,
+            via: None,
+        };
+
+        pool.on_note_on(&Note::new(57), &Velocity(127));
+        let samples: std::vec::Vec<(f32, f32)> =
+            (0..2_000).map(|_| pool.next_sample_stereo()).collect();
+
+        // hard left
+        assert!(samples.iter().any(|(l, _)| l.abs() > 0.1));
+        assert!(samples.iter().all(|(_, r)| r.abs() < 1e-6));
+    }
+
+    #[test]
+    fn drive_runs_where_it_is_placed() {
+        let render = |placement: Option<DrivePlacement>| {
+            let mut pool = VoicePool::new(envelope());
+            if let Some(placement) = placement {
+                (0..24).for_each(|_| pool.adjust_drive(&DriveParams::Drive, Rotation::Right));
+                pool.patch.drive.placement = placement;
+            }
+            pool.on_note_on(&Note::new(45), &Velocity(127));
+            pool.on_note_on(&Note::new(52), &Velocity(127));
+
+            (0..2_000)
+                .map(|_| pool.next_sample_stereo())
+                .collect::<std::vec::Vec<_>>()
+        };
+
+        let untouched = render(None);
+        // 24 dB of drive, but nowhere to run
+        assert_eq!(render(Some(DrivePlacement::Off)), untouched);
+
+        // each voice clipped apart sounds unlike the chord clipped together
+        let voice = render(Some(DrivePlacement::Voice));
+        let master = render(Some(DrivePlacement::Master));
+        assert_ne!(voice, untouched);
+        assert_ne!(master, untouched);
+        assert_ne!(voice, master);
+    }
+
+    #[test]
+    fn soft_notes_play_quieter() {
+        let peak = |velocity: u8| {
+            let mut pool = VoicePool::new(envelope());
+            pool.patch.velocity.amp = 1.0;
+            pool.on_note_on(&Note::new(57), &Velocity(velocity));
+
+            (0..2_000)
+                .map(|_| pool.next_sample().abs())
+                .fold(0.0, f32::max)
+        };
+
+        let (hard, soft) = (peak(127), peak(32));
+        assert!((soft / hard - 32.0 / 127.0).abs() < 0.02, "{soft} {hard}");
+
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.velocity.amp = 0.0;
+        pool.on_note_on(&Note::new(57), &Velocity(32));
+        run(&mut pool, 1);
+        assert_eq!(pool.voices[0].velocity_gain, 1.0);
+    }
+
+    #[test]
+    fn hard_notes_attack_faster() {
+        let attack_samples = |velocity: u8| {
+            let mut pool = VoicePool::new(envelope());
+            pool.patch.velocity.attack = 1.0;
+            pool.on_note_on(&Note::new(57), &Velocity(velocity));
+
+            (0..)
+                .take_while(|_| {
+                    pool.next_sample();
+                    pool.voices[0].envelope.value() < 0.999
+                })
+                .count() as f32
+        };
+
+        // 5 ms attack, halved at full velocity and doubled at none
+        let hard = attack_samples(127);
+        let soft = attack_samples(0);
+        assert!((hard - SAMPLE_RATE * 0.0025).abs() < 2.0, "{hard}");
+        assert!((soft - SAMPLE_RATE * 0.01).abs() < 2.0, "{soft}");
+    }
+
+    #[test]
+    fn quick_release_shortens_the_release() {
+        let release_ms = |velocity: u8| {
+            let mut pool = VoicePool::new(envelope());
+            pool.patch.velocity.release = 1.0;
+            let note = Note::new(57);
+            pool.on_note_on(&note, &Velocity(100));
+            run(&mut pool, 20);
+            pool.on_note_off(&note, &Velocity(velocity));
+
+            let mut ms = 0;
+            while pool.is_active() {
+                run(&mut pool, 1);
+                ms += 1;
+            }
+            ms
+        };
+
+        // 50 ms release, halved by the fastest note off
+        assert!((24..=27).contains(&release_ms(127)), "{}", release_ms(127));
+        assert!((48..=52).contains(&release_ms(64)), "{}", release_ms(64));
+        assert!(release_ms(1) > 90);
+    }
+
+    #[test]
+    fn note_on_at_zero_velocity_releases_as_by_default() {
+        // how most keyboards send a note off
+        let mut parser = MidiParser::new(MidiChannel::Ch1);
+        let msg = [0x90, 57, 0]
+            .into_iter()
+            .find_map(|byte| parser.process(byte));
+        let Some(MidiMessage::NoteOff(note, velocity)) = msg else {
+            panic!("{msg:?}");
+        };
+
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.velocity.release = 1.0;
+        pool.on_note_on(&note, &Velocity(100));
+        run(&mut pool, 20);
+        pool.on_note_off(&note, &velocity);
+
+        let ms = released_ms(&mut pool);
+        assert!((48..=52).contains(&ms), "{ms}");
+    }
+
+    fn released_ms(pool: &mut VoicePool) -> usize {
+        let mut ms = 0;
+        while pool.is_active() {
+            run(pool, 1);
+            ms += 1;
+        }
+        ms
+    }
+
+    #[test]
+    fn sustain_holds_notes_until_it_comes_up() {
+        let mut pool = VoicePool::new(envelope());
+        let note = Note::new(60);
+        pool.set_sustain(1.0);
+
+        pool.on_note_on(&note, &Velocity(100));
+        pool.on_note_off(&note, &Velocity(64));
+        run(&mut pool, 200);
+        assert_eq!(live_notes(&pool), [60]);
+        assert!(pool.voices[0].is_held());
+
+        // struck again under the pedal, it carries on the same voice
+        pool.on_note_on(&note, &Velocity(100));
+        pool.on_note_off(&note, &Velocity(64));
+        run(&mut pool, 200);
+        assert_eq!(live_notes(&pool), [60]);
+        assert!(pool.voices[0].is_held());
+
+        pool.set_sustain(0.0);
+        assert!((48..=52).contains(&released_ms(&mut pool)));
+    }
+
+    #[test]
+    fn sustain_keeps_keys_down_playing() {
+        let mut pool = VoicePool::new(envelope());
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+        pool.set_sustain(1.0);
+        pool.set_sustain(0.0);
+        run(&mut pool, 100);
+
+        assert!(pool.voices[0].is_held());
+    }
+
+    #[test]
+    fn half_pedal_lengthens_the_release() {
+        let mut pool = VoicePool::new(envelope());
+        let note = Note::new(60);
+        pool.set_sustain(0.45);
+
+        pool.on_note_on(&note, &Velocity(100));
+        run(&mut pool, 20);
+        pool.on_note_off(&note, &Velocity(64));
+
+        // 50 ms release, 4 times longer
+        let ms = released_ms(&mut pool);
+        assert!((195..=205).contains(&ms), "{ms}");
+    }
+
+    #[test]
+    fn sostenuto_holds_only_the_notes_down_when_pressed() {
+        let mut pool = VoicePool::new(envelope());
+        pool.on_note_on(&Note::new(48), &Velocity(100));
+        pool.set_sostenuto(true);
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+
+        pool.on_note_off(&Note::new(48), &Velocity(64));
+        pool.on_note_off(&Note::new(60), &Velocity(64));
+        run(&mut pool, 100);
+        assert_eq!(live_notes(&pool), [48]);
+
+        pool.set_sostenuto(false);
+        run(&mut pool, 60);
+        assert!(!pool.is_active());
+    }
+
+    #[test]
+    fn soft_pedal_plays_quieter_and_darker() {
+        let mut pool = VoicePool::new(envelope());
+        pool.patch.filter.cutoff = 1_000.0;
+        pool.set_soft(true);
+        pool.on_note_on(&Note::new(60), &Velocity(100));
+        pool.set_soft(false);
+        pool.on_note_on(&Note::new(64), &Velocity(100));
+        pool.next_sample();
+
+        let (soft, normal) = (&pool.voices[0], &pool.voices[1]);
+        assert!(soft.velocity < normal.velocity);
+        assert!((soft.cutoff / normal.cutoff - SOFT_CUTOFF).abs() < 1e-3);
+    }
 }
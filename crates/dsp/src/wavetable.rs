@@ -0,0 +1,498 @@
+use core::f32::consts::TAU;
+
+use defmt::Format;
+use libm::{cosf, sinf};
+
+use crate::consts::TABLE_SIZE;
+
+/// Every next mip level keeps half of the harmonics of the previous one
+pub const MIP_LEVELS: usize = 8;
+pub const MAX_FRAMES: usize = 4;
+
+/// Highest harmonic of the first mip level
+const MAX_HARMONICS: usize = TABLE_SIZE / 2;
+const TABLE_MASK: usize = TABLE_SIZE - 1;
+
+pub type Table = [f32; TABLE_SIZE];
+
+pub static SINE: Table = sine_table();
+
+pub static BASIC: WavetableSet =
+    WavetableSet::generate(&[Shape::Sine, Shape::Triangle, Shape::SawTooth, Shape::Square]);
+
+pub static PULSES: WavetableSet = WavetableSet::generate(&[
+    Shape::Pulse(0.5),
+    Shape::Pulse(0.3),
+    Shape::Pulse(0.15),
+    Shape::Pulse(0.05),
+]);
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Interpolation {
+    Linear,
+    /// 4-point Catmull-Rom
+    Cubic,
+}
+
+impl Interpolation {
+    pub fn next(&self) -> Self {
+        match self {
+            Interpolation::Linear => Interpolation::Cubic,
+            Interpolation::Cubic => Interpolation::Linear,
+        }
+    }
+}
+
+/// Which table set a wavetable oscillator morphs across
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum TableSource {
+    /// Sine, triangle, saw and square
+    Basic,
+    /// Pulses narrowing from a square
+    Pulses,
+    /// Loaded into the patch, silent until then
+    User,
+}
+
+impl TableSource {
+    pub fn next(&self) -> Self {
+        match self {
+            TableSource::Basic => TableSource::Pulses,
+            TableSource::Pulses => TableSource::User,
+            TableSource::User => TableSource::Basic,
+        }
+    }
+
+    /// The set it stands for, `user` being the one of the patch
+    pub fn set<'a>(&self, user: &'a WavetableSet) -> &'a WavetableSet {
+        match self {
+            TableSource::Basic => &BASIC,
+            TableSource::Pulses => &PULSES,
+            TableSource::User => user,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Format)]
+pub enum WavetableError {
+    NoFrames,
+    TooManyFrames,
+    /// The sample data can't be split into frames of at least 2 samples
+    BadLength,
+}
+
+/// Built-in single cycle shapes, generated additively
+#[derive(Clone, Copy)]
+pub enum Shape {
+    Sine,
+    Triangle,
+    SawTooth,
+    Square,
+    Pulse(f32),
+}
+
+impl Shape {
+    /// `(sin, cos)` amplitudes of the k-th harmonic, matching the naive
+    /// waves of `Oscillator` (phase 0 is the start of the cycle)
+    const fn harmonic(&self, k: usize) -> (f32, f32) {
+        use core::{f32::consts::PI, f64::consts::FRAC_PI_2};
+        let kf = k as f32;
+
+        match *self {
+            Shape::Sine => (if k == 1 { 1.0 } else { 0.0 }, 0.0),
+            Shape::Triangle if k % 2 == 1 => (0.0, -8.0 / (PI * PI * kf * kf)),
+            Shape::SawTooth => (-2.0 / (PI * kf), 0.0),
+            Shape::Square if k % 2 == 1 => (4.0 / (PI * kf), 0.0),
+            Shape::Pulse(duty) => {
+                let angle = core::f64::consts::TAU * k as f64 * duty as f64;
+                let (sin, cos) = (const_sin(angle) as f32, const_sin(angle + FRAC_PI_2) as f32);
+
+                (2.0 * (1.0 - cos) / (PI * kf), 2.0 * sin / (PI * kf))
+            }
+            _ => (0.0, 0.0),
+        }
+    }
+}
+
+/// A set of single cycle frames to morph across, each one stored as
+/// [`MIP_LEVELS`] band-limited tables
+pub struct WavetableSet {
+    frames: usize,
+    tables: [[Table; MIP_LEVELS]; MAX_FRAMES],
+}
+
+impl WavetableSet {
+    /// An empty set to load user tables into
+    pub const fn empty() -> Self {
+        Self {
+            frames: 0,
+            tables: [[[0.0; TABLE_SIZE]; MIP_LEVELS]; MAX_FRAMES],
+        }
+    }
+
+    pub const fn generate(shapes: &[Shape]) -> Self {
+        assert!(!shapes.is_empty() && shapes.len() <= MAX_FRAMES);
+
+        let mut this = Self::empty();
+        this.frames = shapes.len();
+
+        let mut frame = 0;
+        while frame < shapes.len() {
+            let mut level = 0;
+            while level < MIP_LEVELS {
+                let table = &mut this.tables[frame][level];
+
+                let mut k = 1;
+                while k <= MAX_HARMONICS >> level {
+                    let (sin, cos) = shapes[frame].harmonic(k);
+
+                    let mut i = 0;
+                    while i < TABLE_SIZE {
+                        let pos = k * i;
+                        table[i] += sin * SINE[pos & TABLE_MASK]
+                            + cos * SINE[(pos + TABLE_SIZE / 4) & TABLE_MASK];
+                        i += 1;
+                    }
+                    k += 1;
+                }
+                level += 1;
+            }
+            frame += 1;
+        }
+
+        this
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Loads user frames from patch data: a frame count byte followed by
+    /// little-endian `i16` samples, split evenly between the frames. A frame
+    /// may be of any length, it gets resampled to the table size
+    pub fn load_patch_data(&mut self, data: &[u8]) -> Result<(), WavetableError> {
+        let Some((&frames, samples)) = data.split_first() else {
+            return Err(WavetableError::NoFrames);
+        };
+        let frames = frames as usize;
+
+        if frames == 0 {
+            return Err(WavetableError::NoFrames);
+        }
+        if frames > MAX_FRAMES {
+            return Err(WavetableError::TooManyFrames);
+        }
+
+        let frame_bytes = samples.len() / frames;
+        if samples.len() % frames != 0 || frame_bytes % 2 != 0 || frame_bytes < 4 {
+            return Err(WavetableError::BadLength);
+        }
+
+        for (frame, bytes) in samples.chunks_exact(frame_bytes).enumerate() {
+            self.load_frame(frame, bytes.len() / 2, |n| {
+                i16::from_le_bytes([bytes[n * 2], bytes[n * 2 + 1]]) as f32 / i16::MAX as f32
+            });
+        }
+
+        self.frames = frames;
+
+        Ok(())
+    }
+
+    /// Analyses a single cycle of `len` samples and rebuilds the mip levels
+    /// of the frame from its harmonics, normalized to a peak of 1
+    fn load_frame(&mut self, frame: usize, len: usize, sample: impl Fn(usize) -> f32) {
+        let harmonics = MAX_HARMONICS.min(len / 2);
+
+        self.tables[frame] = [[0.0; TABLE_SIZE]; MIP_LEVELS];
+
+        for k in 1..=harmonics {
+            let (mut sin, mut cos) = (0.0, 0.0);
+
+            for n in 0..len {
+                let angle = TAU * (k * n) as f32 / len as f32;
+                sin += sample(n) * sinf(angle);
+                cos += sample(n) * cosf(angle);
+            }
+
+            let (sin, cos) = (sin * 2.0 / len as f32, cos * 2.0 / len as f32);
+
+            for (level, table) in self.tables[frame].iter_mut().enumerate() {
+                if k > MAX_HARMONICS >> level {
+                    break;
+                }
+
+                for (i, value) in table.iter_mut().enumerate() {
+                    let pos = k * i;
+                    *value += sin * SINE[pos & TABLE_MASK]
+                        + cos * SINE[(pos + TABLE_SIZE / 4) & TABLE_MASK];
+                }
+            }
+        }
+
+        let peak = self.tables[frame][0]
+            .iter()
+            .fold(0.0f32, |acc, s| acc.max(s.abs()));
+
+        if peak > 0.0 {
+            for table in self.tables[frame].iter_mut() {
+                table.iter_mut().for_each(|s| *s /= peak);
+            }
+        }
+    }
+
+    /// The first mip level that has no harmonics above Nyquist at `phase_inc`
+    pub const fn mip_level(phase_inc: f32) -> usize {
+        let mut level = 0;
+
+        while level < MIP_LEVELS - 1 && (MAX_HARMONICS >> level) as f32 * phase_inc > 0.5 {
+            level += 1;
+        }
+
+        level
+    }
+
+    /// `morph` in `0.0..=1.0` crossfades from the first frame to the last one
+    pub fn sample(
+        &self,
+        phase: f32,
+        morph: f32,
+        level: usize,
+        interpolation: Interpolation,
+    ) -> f32 {
+        if self.frames < 2 {
+            return lookup(&self.tables[0][level], phase, interpolation);
+        }
+
+        let position = morph.clamp(0.0, 1.0) * (self.frames - 1) as f32;
+        let frame = (position as usize).min(self.frames - 2);
+        let frac = position - frame as f32;
+
+        let a = lookup(&self.tables[frame][level], phase, interpolation);
+        let b = lookup(&self.tables[frame + 1][level], phase, interpolation);
+
+        a + (b - a) * frac
+    }
+}
+
+pub fn lookup(table: &Table, phase: f32, interpolation: Interpolation) -> f32 {
+    let position = phase * TABLE_SIZE as f32;
+    let i = position as usize;
+    let frac = position - i as f32;
+
+    let y1 = table[i & TABLE_MASK];
+    let y2 = table[(i + 1) & TABLE_MASK];
+
+    match interpolation {
+        Interpolation::Linear => y1 + (y2 - y1) * frac,
+        Interpolation::Cubic => {
+            let y0 = table[(i + TABLE_SIZE - 1) & TABLE_MASK];
+            let y3 = table[(i + 2) & TABLE_MASK];
+
+            let c1 = 0.5 * (y2 - y0);
+            let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+            let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+
+            ((c3 * frac + c2) * frac + c1) * frac + y1
+        }
+    }
+}
+
+const fn sine_table() -> Table {
+    let mut table = [0.0; TABLE_SIZE];
+
+    let mut i = 0;
+    while i < TABLE_SIZE {
+        table[i] = const_sin(core::f64::consts::TAU * i as f64 / TABLE_SIZE as f64) as f32;
+        i += 1;
+    }
+
+    table
+}
+
+/// `libm` isn't usable in const context, so the tables are generated with a
+/// Taylor series, accurate to ~1e-12 after reducing `x` to `-PI..=PI`
+const fn const_sin(x: f64) -> f64 {
+    use core::f64::consts::{PI, TAU};
+
+    let mut x = x % TAU;
+    if x > PI {
+        x -= TAU;
+    } else if x < -PI {
+        x += TAU;
+    }
+
+    let mut term = x;
+    let mut sum = x;
+    let mut n = 1;
+    while n < 14 {
+        term = -term * x * x / ((2 * n) * (2 * n + 1)) as f64;
+        sum += term;
+        n += 1;
+    }
+
+    sum
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Amplitudes of the harmonics of a single cycle table
+    fn harmonics(table: &Table) -> Vec<f32> {
+        (0..=TABLE_SIZE / 2)
+            .map(|k| {
+                let (re, im) = table
+                    .iter()
+                    .enumerate()
+                    .fold((0.0, 0.0), |(re, im), (n, s)| {
+                        let angle = std::f64::consts::TAU * (k * n) as f64 / TABLE_SIZE as f64;
+                        (re + *s as f64 * angle.cos(), im + *s as f64 * angle.sin())
+                    });
+                ((re * re + im * im).sqrt() * 2.0 / TABLE_SIZE as f64) as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sine_table_is_accurate() {
+        for (i, s) in SINE.iter().enumerate() {
+            let expected = (std::f32::consts::TAU * i as f32 / TABLE_SIZE as f32).sin();
+            assert!((s - expected).abs() < 1e-6, "{i}: {s} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn mip_levels_are_band_limited() {
+        for (frame, tables) in BASIC.tables.iter().take(BASIC.frames()).enumerate() {
+            for (level, table) in tables.iter().enumerate() {
+                let limit = MAX_HARMONICS >> level;
+
+                for (k, amp) in harmonics(table).iter().enumerate() {
+                    if k > limit {
+                        assert!(
+                            *amp < 1e-5,
+                            "frame {frame}, level {level}, harmonic {k}: {amp}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mip_level_fits_under_nyquist() {
+        for note in [0, 36, 60, 84, 108, 127] {
+            let phase_inc = midi_parser::parser::Note::new(note).freq / crate::consts::SAMPLE_RATE;
+            let level = WavetableSet::mip_level(phase_inc);
+
+            if level < MIP_LEVELS - 1 {
+                assert!(
+                    (MAX_HARMONICS >> level) as f32 * phase_inc <= 0.5,
+                    "note {note}"
+                );
+            }
+            if level > 0 {
+                assert!(
+                    (MAX_HARMONICS >> (level - 1)) as f32 * phase_inc > 0.5,
+                    "note {note}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn saw_frame_matches_naive_saw() {
+        // away from the wrap the band-limited saw follows the naive one
+        let table = &BASIC.tables[2][0];
+        for (i, s) in table.iter().enumerate() {
+            if (TABLE_SIZE / 8..TABLE_SIZE * 7 / 8).contains(&i) {
+                let naive = 2.0 * i as f32 / TABLE_SIZE as f32 - 1.0;
+                assert!((s - naive).abs() < 0.05, "{i}: {s} vs {naive}");
+            }
+        }
+    }
+
+    #[test]
+    fn morph_crossfades_frames() {
+        let phase = 0.3;
+        let frames: Vec<f32> = (0..4)
+            .map(|f| lookup(&BASIC.tables[f][0], phase, Interpolation::Linear))
+            .collect();
+
+        let sample = |morph| BASIC.sample(phase, morph, 0, Interpolation::Linear);
+
+        assert!((sample(0.0) - frames[0]).abs() < 1e-6);
+        assert!((sample(1.0) - frames[3]).abs() < 1e-6);
+        assert!((sample(0.5) - (frames[1] + frames[2]) / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cubic_is_more_accurate_than_linear() {
+        let error = |interpolation| {
+            (0..1000)
+                .map(|i| {
+                    let phase = i as f32 / 1000.0;
+                    let expected = (std::f32::consts::TAU * phase).sin();
+                    (lookup(&SINE, phase, interpolation) - expected).abs()
+                })
+                .fold(0.0f32, f32::max)
+        };
+
+        assert!(error(Interpolation::Cubic) < error(Interpolation::Linear));
+    }
+
+    #[test]
+    fn loads_user_frames_from_patch_data() {
+        // two frames of a saw in a different length than the tables
+        let len = 600;
+        let mut data = vec![2];
+        for _ in 0..2 {
+            for n in 0..len {
+                let sample = (2.0 * n as f32 / len as f32 - 1.0) * i16::MAX as f32;
+                data.extend_from_slice(&(sample as i16).to_le_bytes());
+            }
+        }
+
+        let mut set = Box::new(WavetableSet::empty());
+        set.load_patch_data(&data).unwrap();
+
+        assert_eq!(set.frames(), 2);
+
+        let expected = harmonics(&BASIC.tables[2][3]);
+        let loaded = harmonics(&set.tables[1][3]);
+        let scale = loaded[1] / expected[1];
+
+        for (k, (a, b)) in loaded.iter().zip(&expected).enumerate() {
+            assert!(
+                (a - b * scale).abs() < 1e-3,
+                "harmonic {k}: {a} vs {}",
+                b * scale
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_patch_data() {
+        let mut set = Box::new(WavetableSet::empty());
+
+        assert_eq!(set.load_patch_data(&[]), Err(WavetableError::NoFrames));
+        assert_eq!(
+            set.load_patch_data(&[0, 1, 2]),
+            Err(WavetableError::NoFrames)
+        );
+        assert_eq!(
+            set.load_patch_data(&[5, 0, 0, 0, 0]),
+            Err(WavetableError::TooManyFrames)
+        );
+        assert_eq!(
+            set.load_patch_data(&[1, 0, 0, 0]),
+            Err(WavetableError::BadLength)
+        );
+        assert_eq!(
+            set.load_patch_data(&[2, 0, 0, 0, 0, 0]),
+            Err(WavetableError::BadLength)
+        );
+    }
+}
@@ -28,16 +28,30 @@ impl PartialEq for Note {
 pub struct Velocity(pub u8);
 
 #[derive(Debug, PartialEq, Clone, Copy)]
-pub struct ControlNum(u8);
+pub struct ControlNum(pub u8);
 
 #[derive(Debug, PartialEq, Clone, Copy)]
-pub struct ControlVal(u8);
+pub struct ControlVal(pub u8);
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct ProgramNumber(u8);
 
 #[derive(Debug, PartialEq, Clone, Copy)]
-pub struct PitchBendValue(u16);
+pub struct PitchBendValue(pub u16);
+
+impl PitchBendValue {
+    pub const CENTER: u16 = 0x2000;
+
+    /// `-1.0..=1.0`, reaching both ends
+    pub fn bipolar(&self) -> f32 {
+        let offset = self.0 as f32 - Self::CENTER as f32;
+        if offset < 0.0 {
+            offset / Self::CENTER as f32
+        } else {
+            offset / (Self::CENTER - 1) as f32
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum MidiMessage {
@@ -49,6 +63,11 @@ pub enum MidiMessage {
     ChannelAT(Velocity),
     PithBend(PitchBendValue),
     SysEx,
+    /// Real-time, 24 per quarter note
+    Clock,
+    Start,
+    Continue,
+    Stop,
 }
 
 impl MidiMessage {
@@ -68,6 +87,19 @@ impl MidiMessage {
         }
     }
 
+    /// Real-time messages are a single byte, for every channel
+    pub fn real_time(byte: u8) -> Option<Self> {
+        use MidiMessage::*;
+
+        match byte {
+            0xF8 => Some(Clock),
+            0xFA => Some(Start),
+            0xFB => Some(Continue),
+            0xFC => Some(Stop),
+            _ => None,
+        }
+    }
+
     pub fn bytes_requires(&self) -> usize {
         match self {
             Self::NoteOff(_, _) => 2,
@@ -78,6 +110,7 @@ impl MidiMessage {
             Self::ChannelAT(_) => 1,
             Self::PithBend(_) => 2,
             Self::SysEx => 0,
+            Self::Clock | Self::Start | Self::Continue | Self::Stop => 0,
         }
     }
 }
@@ -155,11 +188,17 @@ impl MidiParser {
         &self.message
     }
 
+    /// A message under way, a new one or one on the running status
     pub fn in_progress(&self) -> bool {
-        self.message_reading.is_some()
+        self.message_reading.is_some() || !self.data_buffer.is_empty()
     }
 
     pub fn process(&mut self, byte: u8) -> Option<MidiMessage> {
+        // they can come between the bytes of another message, which carries on
+        if let Some(msg) = MidiMessage::real_time(byte) {
+            return Some(msg);
+        }
+
         self.process_midi_byte(byte);
 
         if self.in_progress() {
@@ -246,7 +285,7 @@ impl MidiParser {
                 let value = (self.data_buffer[0] as u16) | ((self.data_buffer[1] as u16) << 7);
                 *bend_value = PitchBendValue(value);
             }
-            SysEx => {
+            SysEx | Clock | Start | Continue | Stop => {
                 // info!("SysEx MIDI data byte is ignored");
             }
         }
@@ -310,6 +349,12 @@ pub const fn midi_note_to_freq(note: u8) -> f32 {
 // 0lllllll - the least significant 7 bits
 // 0mmmmmmm - the most significant 7 bits
 
+// 11111xxx - real-time, no data bytes, may come in the middle of another message
+// 11111000 - clock, 24 per quarter note
+// 11111010 - start
+// 11111011 - continue
+// 11111100 - stop
+
 // Running Status
 // do not send status part if the message kind and the channel are the same, i.e., the whole status byte is same
 // a trick with it: send note on with velocity = 0 instead of note off
@@ -356,7 +401,7 @@ mod tests {
     fn assert_status_is_init(rs: &MidiParser, channel: MidiChannel) {
         assert_eq!(rs.midi_channel, channel);
         assert_eq!(rs.bytes_to_read, 0);
-        assert_eq!(rs.message_kind, None);
+        assert_eq!(rs.message_kind(), &None);
     }
 
     #[test]
@@ -365,10 +410,13 @@ mod tests {
         let mut rs = MidiParser::new(ch);
 
         rs.process_midi_byte(0x9A);
-        assert_eq!(rs.message_kind, None);
+        assert_eq!(rs.message_kind(), &None);
         rs.process_midi_byte(0x73);
         rs.process_midi_byte(0x48);
-        assert_eq!(rs.message_kind, Some(NoteOn(Note::new(115), Velocity(72))));
+        assert_eq!(
+            rs.message_kind(),
+            &Some(NoteOn(Note::new(115), Velocity(72)))
+        );
     }
 
     #[test]
@@ -377,21 +425,69 @@ mod tests {
         let mut rs = MidiParser::new(ch);
 
         rs.process_midi_byte(0x94);
-        assert_eq!(rs.message_kind, None);
+        assert_eq!(rs.message_kind(), &None);
 
         rs.process_midi_byte(0x73);
         rs.process_midi_byte(0x48);
-        assert_eq!(rs.message_kind, Some(NoteOn(Note::new(115), Velocity(72))));
+        assert_eq!(
+            rs.message_kind(),
+            &Some(NoteOn(Note::new(115), Velocity(72)))
+        );
 
         rs.process_midi_byte(0x39);
         rs.process_midi_byte(0x77);
-        assert_eq!(rs.message_kind, Some(NoteOn(Note::new(57), Velocity(119))));
+        assert_eq!(
+            rs.message_kind(),
+            &Some(NoteOn(Note::new(57), Velocity(119)))
+        );
 
         rs.process_midi_byte(0x53);
         // it keeps previous message kind until all required data received
-        assert_eq!(rs.message_kind, Some(NoteOn(Note::new(57), Velocity(119))));
+        assert_eq!(
+            rs.message_kind(),
+            &Some(NoteOn(Note::new(57), Velocity(119)))
+        );
         rs.process_midi_byte(0x0F);
         // println!("{rs:?}");
-        assert_eq!(rs.message_kind, Some(NoteOn(Note::new(83), Velocity(15))));
+        assert_eq!(
+            rs.message_kind(),
+            &Some(NoteOn(Note::new(83), Velocity(15)))
+        );
+    }
+
+    #[test]
+    fn pitch_bend() {
+        let ch = MidiChannel::Ch1;
+        let mut rs = MidiParser::new(ch);
+
+        rs.process_midi_byte(0xE0);
+        rs.process_midi_byte(0x00);
+        rs.process_midi_byte(0x40);
+        assert_eq!(
+            rs.message_kind(),
+            &Some(PithBend(PitchBendValue(PitchBendValue::CENTER)))
+        );
+
+        assert_eq!(PitchBendValue(PitchBendValue::CENTER).bipolar(), 0.0);
+        assert_eq!(PitchBendValue(0).bipolar(), -1.0);
+        assert_eq!(PitchBendValue(0x3FFF).bipolar(), 1.0);
+    }
+
+    #[test]
+    fn real_time_between_data_bytes() {
+        let ch = MidiChannel::Ch1;
+        let mut rs = MidiParser::new(ch);
+
+        assert_eq!(rs.process(0x90), None);
+        assert_eq!(rs.process(0x3C), None);
+        assert_eq!(rs.process(0xF8), Some(Clock));
+        assert_eq!(rs.process(0x64), Some(NoteOn(Note::new(60), Velocity(100))));
+        assert_eq!(rs.process(0xFA), Some(Start));
+        assert_eq!(rs.process(0xFC), Some(Stop));
+
+        // the running status outlasts them too
+        assert_eq!(rs.process(0x3E), None);
+        assert_eq!(rs.process(0xF8), Some(Clock));
+        assert_eq!(rs.process(0x00), Some(NoteOff(Note::new(62), Velocity(0))));
     }
 }